@@ -1,13 +1,61 @@
 use crate::*;
 use blake3::hash;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BicError {
+    #[error("invalid_bic")]
+    InvalidBic,
+    #[error("invalid_function")]
+    InvalidFunction,
+    #[error("invalid_args")]
+    InvalidArgs,
+    #[error("account_has_no_bytecode")]
+    AccountHasNoBytecode,
+    #[error("invalid_attached_amount")]
+    InvalidAttachedAmount,
+    #[error("attached_amount_insufficient_funds")]
+    AttachedAmountInsufficientFunds,
+    #[error("{0}")]
+    Abort(String),
+    #[error("rocksdb_error")]
+    RocksDB(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Kv(#[from] KvError),
+}
+
+/// Result of a single tx, stored per tx and hashed into the mutations_hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxReturn {
+    pub error: String,
+    pub result: Option<Vec<u8>>,
+    pub logs: Vec<Vec<u8>>,
+    pub exec_used: u64,
+}
+
+impl TxReturn {
+    pub fn ok(result: Option<Vec<u8>>, logs: Vec<Vec<u8>>, exec_used: u64) -> Self {
+        Self {
+            error: "ok".to_string(),
+            result,
+            logs,
+            exec_used,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error == "ok"
+    }
+}
 
 pub struct Base;
 
 impl Base {
-    // pub fn exec_cost(txu: &Txu) -> i64 {
-    //     let bytes = txu.tx_encoded.len() as i64 + 32 + 96;
-    //     3 + (bytes / 256) * 3
-    // }
+    /// Flat fee for carrying the tx, priced on its packed size plus hash and signature
+    pub fn exec_cost(epoch: u64, txu: &Txu) -> i64 {
+        let bytes = TX::pack(txu).len() + 32 + 96;
+        GasSchedule::for_epoch(epoch).tx_cost(bytes)
+    }
 
     pub fn seed_random(vr: &[u8], txhash: &[u8], action_index: &str, call_cnt: &str) -> Vec<u8> {
         let mut data = Vec::new();
@@ -18,176 +66,220 @@ impl Base {
         hash(&data).as_bytes().to_vec()
     }
 
-    pub fn call_txs_pre_parallel(env: MapEnv, txus: Vec<Txu>) -> (Vec<String>, Vec<String>) {
-        (vec![], vec![])
+    pub fn nonce_key(signer: &[u8]) -> Vec<u8> {
+        [b"bic:base:nonce:".as_slice(), signer].concat()
     }
 
-    // pub fn call_tx_actions(
-    //     env: &mut MapEnv,
-    //     txu: &Txu,
-    // ) -> (
-    //     Vec<Mutation>,
-    //     Vec<Mutation>,
-    //     Vec<Mutation>,
-    //     Vec<Mutation>,
-    //     (),
-    //     // TxReturn,
-    // ) {
-    //     Process::delete("mutations_gas");
-    //     Process::delete("mutations_gas_reverse");
-    //     Process::delete("mutations");
-    //     Process::delete("mutations_reverse");
-
-    //     let result: Result<TxReturn, TxError> = (|| -> TxReturn {
-    //         let action = txu
-    //             .tx
-    //             .unwrap()
-    //             .actions
-    //             .get(0)
-    //             .ok_or(TxError::NoActions)
-    //             .unwrap();
-
-    //         env.account_current = Some(action.contract.clone());
-
-    //         if BlsRs::validate_public_key(&action.contract.as_bytes()) {
-    //             if let Some(bytecode) = Contract::bytecode(&action.contract) {
-    //                 let seed_bin = Self::seed_random(
-    //                     &env.entry_vr,
-    //                     env.tx_hash.unwrap().as_ref(),
-    //                     "0",
-    //                     &env.call_counter.to_string(),
-    //                 );
-    //                 let float64 = f64::from_le_bytes(seed_bin[..8].try_into().unwrap());
-    //                 env.seed = Some(seed_bin);
-    //                 env.seedf64 = float64;
-
-    //                 if !action.attached_symbol.unwrap().is_empty() {
-    //                     env.attached_symbol = action.attached_symbol.unwrap();
-    //                     env.attached_amount = action.attached_amount.unwrap();
-
-    //                     let amount: i64 = action.attached_amount.unwrap();
-    //                     if amount <= 0 {
-    //                         return TxResult::Err(TxError::InvalidAttachedAmount).expect("REASON");
-    //                     }
-    //                     if amount
-    //                         > Coin::balance(
-    //                             env.tx_signer.as_ref().unwrap(),
-    //                             &action.attached_symbol.unwrap(),
-    //                         )
-    //                     {
-    //                         return TxResult::Err(TxError::AttachedAmountInsufficientFunds)
-    //                             .expect("REASON");
-    //                     }
-
-    //                     ConsensusKV::kv_increment(
-    //                         format!(
-    //                             "bic:coin:balance:{}:{}",
-    //                             action.contract,
-    //                             action.attached_symbol.unwrap(),
-    //                         )
-    //                         .as_bytes()
-    //                         .to_vec(),
-    //                         amount,
-    //                     );
-    //                     ConsensusKV::kv_increment(
-    //                         format!(
-    //                             "bic:coin:balance:{}:{}",
-    //                             env.tx_signer.as_ref().unwrap(),
-    //                             action.attached_symbol.unwrap(),
-    //                         )
-    //                         .as_bytes()
-    //                         .to_vec(),
-    //                         -amount,
-    //                     );
-    //                 }
-
-    //                 let string_args: Vec<String> = action
-    //                     .args
-    //                     .iter()
-    //                     .map(|v| String::from_utf8(v.clone()).unwrap()) // or handle error properly
-    //                     .collect();
-
-    //                 let result = WASM::call(
-    //                     env.clone(),      // MapEnv
-    //                     &bytecode,        // &[u8]
-    //                     &action.function, // &str
-    //                     &string_args,     // &[String]
-    //                 ).unwrap();
-
-    //                 let muts = Process::get("mutations").unwrap_or_default();
-    //                 Process::delete("mutations");
-    //                 let muts_rev = Process::get("mutations_reverse").unwrap_or_default();
-    //                 Process::delete("mutations_reverse");
-
-    //                 let exec_used = result.exec_used.unwrap_or(0) * 100;
-    //                 ConsensusKV::kv_increment(
-    //                     format!(
-    //                         "bic:coin:balance:{}:AMA",
-    //                         String::from_utf8(env.entry_signer).unwrap()
-    //                     )
-    //                     .as_bytes()
-    //                     .to_vec(),
-    //                     exec_used,
-    //                 );
-    //                 ConsensusKV::kv_increment(
-    //                     format!("bic:coin:balance:{}:AMA", env.tx_signer.as_ref().unwrap())
-    //                         .as_bytes()
-    //                         .to_vec(),
-    //                     -exec_used,
-    //                 );
-
-    //                 Process::put(
-    //                     "mutations_gas",
-    //                     Process::get("mutations").unwrap_or_default(),
-    //                 );
-    //                 Process::put(
-    //                     "mutations_gas_reverse",
-    //                     Process::get("mutations_reverse").unwrap_or_default(),
-    //                 );
-    //                 Process::put("mutations", muts);
-    //                 Process::put("mutations_reverse", muts_rev);
-
-    //                 result
-    //             } else {
-    //                 TxResult::Err(TxError::AccountHasNoBytecode);
-    //             }
-    //         } else {
-    //             Self::seed_random(&env.entry_vr, env.tx_hash.unwrap().as_ref(), "0", "");
-
-    //             if !["Epoch", "Coin", "Contract"].contains(&action.contract.as_str()) {
-    //                 return TxResult::Err(TxError::InvalidBic);
-    //             }
-    //             if ![
-    //                 "submit_sol",
-    //                 "transfer",
-    //                 "set_emission_address",
-    //                 "slash_trainer",
-    //                 "deploy",
-    //             ]
-    //             .contains(&action.function.as_str())
-    //             {
-    //                 return TxResult::Err(TxError::InvalidFunction);
-    //             }
-
-    //             let contract: Box<dyn Contract> = match action.contract {
-    //                 ContractType::Epoch => Box::new(Epoch),
-    //                 ContractType::Coin => Box::new(Coin),
-    //                 ContractType::Contract => Box::new(ContractImpl),
-    //             };
-
-    //             match contract.call(&action.function, &env, &action.args) {
-    //                 Ok(_) => HashMap::from([("error", "ok")]),
-    //                 Err(_) => HashMap::from([("error", "failed")]),
-    //             }
-    //         }
-    //     })();
-
-    //     (
-    //         Process::get("mutations").unwrap_or_default(),
-    //         Process::get("mutations_reverse").unwrap_or_default(),
-    //         Process::get("mutations_gas").unwrap_or_default(),
-    //         Process::get("mutations_gas_reverse").unwrap_or_default(),
-    //         (),
-    //     )
-    // }
+    /// Bumps the signer nonces for every tx before any of them runs
+    pub fn call_txs_pre_parallel(
+        kv: &mut ConsensusKV,
+        _env: &MapEnv,
+        txus: &[Txu],
+    ) -> Result<(Vec<Mutation>, Vec<Mutation>), rocksdb::Error> {
+        for txu in txus {
+            kv.kv_put(
+                Self::nonce_key(&txu.tx.signer),
+                txu.tx.nonce.to_string().into_bytes(),
+            )?;
+        }
+        Ok(kv.take_mutations())
+    }
+
+    /// Runs the tx action and returns (muts, muts_rev, muts_gas, muts_gas_rev, result).
+    /// A failed action is reverted here, only the gas mutations survive it.
+    /// Only a storage failure is an error, it fails the whole entry.
+    #[allow(clippy::type_complexity)]
+    pub fn call_tx_actions(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        txu: &Txu,
+    ) -> Result<
+        (
+            Vec<Mutation>,
+            Vec<Mutation>,
+            Vec<Mutation>,
+            Vec<Mutation>,
+            TxReturn,
+        ),
+        KvError,
+    > {
+        let result = match Self::call_tx_action(kv, env, txu) {
+            Ok(result) => result,
+            Err(err) => Self::error_return(err),
+        };
+        let (mut muts, mut muts_rev) = kv.take_mutations();
+        if !result.is_ok() {
+            kv.revert(&muts_rev)?;
+            muts.clear();
+            muts_rev.clear();
+        }
+
+        // the tx fee and the exec used by the wasm runtime are paid to the entry signer,
+        // a failed tx still pays both. TXPool only admits signers that cover the tx fee,
+        // the exec on top of it is capped at what the signer has left.
+        let schedule = GasSchedule::for_epoch(env.entry_epoch);
+        let fee = Self::exec_cost(env.entry_epoch, txu) + schedule.exec_fee(result.exec_used);
+        let signer = env.tx_signer.clone().unwrap_or_default();
+        let fee = fee.min(Coin::tx_balance(kv, &signer, "AMA")?.max(0));
+        kv.kv_increment(Coin::balance_key(&env.entry_signer, "AMA"), fee)?;
+        kv.kv_increment(Coin::balance_key(&signer, "AMA"), -fee)?;
+        let (muts_gas, muts_gas_rev) = kv.take_mutations();
+
+        Ok((muts, muts_rev, muts_gas, muts_gas_rev, result))
+    }
+
+    fn error_return(err: BicError) -> TxReturn {
+        TxReturn {
+            error: err.to_string(),
+            result: None,
+            logs: vec![],
+            exec_used: 0,
+        }
+    }
+
+    fn call_tx_action(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        txu: &Txu,
+    ) -> Result<TxReturn, BicError> {
+        let action = txu.tx.actions.first().ok_or(BicError::InvalidFunction)?;
+
+        env.account_current = Some(action.contract.as_bytes().to_vec());
+        // every tx starts with a full budget, nested calls and deploy init share it
+        env.call_exec_points_remaining = env.call_exec_points;
+
+        if BlsRs::validate_public_key(action.contract.as_bytes()) {
+            let bytecode = Contract::bytecode(kv, action.contract.as_bytes())?
+                .ok_or(BicError::AccountHasNoBytecode)?;
+
+            let seed_bin = Self::seed_random(
+                &env.entry_vr,
+                env.tx_hash.as_deref().unwrap_or_default(),
+                "0",
+                &env.call_counter.to_string(),
+            );
+            env.seedf64 = f64::from_le_bytes(seed_bin[..8].try_into().unwrap());
+            env.seed = Some(seed_bin);
+
+            if let (Some(symbol), Some(amount)) = (&action.attached_symbol, action.attached_amount)
+            {
+                let amount = amount as i64;
+                let signer = env.tx_signer.clone().unwrap_or_default();
+                let symbol = Coin::resolve_symbol(kv, symbol.as_bytes())?;
+                if amount <= 0 {
+                    return Err(BicError::InvalidAttachedAmount);
+                }
+                if amount > Coin::tx_balance(kv, &signer, &symbol)? {
+                    return Err(BicError::AttachedAmountInsufficientFunds);
+                }
+
+                kv.kv_increment(
+                    Coin::balance_key(action.contract.as_bytes(), &symbol),
+                    amount,
+                )?;
+                kv.kv_increment(Coin::balance_key(&signer, &symbol), -amount)?;
+
+                env.attached_symbol = symbol;
+                env.attached_amount = amount;
+            }
+
+            Ok(WASM::call(
+                kv,
+                env,
+                &bytecode,
+                &action.function,
+                &action.args,
+            ))
+        } else {
+            env.seed = Some(Self::seed_random(
+                &env.entry_vr,
+                env.tx_hash.as_deref().unwrap_or_default(),
+                "0",
+                "",
+            ));
+
+            // built-ins run as the signer, so an attachment never leaves its account;
+            // it is only exposed to the deploy `init` call
+            if let (Some(symbol), Some(amount)) = (&action.attached_symbol, action.attached_amount)
+            {
+                let amount = amount as i64;
+                let signer = env.tx_signer.clone().unwrap_or_default();
+                let symbol = Coin::resolve_symbol(kv, symbol.as_bytes())?;
+                if amount <= 0 {
+                    return Err(BicError::InvalidAttachedAmount);
+                }
+                if amount > Coin::tx_balance(kv, &signer, &symbol)? {
+                    return Err(BicError::AttachedAmountInsufficientFunds);
+                }
+                env.attached_symbol = symbol;
+                env.attached_amount = amount;
+            }
+
+            let result = match action.contract.as_str() {
+                "Coin" => Coin::call(kv, env, &action.function, &action.args)?,
+                "Contract" => return Contract::call(kv, env, &action.function, &action.args),
+                "Epoch" => Epoch::call(kv, env, &action.function, &action.args)?,
+                _ => return Err(BicError::InvalidBic),
+            };
+
+            Ok(TxReturn::ok(result, vec![], 0))
+        }
+    }
+
+    /// Runs once per entry after all txs
+    pub fn call_exit(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+    ) -> Result<(Vec<Mutation>, Vec<Mutation>), KvError> {
+        if env.entry_height % 1000 == 0 {
+            kv.kv_put(b"bic:epoch:segment_vr".to_vec(), env.entry_vr.clone())?;
+            kv.kv_put(
+                b"bic:epoch:segment_vr_hash".to_vec(),
+                env.entry_vr_b3.clone(),
+            )?;
+        }
+        if (env.entry_height + 1) % Epoch::EPOCH_INTERVAL == 0 {
+            Epoch::next(kv, env)?;
+        }
+
+        Ok(kv.take_mutations())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_capped_at_balance() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let sk = [81u8; 64];
+        let signer = BlsRs::get_public_key(&sk).unwrap();
+        let receiver = BlsRs::get_public_key(&[82u8; 64]).unwrap();
+        kv.kv_increment(Coin::balance_key(&signer, "AMA"), 5)
+            .unwrap();
+        kv.take_mutations();
+
+        let args = vec![receiver, b"10".to_vec()];
+        let tx_packed = TX::build(&sk, "Coin", "transfer", args, Some(1), None, None);
+        let txu = TX::unpack(&tx_packed).unwrap();
+        let mut env = Consensus::make_mapenv(&EntryGenesis::get());
+        env.tx_signer = Some(signer.clone());
+        env.account_caller = Some(signer.clone());
+        let earned = Coin::tx_balance(&kv, &env.entry_signer, "AMA").unwrap();
+        assert!(Base::exec_cost(env.entry_epoch, &txu) > 5);
+
+        let (muts, _, _, _, result) = Base::call_tx_actions(&mut kv, &mut env, &txu).unwrap();
+
+        // the failed tx pays what the signer has and never leaves it negative
+        assert_eq!(result.error, "insufficient_funds");
+        assert!(muts.is_empty());
+        assert_eq!(Coin::tx_balance(&kv, &signer, "AMA").unwrap(), 0);
+        assert_eq!(
+            Coin::tx_balance(&kv, &env.entry_signer, "AMA").unwrap(),
+            earned + 5
+        );
+    }
 }
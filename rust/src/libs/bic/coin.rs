@@ -32,17 +32,239 @@ impl Coin {
     }
 
     pub fn burn_balance(symbol: &str) -> i64 {
-        Self::balance(Self::BURN_ADDRESS, symbol)
+        Self::balance(Self::BURN_ADDRESS.as_bytes(), symbol)
     }
 
-    pub fn balance(pubkey: &str, symbol: &str) -> i64 {
-        let key = format!("bic:coin:balance:{}:{}", pubkey, symbol);
-        let raw_value: Option<Vec<u8>> = ConsensusKV::kv_get(&key.as_bytes());
-        if let Some(value) = raw_value {
-            i64::from_be_bytes(value[..8].try_into().unwrap())
-        } else {
-            0
+    pub fn balance_key(pubkey: &[u8], symbol: &str) -> Vec<u8> {
+        [
+            b"bic:coin:balance:".as_slice(),
+            pubkey,
+            b":",
+            symbol.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Committed balance, read outside of entry application
+    pub fn balance(pubkey: &[u8], symbol: &str) -> i64 {
+        match ConsensusKV::chain_get(&Self::balance_key(pubkey, symbol)) {
+            Some(value) => ConsensusKV::parse_int(&value),
+            None => 0,
+        }
+    }
+
+    /// Balance as seen inside the entry transaction
+    pub fn tx_balance(
+        kv: &ConsensusKV,
+        pubkey: &[u8],
+        symbol: &str,
+    ) -> Result<i64, rocksdb::Error> {
+        Ok(match kv.kv_get(&Self::balance_key(pubkey, symbol))? {
+            Some(value) => ConsensusKV::parse_int(&value),
+            None => 0,
+        })
+    }
+
+    pub fn total_supply_key(symbol: &str) -> Vec<u8> {
+        format!("bic:coin:totalSupply:{}", symbol).into_bytes()
+    }
+
+    pub fn permission_key(symbol: &str) -> Vec<u8> {
+        format!("bic:coin:permission:{}", symbol).into_bytes()
+    }
+
+    pub fn mintable_key(symbol: &str) -> Vec<u8> {
+        format!("bic:coin:mintable:{}", symbol).into_bytes()
+    }
+
+    pub fn pausable_key(symbol: &str) -> Vec<u8> {
+        format!("bic:coin:pausable:{}", symbol).into_bytes()
+    }
+
+    pub fn paused_key(symbol: &str) -> Vec<u8> {
+        format!("bic:coin:paused:{}", symbol).into_bytes()
+    }
+
+    pub fn call(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        function: &str,
+        args: &[Vec<u8>],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        match (function, args) {
+            ("transfer", [receiver, amount]) => Self::transfer(kv, env, receiver, amount, b"AMA"),
+            ("transfer", [symbol, receiver, amount]) if symbol.as_slice() == b"AMA" => {
+                Self::transfer(kv, env, receiver, amount, symbol)
+            }
+            ("transfer", [receiver, amount, symbol]) => {
+                Self::transfer(kv, env, receiver, amount, symbol)
+            }
+            ("create_and_mint", [symbol, amount, decimals, mintable, pausable]) => {
+                Self::create_and_mint(kv, env, symbol, amount, decimals, mintable, pausable)
+            }
+            ("mint", [symbol, amount]) => Self::mint(kv, env, symbol, amount),
+            ("pause", [symbol, direction]) => Self::pause(kv, env, symbol, direction),
+            ("transfer" | "create_and_mint" | "mint" | "pause", _) => Err(BicError::InvalidArgs),
+            _ => Err(BicError::InvalidFunction),
+        }
+    }
+
+    fn transfer(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        receiver: &[u8],
+        amount: &[u8],
+        symbol: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        let caller = env.account_caller.as_ref().ok_or(BicError::InvalidArgs)?;
+        let symbol = Self::resolve_symbol(kv, symbol)?;
+        let amount = Self::parse_amount(amount)?;
+        if !TX::valid_pk(receiver) {
+            return Err(BicError::Abort("invalid_receiver_pk".into()));
+        }
+        if amount > Self::tx_balance(kv, caller, &symbol)? {
+            return Err(BicError::Abort("insufficient_funds".into()));
         }
+        if Self::is_paused(kv, &symbol)? {
+            return Err(BicError::Abort("paused".into()));
+        }
+
+        kv.kv_increment(Self::balance_key(caller, &symbol), -amount)?;
+        kv.kv_increment(Self::balance_key(receiver, &symbol), amount)?;
+        Ok(None)
+    }
+
+    fn create_and_mint(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        symbol: &[u8],
+        amount: &[u8],
+        decimals: &[u8],
+        mintable: &[u8],
+        pausable: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        let caller = env.account_caller.as_ref().ok_or(BicError::InvalidArgs)?;
+        let symbol = Self::parse_symbol(symbol)?;
+        let amount = Self::parse_amount(amount)?;
+        let decimals: u32 = std::str::from_utf8(decimals)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| BicError::Abort("invalid_decimals".into()))?;
+
+        CoinSymbolRegistry::register(kv, &symbol, caller, decimals, env.entry_height)?;
+        kv.kv_increment(Self::balance_key(caller, &symbol), amount)?;
+        kv.kv_increment(Self::total_supply_key(&symbol), amount)?;
+        kv.kv_put(
+            Self::permission_key(&symbol),
+            bincode::serialize(&vec![caller.clone()]).unwrap(),
+        )?;
+        if mintable == b"true" {
+            kv.kv_put(Self::mintable_key(&symbol), b"true".to_vec())?;
+        }
+        if pausable == b"true" {
+            kv.kv_put(Self::pausable_key(&symbol), b"true".to_vec())?;
+        }
+        Ok(None)
+    }
+
+    fn mint(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        symbol: &[u8],
+        amount: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        let caller = env.account_caller.as_ref().ok_or(BicError::InvalidArgs)?;
+        let symbol = Self::resolve_symbol(kv, symbol)?;
+        let amount = Self::parse_amount(amount)?;
+        Self::check_admin(kv, caller, &symbol)?;
+        if kv.kv_get(&Self::mintable_key(&symbol))?.as_deref() != Some(b"true") {
+            return Err(BicError::Abort("not_mintable".into()));
+        }
+        if Self::is_paused(kv, &symbol)? {
+            return Err(BicError::Abort("paused".into()));
+        }
+        // balances and the supply are read back as i64, neither may wrap
+        let supply = kv
+            .kv_get(&Self::total_supply_key(&symbol))?
+            .map_or(0, |value| ConsensusKV::parse_int(&value));
+        if supply.checked_add(amount).is_none() {
+            return Err(BicError::Abort("supply_overflow".into()));
+        }
+        if Self::tx_balance(kv, caller, &symbol)?
+            .checked_add(amount)
+            .is_none()
+        {
+            return Err(BicError::Abort("balance_overflow".into()));
+        }
+
+        kv.kv_increment(Self::balance_key(caller, &symbol), amount)?;
+        kv.kv_increment(Self::total_supply_key(&symbol), amount)?;
+        Ok(None)
+    }
+
+    fn pause(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        symbol: &[u8],
+        direction: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        let caller = env.account_caller.as_ref().ok_or(BicError::InvalidArgs)?;
+        let symbol = Self::resolve_symbol(kv, symbol)?;
+        if direction != b"true" && direction != b"false" {
+            return Err(BicError::Abort("invalid_direction".into()));
+        }
+        Self::check_admin(kv, caller, &symbol)?;
+        if kv.kv_get(&Self::pausable_key(&symbol))?.as_deref() != Some(b"true") {
+            return Err(BicError::Abort("not_pausable".into()));
+        }
+
+        kv.kv_put(Self::paused_key(&symbol), direction.to_vec())?;
+        Ok(None)
+    }
+
+    /// Only accounts listed in the symbol permission list may mint or pause
+    fn check_admin(kv: &ConsensusKV, caller: &[u8], symbol: &str) -> Result<(), BicError> {
+        let admins: Vec<Vec<u8>> = match kv.kv_get(&Self::permission_key(symbol))? {
+            Some(value) => bincode::deserialize(&value).unwrap_or_default(),
+            None => return Err(BicError::Abort("symbol_doesnt_exist".into())),
+        };
+        if !admins.iter().any(|admin| admin == caller) {
+            return Err(BicError::Abort("no_permissions".into()));
+        }
+        Ok(())
+    }
+
+    fn is_paused(kv: &ConsensusKV, symbol: &str) -> Result<bool, rocksdb::Error> {
+        Ok(
+            kv.kv_get(&Self::pausable_key(symbol))?.as_deref() == Some(b"true")
+                && kv.kv_get(&Self::paused_key(symbol))?.as_deref() == Some(b"true"),
+        )
+    }
+
+    fn parse_symbol(symbol: &[u8]) -> Result<String, BicError> {
+        CoinSymbolRegistry::validate(symbol)
+            .map(str::to_string)
+            .map_err(|err| BicError::Abort(err.into()))
+    }
+
+    /// The symbol as it was registered, so every casing of it reads and writes the same
+    /// keys. AMA is native and never in the registry.
+    pub fn resolve_symbol(kv: &ConsensusKV, symbol: &[u8]) -> Result<String, BicError> {
+        let symbol = Self::parse_symbol(symbol)?;
+        if CoinSymbolRegistry::normalize(&symbol) == "AMA" {
+            return Ok("AMA".to_string());
+        }
+        CoinSymbolRegistry::tx_get(kv, &symbol)?
+            .map(|info| info.symbol)
+            .ok_or_else(|| BicError::Abort("symbol_doesnt_exist".into()))
+    }
+
+    fn parse_amount(amount: &[u8]) -> Result<i64, BicError> {
+        std::str::from_utf8(amount)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|amount| *amount > 0)
+            .ok_or_else(|| BicError::Abort("invalid_amount".into()))
     }
 }
 
@@ -85,10 +307,108 @@ mod coin_tests {
         );
     }
 
+    #[test]
+    fn test_parse_symbol() {
+        assert_eq!(Coin::parse_symbol(b"USDX").unwrap(), "USDX");
+        assert!(Coin::parse_symbol(b"").is_err());
+        assert!(Coin::parse_symbol(b"BAD-SYM").is_err());
+        assert!(Coin::parse_symbol(&[b'A'; 33]).is_err());
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(Coin::parse_amount(b"1000").unwrap(), 1000);
+        assert!(Coin::parse_amount(b"0").is_err());
+        assert!(Coin::parse_amount(b"-5").is_err());
+        assert!(Coin::parse_amount(b"1e9").is_err());
+    }
+
+    fn call(kv: &mut ConsensusKV, env: &MapEnv, function: &str, args: &[&[u8]]) -> String {
+        let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.to_vec()).collect();
+        match Coin::call(kv, env, function, &args) {
+            Ok(_) => "ok".to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_symbol_resolves_to_registered() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let creator = BlsRs::get_public_key(&[71u8; 64]).unwrap();
+        let receiver = BlsRs::get_public_key(&[72u8; 64]).unwrap();
+        let mut env = Consensus::make_mapenv(&EntryGenesis::get());
+        env.account_caller = Some(creator.clone());
+
+        let created = call(
+            &mut kv,
+            &env,
+            "create_and_mint",
+            &[b"UsdX", b"100", b"9", b"true", b"false"],
+        );
+        assert_eq!(created, "ok");
+
+        // any casing moves the balance kept under the registered symbol
+        assert_eq!(
+            call(&mut kv, &env, "transfer", &[&receiver, b"40", b"usdx"]),
+            "ok"
+        );
+        assert_eq!(Coin::tx_balance(&kv, &receiver, "UsdX").unwrap(), 40);
+        assert_eq!(Coin::tx_balance(&kv, &creator, "UsdX").unwrap(), 60);
+        assert!(
+            kv.kv_get(&Coin::balance_key(&receiver, "usdx"))
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(call(&mut kv, &env, "mint", &[b"USDX", b"5"]), "ok");
+        let supply = kv.kv_get(&Coin::total_supply_key("UsdX")).unwrap().unwrap();
+        assert_eq!(ConsensusKV::parse_int(&supply), 105);
+
+        // only registered symbols and AMA can be transferred
+        assert_eq!(
+            call(&mut kv, &env, "transfer", &[&receiver, b"1", b"NOPE"]),
+            "symbol_doesnt_exist"
+        );
+    }
+
+    #[test]
+    fn test_mint_stays_within_i64() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let creator = BlsRs::get_public_key(&[73u8; 64]).unwrap();
+        let mut env = Consensus::make_mapenv(&EntryGenesis::get());
+        env.account_caller = Some(creator.clone());
+        let created = call(
+            &mut kv,
+            &env,
+            "create_and_mint",
+            &[b"BIGX", b"100", b"9", b"true", b"false"],
+        );
+        assert_eq!(created, "ok");
+
+        let max = i64::MAX.to_string();
+        assert_eq!(
+            call(&mut kv, &env, "mint", &[b"BIGX", max.as_bytes()]),
+            "supply_overflow"
+        );
+
+        let near_max = (i64::MAX - 10).to_string().into_bytes();
+        kv.kv_put(Coin::balance_key(&creator, "BIGX"), near_max)
+            .unwrap();
+        assert_eq!(
+            call(&mut kv, &env, "mint", &[b"BIGX", b"20"]),
+            "balance_overflow"
+        );
+        let supply = kv.kv_get(&Coin::total_supply_key("BIGX")).unwrap().unwrap();
+        assert_eq!(ConsensusKV::parse_int(&supply), 100);
+    }
+
     //  Fabric not initialized
     #[test]
     fn test_balance_zero() {
-        let pubkey = "pubkey1";
+        let pubkey = b"pubkey1";
         let symbol = "SYM";
         let balance = Coin::balance(pubkey, symbol);
         assert_eq!(balance, 0);
@@ -96,7 +416,7 @@ mod coin_tests {
 
     #[test]
     fn test_balance_set() {
-        let pubkey = "pubkey1";
+        let pubkey = b"pubkey1";
         let symbol = "SYM";
         let value: i64 = 12345;
         // ConsensusKV::kv_set(
@@ -0,0 +1,139 @@
+use rocksdb::{Direction, IteratorMode};
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+pub const SYMBOL_REGISTRY_PREFIX: &[u8] = b"bic:coin:symbol:";
+
+/// Registry record of a user created token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SymbolInfo {
+    pub symbol: String,
+    pub creator: Vec<u8>,
+    pub decimals: u32,
+    pub created_height: u64,
+}
+
+pub struct CoinSymbolRegistry;
+
+impl CoinSymbolRegistry {
+    pub const MIN_LEN: usize = 1;
+    pub const MAX_LEN: usize = 32;
+    pub const MAX_DECIMALS: u32 = 18;
+
+    /// Checks length and charset. Symbols keep the case they were created with,
+    /// but two symbols that only differ in case are the same registry entry.
+    pub fn validate(symbol: &[u8]) -> Result<&str, &'static str> {
+        let symbol = std::str::from_utf8(symbol).map_err(|_| "invalid_symbol")?;
+        if symbol.len() < Self::MIN_LEN {
+            return Err("symbol_too_short");
+        }
+        if symbol.len() > Self::MAX_LEN {
+            return Err("symbol_too_long");
+        }
+        if !symbol.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err("invalid_symbol");
+        }
+        Ok(symbol)
+    }
+
+    /// Case folded form used for the registry key
+    pub fn normalize(symbol: &str) -> String {
+        symbol.to_ascii_uppercase()
+    }
+
+    pub fn key(symbol: &str) -> Vec<u8> {
+        [SYMBOL_REGISTRY_PREFIX, Self::normalize(symbol).as_bytes()].concat()
+    }
+
+    /// Claims a symbol for `creator`, rejecting reserved and already taken ones
+    pub fn register(
+        kv: &mut ConsensusKV,
+        symbol: &str,
+        creator: &[u8],
+        decimals: u32,
+        created_height: u64,
+    ) -> Result<SymbolInfo, BicError> {
+        if !CoinSymbolReserved::new().is_free_for(symbol, creator) {
+            return Err(BicError::Abort("symbol_reserved".into()));
+        }
+        if decimals > Self::MAX_DECIMALS {
+            return Err(BicError::Abort("invalid_decimals".into()));
+        }
+        if kv.kv_get(&Self::key(symbol))?.is_some() {
+            return Err(BicError::Abort("symbol_exists".into()));
+        }
+
+        let info = SymbolInfo {
+            symbol: symbol.to_string(),
+            creator: creator.to_vec(),
+            decimals,
+            created_height,
+        };
+        kv.kv_put(Self::key(symbol), bincode::serialize(&info).unwrap())?;
+        Ok(info)
+    }
+
+    /// Registry record as seen inside the entry transaction
+    pub fn tx_get(kv: &ConsensusKV, symbol: &str) -> Result<Option<SymbolInfo>, rocksdb::Error> {
+        Ok(kv
+            .kv_get(&Self::key(symbol))?
+            .and_then(|value| bincode::deserialize(&value).ok()))
+    }
+
+    /// Committed registry record, matched case insensitively
+    pub fn get(symbol: &str) -> Option<SymbolInfo> {
+        ConsensusKV::chain_get(&Self::key(symbol))
+            .and_then(|value| bincode::deserialize(&value).ok())
+    }
+
+    /// All registered tokens ordered by normalized symbol
+    pub fn list() -> Vec<SymbolInfo> {
+        Self::list_from(None, usize::MAX)
+    }
+
+    /// Page through the registry, starting after the `after` symbol
+    pub fn list_from(after: Option<&str>, limit: usize) -> Vec<SymbolInfo> {
+        let db = Fabric::db();
+        let cf = Fabric::cf_handle("contractstate");
+        let start = match after {
+            Some(after) => [Self::key(after).as_slice(), &[0]].concat(),
+            None => SYMBOL_REGISTRY_PREFIX.to_vec(),
+        };
+
+        db.iterator_cf(&cf, IteratorMode::From(&start, Direction::Forward))
+            .map_while(|item| item.ok())
+            .take_while(|(key, _)| key.starts_with(SYMBOL_REGISTRY_PREFIX))
+            .filter_map(|(_, value)| bincode::deserialize(&value).ok())
+            .take(limit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(CoinSymbolRegistry::validate(b"UsdX1"), Ok("UsdX1"));
+        assert_eq!(CoinSymbolRegistry::validate(b""), Err("symbol_too_short"));
+        assert_eq!(CoinSymbolRegistry::validate(&[b'A'; 33]), Err("symbol_too_long"));
+        assert_eq!(CoinSymbolRegistry::validate(b"BAD-SYM"), Err("invalid_symbol"));
+        assert_eq!(CoinSymbolRegistry::validate(b"1INCH"), Ok("1INCH"));
+    }
+
+    #[test]
+    fn test_key_is_case_insensitive() {
+        assert_eq!(CoinSymbolRegistry::key("usdx"), CoinSymbolRegistry::key("USDX"));
+        assert_eq!(CoinSymbolRegistry::key("Abc"), b"bic:coin:symbol:ABC".to_vec());
+    }
+
+    #[test]
+    fn test_reserved() {
+        let reserved = CoinSymbolReserved::new();
+        assert!(!reserved.is_free_for("btc", &[1u8; 48]));
+        assert!(!reserved.is_free_for("AMAX", &[1u8; 48]));
+        assert!(reserved.is_free_for("MYTOKEN", &[1u8; 48]));
+    }
+}
@@ -1,9 +1,17 @@
 use std::collections::HashMap;
 
+use crate::*;
+
 pub struct CoinSymbolReserved {
     reserved_list: HashMap<&'static str, &'static str>,
 }
 
+impl Default for CoinSymbolReserved {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CoinSymbolReserved {
     pub fn new() -> Self {
         let mut reserved_list = HashMap::new();
@@ -141,4 +149,19 @@ impl CoinSymbolReserved {
         // Otherwise reserved
         false
     }
+
+    /// Same as `is_free` for an on-chain caller: SYSTEM reservations belong
+    /// to the genesis signer, any other owner is a base58 pk
+    pub fn is_free_for(&self, symbol: &str, caller_pk: &[u8]) -> bool {
+        let upcase_symbol = symbol.to_uppercase();
+        if upcase_symbol.starts_with("AMA") {
+            return false;
+        }
+
+        match self.reserved_list.get(upcase_symbol.as_str()) {
+            None => true,
+            Some(&"SYSTEM") => caller_pk == EntryGenesis::signer().as_slice(),
+            Some(owner) => bs58::encode(caller_pk).into_string() == *owner,
+        }
+    }
 }
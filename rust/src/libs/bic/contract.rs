@@ -1,45 +1,141 @@
-use std::collections::HashMap;
-
 use crate::*;
 
-// use wasmer_ex::validate_contract;
+/// Chain state a view call reads from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewState {
+    Rooted,
+    Temporal,
+}
 
 pub struct Contract;
 
 impl Contract {
-    // pub fn validate(wasmbytes: &[u8], env: MapEnv) -> HashMap<String, String> {
-    //     let mut map_env = HashMap::new();
-
-    //     // Convert env struct into map for WasmerEx
-    //     // (in real code, you’d serialize fields properly)
-    //     map_env.insert("readonly".to_string(), vec![env.readonly as u8]);
-
-    //     match WasmerEx::validate_contract(&map_env, wasmbytes) {
-    //         Ok(_) => {
-    //             let mut res = HashMap::new();
-    //             res.insert("error".to_string(), "ok".to_string());
-    //             res
-    //         }
-    //         Err(reason) => {
-    //             let mut res = HashMap::new();
-    //             res.insert("error".to_string(), "abort".to_string());
-    //             res.insert("reason".to_string(), reason);
-    //             res
-    //         }
-    //     }
-    // }
-
-    pub fn bytecode(account: &str) -> Option<Vec<u8>> {
-        let key = format!("bic:contract:account:{}:bytecode", account);
-        ConsensusKV::kv_get(&key.as_bytes())
-    }
-
-    pub fn call(env: &MapEnv, wasmbytes: &[u8]) {
-        let key = format!(
-            "bic:contract:account:{:?}:bytecode",
-            hex::encode(env.account_caller.as_ref().unwrap())
-        );
-        let _ = ConsensusKV::kv_put(key.as_bytes().to_vec(), wasmbytes.to_vec());
+    /// Upper bound on stored bytecode
+    pub const MAX_BYTECODE_SIZE: usize = 512 * 1024;
+    /// Flat AMA burned per byte of deployed bytecode
+    pub const DEPLOY_COST_PER_BYTE: i64 = 10_000;
+
+    pub fn deploy_cost(wasmbytes: &[u8]) -> i64 {
+        wasmbytes.len() as i64 * Self::DEPLOY_COST_PER_BYTE
+    }
+
+    /// Runs deploy time validation under the gas schedule of `epoch`,
+    /// returning the exported function names
+    pub fn validate(wasmbytes: &[u8], epoch: u64) -> Result<Vec<String>, BicError> {
+        if wasmbytes.is_empty() || wasmbytes.len() > Self::MAX_BYTECODE_SIZE {
+            return Err(BicError::Abort("invalid_bytecode_size".into()));
+        }
+        WasmerRs::validate_contract(wasmbytes, GasSchedule::for_epoch(epoch))
+            .map_err(BicError::Abort)
+    }
+
+    pub fn bytecode_key(account: &[u8]) -> Vec<u8> {
+        [b"bic:contract:account:".as_slice(), account, b":bytecode"].concat()
+    }
+
+    pub fn bytecode(kv: &ConsensusKV, account: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        kv.kv_get(&Self::bytecode_key(account))
+    }
+
+    /// Unlike the other built-ins a deploy can run wasm, so it returns the full TxReturn
+    pub fn call(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        function: &str,
+        args: &[Vec<u8>],
+    ) -> Result<TxReturn, BicError> {
+        match function {
+            "deploy" => {
+                let [wasmbytes] = args else {
+                    return Err(BicError::InvalidArgs);
+                };
+                Self::deploy(kv, env, wasmbytes)
+            }
+            _ => Err(BicError::InvalidFunction),
+        }
+    }
+
+    /// Read-only call against the temporal tip state, no tx and no fee.
+    /// Returns the result, logs and exec used; any write makes it fail with read_only.
+    pub fn view(account: &[u8], function: &str, args: &[Vec<u8>]) -> Result<TxReturn, BicError> {
+        Self::view_at(ViewState::Temporal, account, function, args)
+    }
+
+    /// Runs the view inside a db transaction that is always rolled back.
+    /// For the rooted state the entries above the rooted tip are reverted first.
+    pub fn view_at(
+        state: ViewState,
+        account: &[u8],
+        function: &str,
+        args: &[Vec<u8>],
+    ) -> Result<TxReturn, BicError> {
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+
+        let mut tip = Consensus::chain_tip_entry();
+        if state == ViewState::Rooted {
+            let rooted_height = Fabric::rooted_tip_height().unwrap_or(0);
+            while tip.header_unpacked.height > rooted_height {
+                let m_rev = Fabric::muts_rev(&tip.hash)
+                    .ok_or_else(|| BicError::Abort("missing_muts_rev".into()))?;
+                kv.revert(&m_rev)?;
+                tip = Fabric::entry_by_hash(Some(&tip.header_unpacked.prev_hash))
+                    .ok_or_else(|| BicError::Abort("missing_entry".into()))?;
+            }
+        }
+
+        let bytecode = Self::bytecode(&kv, account)?.ok_or(BicError::AccountHasNoBytecode)?;
+
+        let mut env = Consensus::make_mapenv(&tip);
+        env.readonly = true;
+        env.account_current = Some(account.to_vec());
+        let seed_bin = Base::seed_random(&env.entry_vr, &[], "0", "0");
+        env.seedf64 = f64::from_le_bytes(seed_bin[..8].try_into().unwrap());
+        env.seed = Some(seed_bin);
+
+        let result = WASM::call(&mut kv, &mut env, &bytecode, function, args);
+
+        drop(kv);
+        rtx.rollback()?;
+        Ok(result)
+    }
+
+    /// Stores validated bytecode under the caller account, burns the size based
+    /// fee and runs the optional `init` export with the attached amount.
+    /// `init` draws on what is left of the tx budget and its exec and logs are
+    /// returned like those of any contract call, a failing `init` fails the tx.
+    fn deploy(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        wasmbytes: &[u8],
+    ) -> Result<TxReturn, BicError> {
+        let account = env.account_caller.clone().ok_or(BicError::InvalidArgs)?;
+        let exports = Self::validate(wasmbytes, env.entry_epoch)?;
+
+        let cost = Self::deploy_cost(wasmbytes);
+        if Coin::tx_balance(kv, &account, "AMA")? < cost {
+            return Err(BicError::Abort("insufficient_funds_for_deploy".into()));
+        }
+        kv.kv_increment(Coin::balance_key(&account, "AMA"), -cost)?;
+        kv.kv_increment(
+            Coin::balance_key(Coin::BURN_ADDRESS.as_bytes(), "AMA"),
+            cost,
+        )?;
+
+        kv.kv_put(Self::bytecode_key(&account), wasmbytes.to_vec())?;
+
+        if !exports.iter().any(|name| name == "init") {
+            return Ok(TxReturn::ok(None, vec![], 0));
+        }
+        env.account_current = Some(account);
+        let result = WASM::call(kv, env, wasmbytes, "init", &[]);
+        if !result.is_ok() {
+            return Ok(TxReturn {
+                error: format!("init_failed:{}", result.error),
+                ..result
+            });
+        }
+        Ok(result)
     }
 }
 
@@ -48,12 +144,232 @@ mod contract_tests {
     use super::*;
 
     #[test]
-    fn test_bytecode() {
-        
+    fn test_validate_rejects_bad_size() {
+        assert!(Contract::validate(&[], 0).is_err());
+        assert!(Contract::validate(&vec![0u8; Contract::MAX_BYTECODE_SIZE + 1], 0).is_err());
     }
 
     #[test]
-    fn test_call() {
-        
+    fn test_deploy_cost_scales_with_size() {
+        assert_eq!(
+            Contract::deploy_cost(&[0u8; 100]),
+            100 * Contract::DEPLOY_COST_PER_BYTE
+        );
+    }
+
+    fn wasm(wat: &str) -> Vec<u8> {
+        wasmer::wat2wasm(wat.as_bytes()).unwrap().to_vec()
+    }
+
+    fn validate_err(wat: &str) -> String {
+        Contract::validate(&wasm(wat), 0).unwrap_err().to_string()
+    }
+
+    const MEMORY: &str = r#"(import "env" "memory" (memory 1))"#;
+    const NOOP: &str = r#"(func (export "noop"))"#;
+
+    #[test]
+    fn test_validate_rejects_imports() {
+        let wat = format!(r#"(module {MEMORY} (import "env" "foo" (func)) {NOOP})"#);
+        assert_eq!(validate_err(&wat), "import_not_allowed:env.foo");
+        let wat = format!(r#"(module {MEMORY} (import "wasi" "abort" (func)) {NOOP})"#);
+        assert_eq!(validate_err(&wat), "import_not_allowed:wasi.abort");
+        let wat = format!(r#"(module (import "env" "seed_ptr" (memory 1)) {NOOP})"#);
+        assert_eq!(validate_err(&wat), "invalid_memory_import");
+    }
+
+    #[test]
+    fn test_validate_memory() {
+        let ok = format!("(module {MEMORY} {NOOP})");
+        assert_eq!(
+            Contract::validate(&wasm(&ok), 0).unwrap(),
+            vec!["noop".to_string()]
+        );
+        let ok = format!(r#"(module (import "env" "memory" (memory 1 64)) {NOOP})"#);
+        assert!(Contract::validate(&wasm(&ok), 0).is_ok());
+
+        let cases = [
+            (format!("(module {NOOP})"), "exactly_one_memory_required"),
+            (
+                format!("(module (memory 1) {NOOP})"),
+                "exactly_one_memory_required",
+            ),
+            (
+                format!(r#"(module {MEMORY} (export "m" (memory 0)) {NOOP})"#),
+                "memory_export_not_allowed",
+            ),
+            (
+                format!(r#"(module (import "env" "memory" (func)) {NOOP})"#),
+                "invalid_memory_import",
+            ),
+            (
+                format!(r#"(module (import "env" "memory" (memory 9)) {NOOP})"#),
+                "memory_too_large",
+            ),
+            (
+                format!(r#"(module (import "env" "memory" (memory 1 2)) {NOOP})"#),
+                "memory_maximum_too_small",
+            ),
+            // a second memory needs multi memory, which is disabled
+            (
+                format!("(module {MEMORY} (memory 1) {NOOP})"),
+                "invalid_wasm",
+            ),
+        ];
+        for (wat, err) in cases {
+            assert_eq!(validate_err(&wat), err, "{wat}");
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_start_function() {
+        let wat = format!("(module {MEMORY} (func $start) (start $start) {NOOP})");
+        assert_eq!(validate_err(&wat), "start_function_not_allowed");
+    }
+
+    /// Logs "kv" and stores "v" under "k" on init
+    const INIT_PUT: &str = r#"(module
+        (import "env" "memory" (memory 1))
+        (import "env" "import_log" (func $log (param i32 i32)))
+        (import "env" "import_storage_kv_put" (func $put (param i32 i32 i32 i32) (result i32)))
+        (data (i32.const 1024) "kv")
+        (func (export "init")
+            (call $log (i32.const 1024) (i32.const 2))
+            (drop (call $put (i32.const 1024) (i32.const 1) (i32.const 1025) (i32.const 1))))
+        (func (export "noop")))"#;
+
+    fn deploy_env(account: &[u8]) -> MapEnv {
+        let mut env = Consensus::make_mapenv(&EntryGenesis::get());
+        env.account_caller = Some(account.to_vec());
+        env
+    }
+
+    #[test]
+    fn test_deploy_charges_fee_and_runs_init() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let account = vec![7u8; 48];
+        let mut env = deploy_env(&account);
+        let wasmbytes = wasm(INIT_PUT);
+        let cost = Contract::deploy_cost(&wasmbytes);
+        let burn = Coin::BURN_ADDRESS.as_bytes();
+
+        kv.kv_increment(Coin::balance_key(&account, "AMA"), cost - 1)
+            .unwrap();
+        let args = [wasmbytes.clone()];
+        let err = Contract::call(&mut kv, &mut env, "deploy", &args).unwrap_err();
+        assert_eq!(err.to_string(), "insufficient_funds_for_deploy");
+        assert_eq!(Contract::bytecode(&kv, &account).unwrap(), None);
+
+        kv.kv_increment(Coin::balance_key(&account, "AMA"), 1 + 5)
+            .unwrap();
+        let burned = Coin::tx_balance(&kv, burn, "AMA").unwrap();
+        let result = Contract::call(&mut kv, &mut env, "deploy", &args).unwrap();
+
+        // init is paid for like any contract call
+        assert!(result.is_ok());
+        assert_eq!(result.logs, vec![b"kv".to_vec()]);
+        assert!(result.exec_used > 0);
+        assert_eq!(
+            env.call_exec_points_remaining,
+            env.call_exec_points - result.exec_used
+        );
+        assert_eq!(Coin::tx_balance(&kv, &account, "AMA").unwrap(), 5);
+        assert_eq!(Coin::tx_balance(&kv, burn, "AMA").unwrap(), burned + cost);
+        assert_eq!(Contract::bytecode(&kv, &account).unwrap(), Some(wasmbytes));
+
+        // init ran as the deployed account
+        let key = [b"c:".as_slice(), &account, b":k"].concat();
+        assert_eq!(kv.kv_get(&key).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_deploy_init_rejects_negative_len() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let account = vec![9u8; 48];
+        let mut env = deploy_env(&account);
+        let wasmbytes = wasm(&INIT_PUT.replace(
+            "(i32.const 1024) (i32.const 1)",
+            "(i32.const 1024) (i32.const -1)",
+        ));
+        kv.kv_increment(
+            Coin::balance_key(&account, "AMA"),
+            Contract::deploy_cost(&wasmbytes),
+        )
+        .unwrap();
+
+        let result = Contract::call(&mut kv, &mut env, "deploy", &[wasmbytes]).unwrap();
+        assert_eq!(result.error, "init_failed:invalid_memory");
+    }
+
+    #[test]
+    fn test_deploy_rejects_invalid_bytecode() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let account = vec![8u8; 48];
+        let mut env = deploy_env(&account);
+        let wasmbytes = wasm(&format!("(module (memory 1) {NOOP})"));
+        kv.kv_increment(
+            Coin::balance_key(&account, "AMA"),
+            Contract::deploy_cost(&wasmbytes),
+        )
+        .unwrap();
+
+        let err = Contract::call(&mut kv, &mut env, "deploy", &[wasmbytes]).unwrap_err();
+        assert_eq!(err.to_string(), "exactly_one_memory_required");
+        assert_eq!(Contract::bytecode(&kv, &account).unwrap(), None);
+    }
+
+    #[test]
+    fn test_deploy_tx_with_failing_init() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let sk = [75u8; 64];
+        let signer = BlsRs::get_public_key(&sk).unwrap();
+        let burn = Coin::BURN_ADDRESS.as_bytes();
+        let wasmbytes = wasm(&INIT_PUT.replace(
+            "(i32.const 1025) (i32.const 1))))",
+            "(i32.const 1025) (i32.const 1))) unreachable)",
+        ));
+        let funded = Coin::to_flat(10);
+        kv.kv_increment(Coin::balance_key(&signer, "AMA"), funded)
+            .unwrap();
+        let burned = Coin::tx_balance(&kv, burn, "AMA").unwrap();
+        kv.take_mutations();
+
+        let tx_packed = TX::build(
+            &sk,
+            "Contract",
+            "deploy",
+            vec![wasmbytes],
+            Some(1),
+            None,
+            None,
+        );
+        let txu = TX::unpack(&tx_packed).unwrap();
+        let mut env = deploy_env(&signer);
+        env.tx_signer = Some(signer.clone());
+        env.tx_hash = Some(txu.hash.clone());
+        let (muts, _, _, _, result) = Base::call_tx_actions(&mut kv, &mut env, &txu).unwrap();
+
+        // init ran on the tx budget and failed after writing and logging
+        assert!(result.error.starts_with("init_failed:"), "{}", result.error);
+        assert_eq!(result.logs, vec![b"kv".to_vec()]);
+        assert!(result.exec_used > 0);
+
+        // the deploy is undone, the exec init used is still paid for
+        assert!(muts.is_empty());
+        assert_eq!(Contract::bytecode(&kv, &signer).unwrap(), None);
+        let key = [b"c:".as_slice(), &signer, b":k"].concat();
+        assert_eq!(kv.kv_get(&key).unwrap(), None);
+        assert_eq!(Coin::tx_balance(&kv, burn, "AMA").unwrap(), burned);
+        let schedule = GasSchedule::for_epoch(env.entry_epoch);
+        let fee = Base::exec_cost(env.entry_epoch, &txu) + schedule.exec_fee(result.exec_used);
+        assert_eq!(Coin::tx_balance(&kv, &signer, "AMA").unwrap(), funded - fee);
     }
 }
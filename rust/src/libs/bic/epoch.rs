@@ -1,4 +1,4 @@
-use crate::Coin;
+use crate::*;
 
 pub struct Epoch;
 
@@ -6,10 +6,447 @@ impl Epoch {
     pub const EPOCH_EMISSION_BASE: i64 = Coin::to_flat(1_000_000);
     pub const EPOCH_EMISSION_FIXED: i64 = Coin::to_flat(100_000);
 
-    pub const EPOCH_INTERVAL: i64 = 100_000;
+    pub const EPOCH_INTERVAL: u64 = 100_000;
 
     /// parameters for emission formula
     pub const A: f64 = 23_072_960_000.0;
     pub const C: f64 = 1110.573766;
     pub const START_EPOCH: i64 = 500;
+
+    /// Size of the trainer set elected at each epoch boundary
+    pub const MAX_TRAINERS: usize = 99;
+
+    /// Message trainers sign (DST_MOTION) to agree on slashing `malicious_pk` in `epoch`
+    pub fn slash_trainer_msg(epoch: u64, malicious_pk: &[u8]) -> Vec<u8> {
+        [
+            b"slash_trainer".as_slice(),
+            &(epoch as u32).to_le_bytes(),
+            malicious_pk,
+        ]
+        .concat()
+    }
+
+    /// Flat AMA emitted for `epoch`.
+    /// Before START_EPOCH the base decays by 0.0333% per epoch on top of a fixed part,
+    /// from then on it follows A / (epoch - START_EPOCH + C)^1.5 coins.
+    pub fn emission(epoch: u64) -> i64 {
+        let epoch = epoch as i64;
+        if epoch >= Self::START_EPOCH {
+            let x = (epoch - Self::START_EPOCH) as f64 + Self::C;
+            let coins = Self::A / x.powf(1.5);
+            (coins * Coin::to_flat(1) as f64).floor() as i64
+        } else {
+            let mut acc = Self::EPOCH_EMISSION_BASE;
+            for _ in 0..epoch {
+                acc -= acc * 333 / 1_000_000;
+            }
+            acc + Self::EPOCH_EMISSION_FIXED
+        }
+    }
+
+    /// Closes the epoch on its last height: pays the emission out to solution
+    /// submitters, elects the next trainer set and clears the solution state.
+    pub fn next(kv: &mut ConsensusKV, env: &MapEnv) -> Result<(), KvError> {
+        let epoch = env.entry_epoch;
+
+        let mut leaders: Vec<(Vec<u8>, i64)> = kv
+            .kv_get_prefix(b"bic:epoch:solutions_count:")?
+            .into_iter()
+            .map(|(pk, count)| (pk, ConsensusKV::parse_int(&count)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        leaders.sort_by(|(pk_a, a), (pk_b, b)| b.cmp(a).then_with(|| pk_a.cmp(pk_b)));
+
+        let total_sols: i64 = leaders.iter().map(|(_, count)| count).sum();
+        let emission = Self::emission(epoch) as i128;
+        for (pk, count) in &leaders {
+            let coins = (emission * *count as i128 / total_sols as i128) as i64;
+            let address = kv
+                .kv_get(&Self::emission_address_key(pk))?
+                .unwrap_or_else(|| pk.clone());
+            kv.kv_increment(Coin::balance_key(&address, "AMA"), coins)?;
+        }
+
+        // nobody mined, keep the current set rather than halt the chain
+        let trainers_new: Vec<Vec<u8>> = if leaders.is_empty() {
+            Consensus::trainers_for_height(env.entry_height)
+        } else {
+            leaders
+                .into_iter()
+                .take(Self::MAX_TRAINERS)
+                .map(|(pk, _)| pk)
+                .collect()
+        };
+        let trainers_bin = bincode::serialize(&trainers_new).unwrap();
+        kv.kv_put(
+            format!("bic:epoch:trainers:{}", epoch + 1).into_bytes(),
+            trainers_bin.clone(),
+        )?;
+        kv.kv_put(
+            Consensus::trainers_height_key(env.entry_height + 1),
+            trainers_bin,
+        )?;
+
+        kv.kv_clear(b"bic:epoch:solutions_count:")?;
+        kv.kv_clear(b"bic:epoch:solbloom:")?;
+        Ok(())
+    }
+
+    pub fn solbloom_key(page: usize) -> Vec<u8> {
+        format!("bic:epoch:solbloom:{}", page).into_bytes()
+    }
+
+    pub fn solutions_count_key(pk: &[u8]) -> Vec<u8> {
+        [b"bic:epoch:solutions_count:".as_slice(), pk].concat()
+    }
+
+    pub fn pop_key(pk: &[u8]) -> Vec<u8> {
+        [b"bic:epoch:pop:".as_slice(), pk].concat()
+    }
+
+    pub fn emission_address_key(pk: &[u8]) -> Vec<u8> {
+        [b"bic:epoch:emission_address:".as_slice(), pk].concat()
+    }
+
+    pub fn call(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        function: &str,
+        args: &[Vec<u8>],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        match (function, args) {
+            ("submit_sol", [sol]) => Self::submit_sol(kv, env, sol),
+            ("set_emission_address", [address]) => Self::set_emission_address(kv, env, address),
+            ("slash_trainer", [epoch, malicious_pk, signature, mask_size, mask]) => {
+                Self::slash_trainer(kv, env, epoch, malicious_pk, signature, mask_size, mask)
+            }
+            ("submit_sol" | "set_emission_address" | "slash_trainer", _) => {
+                Err(BicError::InvalidArgs)
+            }
+            _ => Err(BicError::InvalidFunction),
+        }
+    }
+
+    fn submit_sol(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        sol: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        if sol.len() != Sol::SOL_SIZE {
+            return Err(BicError::Abort("invalid_sol_seed_size".into()));
+        }
+        let su = Sol::unpack(sol);
+        let epoch = u32::from_le_bytes(su["epoch"][..].try_into().unwrap()) as u64;
+        if epoch != env.entry_epoch {
+            return Err(BicError::Abort("invalid_epoch".into()));
+        }
+        if kv.kv_get(b"bic:epoch:segment_vr_hash")?.as_ref() != Some(&su["segment_vr_hash"]) {
+            return Err(BicError::Abort("segment_vr_hash".into()));
+        }
+
+        // every bloom bit already set means we have (most likely) seen this sol
+        let segs = SolBloom::segs(blake3::hash(sol).as_bytes());
+        let mut seen = true;
+        for seg in &segs {
+            seen &= kv.kv_get_bit(&Self::solbloom_key(seg.page), seg.bit_offset)?;
+        }
+        if seen {
+            return Err(BicError::Abort("sol_exists".into()));
+        }
+
+        let (pk, pop) = (&su["pk"], &su["pop"]);
+        if !BlsRs::verify(pk, pop, pk, BLS12AggSig::DST_POP) {
+            return Err(BicError::Abort("invalid_pop".into()));
+        }
+        if !Sol::verify(sol).map_err(|err| BicError::Abort(err.into()))? {
+            return Err(BicError::Abort("invalid_sol".into()));
+        }
+
+        for seg in segs {
+            kv.kv_set_bit(
+                Self::solbloom_key(seg.page),
+                seg.bit_offset,
+                SolBloom::PAGE_SIZE,
+            )?;
+        }
+        if kv.kv_get(&Self::pop_key(pk))?.is_none() {
+            kv.kv_put(Self::pop_key(pk), pop.clone())?;
+        }
+        kv.kv_increment(Self::solutions_count_key(pk), 1)?;
+
+        Ok(None)
+    }
+
+    fn set_emission_address(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        address: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        if !BlsRs::validate_public_key(address) {
+            return Err(BicError::Abort("invalid_address_pk".into()));
+        }
+        let signer = env.tx_signer.as_ref().ok_or(BicError::InvalidArgs)?;
+
+        kv.kv_put(Self::emission_address_key(signer), address.to_vec())?;
+        Ok(None)
+    }
+
+    /// Removes a trainer for the rest of the epoch once a special meeting quorum
+    /// signed the motion. The trainer set changes from the next height on.
+    fn slash_trainer(
+        kv: &mut ConsensusKV,
+        env: &MapEnv,
+        epoch: &[u8],
+        malicious_pk: &[u8],
+        signature: &[u8],
+        mask_size: &[u8],
+        mask: &[u8],
+    ) -> Result<Option<Vec<u8>>, BicError> {
+        let epoch = Self::parse_u64(epoch)?;
+        let mask_size = Self::parse_u64(mask_size)? as usize;
+        if epoch != env.entry_epoch {
+            return Err(BicError::Abort("invalid_epoch".into()));
+        }
+
+        let trainers = Self::trainers_next_height(kv, env)?;
+        if !trainers.iter().any(|pk| pk == malicious_pk) {
+            return Err(BicError::Abort("invalid_trainer_pk".into()));
+        }
+        if trainers.len() <= 1 {
+            return Err(BicError::Abort("cannot_slash_last_trainer".into()));
+        }
+        if mask_size != trainers.len() || mask.len() != mask_size.div_ceil(8) {
+            return Err(BicError::Abort("invalid_mask_size".into()));
+        }
+
+        let mask = BLS12AggSig::mask_from_bytes(mask, mask_size);
+        let signers = BLS12AggSig::unmask_trainers(&trainers, &mask);
+        if (signers.len() as f64) < trainers.len() as f64 * Entry::SPECIAL_MEETING_QUORUM {
+            return Err(BicError::Abort("invalid_amount_of_signatures".into()));
+        }
+        let aggpk = BlsRs::aggregate_public_keys(signers)
+            .map_err(|_| BicError::Abort("invalid_signature".into()))?;
+        let msg = Self::slash_trainer_msg(epoch, malicious_pk);
+        if !BlsRs::verify(&aggpk, signature, &msg, BLS12AggSig::DST_MOTION) {
+            return Err(BicError::Abort("invalid_signature".into()));
+        }
+
+        let trainers_new: Vec<Vec<u8>> = trainers
+            .into_iter()
+            .filter(|pk| pk != malicious_pk)
+            .collect();
+        kv.kv_put(
+            Consensus::trainers_height_key(env.entry_height + 1),
+            bincode::serialize(&trainers_new).unwrap(),
+        )?;
+
+        Ok(None)
+    }
+
+    /// The set taking over at the next height. An earlier slash in the same entry has
+    /// already written it, otherwise it is the set of the entry itself.
+    fn trainers_next_height(kv: &ConsensusKV, env: &MapEnv) -> Result<Vec<Vec<u8>>, BicError> {
+        let pending = kv
+            .kv_get(&Consensus::trainers_height_key(env.entry_height + 1))?
+            .and_then(|value| bincode::deserialize(&value).ok());
+        Ok(pending.unwrap_or_else(|| Consensus::trainers_for_height(env.entry_height)))
+    }
+
+    fn parse_u64(value: &[u8]) -> Result<u64, BicError> {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(BicError::InvalidArgs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::prelude::*;
+
+    #[test]
+    fn test_emission_decays_before_start_epoch() {
+        assert_eq!(
+            Epoch::emission(0),
+            Epoch::EPOCH_EMISSION_BASE + Epoch::EPOCH_EMISSION_FIXED
+        );
+        assert_eq!(
+            Epoch::emission(1),
+            Coin::to_flat(1_000_000) - Coin::to_flat(333) + Epoch::EPOCH_EMISSION_FIXED
+        );
+        assert!(Epoch::emission(100) < Epoch::emission(99));
+    }
+
+    #[test]
+    fn test_emission_after_start_epoch() {
+        let at_start = Epoch::emission(Epoch::START_EPOCH as u64);
+        let expected = Epoch::A / Epoch::C.powf(1.5);
+        assert!((Coin::from_flat(at_start as i128) - expected).abs() < 1e-6);
+
+        let mut prev = at_start;
+        for epoch in [501, 600, 1_000, 10_000] {
+            let cur = Epoch::emission(epoch);
+            assert!(cur > 0 && cur < prev);
+            prev = cur;
+        }
+    }
+
+    /// Env of the last entry of epoch 0, where Epoch::next runs
+    fn epoch_end_env() -> MapEnv {
+        let mut entry = EntryGenesis::get();
+        entry.header_unpacked.height = Epoch::EPOCH_INTERVAL - 1;
+        Consensus::make_mapenv(&entry)
+    }
+
+    fn trainers_at(kv: &ConsensusKV, key: &[u8]) -> Vec<Vec<u8>> {
+        bincode::deserialize(&kv.kv_get(key).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_next_pays_emission_by_solution_share() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let env = epoch_end_env();
+        let (miner_a, miner_b, address_b) = (vec![1u8; 48], vec![2u8; 48], vec![3u8; 48]);
+
+        kv.kv_increment(Epoch::solutions_count_key(&miner_a), 3)
+            .unwrap();
+        kv.kv_increment(Epoch::solutions_count_key(&miner_b), 1)
+            .unwrap();
+        kv.kv_put(Epoch::emission_address_key(&miner_b), address_b.clone())
+            .unwrap();
+        kv.kv_set_bit(Epoch::solbloom_key(0), 5, SolBloom::PAGE_SIZE)
+            .unwrap();
+        Epoch::next(&mut kv, &env).unwrap();
+
+        // miner_b asked for its share to be paid to another address
+        let emission = Epoch::emission(0);
+        assert_eq!(
+            Coin::tx_balance(&kv, &miner_a, "AMA").unwrap(),
+            emission * 3 / 4
+        );
+        assert_eq!(Coin::tx_balance(&kv, &miner_b, "AMA").unwrap(), 0);
+        assert_eq!(
+            Coin::tx_balance(&kv, &address_b, "AMA").unwrap(),
+            emission / 4
+        );
+
+        let elected = vec![miner_a, miner_b];
+        assert_eq!(trainers_at(&kv, b"bic:epoch:trainers:1"), elected);
+        let height_key = Consensus::trainers_height_key(Epoch::EPOCH_INTERVAL);
+        assert_eq!(trainers_at(&kv, &height_key), elected);
+
+        // the next epoch starts with no solutions
+        assert!(
+            kv.kv_get_prefix(b"bic:epoch:solutions_count:")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(kv.kv_get_prefix(b"bic:epoch:solbloom:").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_next_elects_top_trainers() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let env = epoch_end_env();
+
+        // one more miner than seats, ties go to the lower pk
+        let miners: Vec<Vec<u8>> = (0..=Epoch::MAX_TRAINERS as u8)
+            .map(|i| vec![i; 48])
+            .collect();
+        for miner in &miners {
+            kv.kv_increment(Epoch::solutions_count_key(miner), 1)
+                .unwrap();
+        }
+        kv.kv_increment(Epoch::solutions_count_key(&miners[Epoch::MAX_TRAINERS]), 1)
+            .unwrap();
+        Epoch::next(&mut kv, &env).unwrap();
+
+        let elected = trainers_at(&kv, b"bic:epoch:trainers:1");
+        assert_eq!(elected.len(), Epoch::MAX_TRAINERS);
+        assert_eq!(elected[0], miners[Epoch::MAX_TRAINERS]);
+        assert_eq!(elected[1..], miners[..Epoch::MAX_TRAINERS - 1]);
+    }
+
+    #[test]
+    fn test_next_without_solutions_keeps_trainers() {
+        let _guard = Fabric::init_for_test();
+        let current = vec![vec![4u8; 48], vec![5u8; 48]];
+        Consensus::invalidate_trainers_cache();
+        TRAINERS_CACHE.insert(0, vec![(0, current.clone())]);
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let env = epoch_end_env();
+
+        Epoch::next(&mut kv, &env).unwrap();
+
+        // no emission is paid, only the carried over set is written
+        let height_key = Consensus::trainers_height_key(Epoch::EPOCH_INTERVAL);
+        let (muts, _) = kv.take_mutations();
+        let keys: Vec<&[u8]> = muts.iter().map(Mutation::key).collect();
+        assert_eq!(
+            keys,
+            vec![b"bic:epoch:trainers:1".as_slice(), height_key.as_slice()]
+        );
+        assert_eq!(trainers_at(&kv, &height_key), current);
+
+        Consensus::invalidate_trainers_cache();
+    }
+
+    fn slash_args(sks: &[[u8; 64]], trainers: &[Vec<u8>], malicious_pk: &[u8]) -> Vec<Vec<u8>> {
+        let signers: Vec<Vec<u8>> = sks
+            .iter()
+            .map(|sk| BlsRs::get_public_key(sk).unwrap())
+            .collect();
+        let signatures = sks
+            .iter()
+            .map(|sk| SpecialMeetingAttestGen::sign_motion(sk, 0, malicious_pk).unwrap())
+            .collect();
+        let mask: BitVec = trainers.iter().map(|pk| signers.contains(pk)).collect();
+        vec![
+            b"0".to_vec(),
+            malicious_pk.to_vec(),
+            BlsRs::aggregate_signatures(signatures).unwrap(),
+            trainers.len().to_string().into_bytes(),
+            BLS12AggSig::mask_to_bytes(&mask),
+        ]
+    }
+
+    #[test]
+    fn test_slash_trainer_twice_in_one_entry() {
+        let _guard = Fabric::init_for_test();
+        let sks = [[61u8; 64], [62u8; 64], [63u8; 64], [64u8; 64]];
+        let trainers: Vec<Vec<u8>> = sks
+            .iter()
+            .map(|sk| BlsRs::get_public_key(sk).unwrap())
+            .collect();
+        Consensus::invalidate_trainers_cache();
+        TRAINERS_CACHE.insert(0, vec![(0, trainers.clone())]);
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let env = Consensus::make_mapenv(&EntryGenesis::get());
+        let height_key = Consensus::trainers_height_key(env.entry_height + 1);
+
+        let args = slash_args(&sks[..3], &trainers, &trainers[3]);
+        Epoch::call(&mut kv, &env, "slash_trainer", &args).unwrap();
+        assert_eq!(trainers_at(&kv, &height_key), trainers[..3]);
+
+        // the second motion is signed over the set the first one left behind
+        let args = slash_args(&sks[..3], &trainers[..3], &trainers[2]);
+        Epoch::call(&mut kv, &env, "slash_trainer", &args).unwrap();
+        assert_eq!(trainers_at(&kv, &height_key), trainers[..2]);
+
+        // a trainer slashed earlier in the entry is no longer in the set
+        let args = slash_args(&sks[..2], &trainers[..2], &trainers[3]);
+        assert!(matches!(
+            Epoch::call(&mut kv, &env, "slash_trainer", &args),
+            Err(BicError::Abort(reason)) if reason == "invalid_trainer_pk"
+        ));
+
+        Consensus::invalidate_trainers_cache();
+    }
 }
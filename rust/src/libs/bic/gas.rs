@@ -0,0 +1,184 @@
+use wasmer::wasmparser::Operator;
+
+use crate::*;
+
+/// Prices for running a tx. Exec points are what the wasm meter counts, the tx
+/// and exec fees are flat AMA paid by the signer to the entry signer.
+/// Schedules are consensus rules: reprice by appending a version with a later
+/// `activation_epoch`, never by editing an active one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasSchedule {
+    pub version: u32,
+    pub activation_epoch: u64,
+
+    /// Exec points per wasm operator, by class
+    pub op_default: u64,
+    pub op_control: u64,
+    pub op_call: u64,
+    pub op_memory: u64,
+
+    /// Exec points per host call, a base plus a price per byte it touches
+    pub log_byte: u64,
+    pub return_value_byte: u64,
+    pub attach_base: u64,
+    pub attach_byte: u64,
+    pub storage_read_base: u64,
+    pub storage_read_byte: u64,
+    pub storage_write_base: u64,
+    pub storage_write_byte: u64,
+    pub call_base: u64,
+    pub call_byte: u64,
+
+    /// Flat fee per tx, charged for every started chunk of the signed tx
+    pub tx_base_cost: i64,
+    pub tx_chunk_cost: i64,
+    pub tx_chunk_bytes: usize,
+    /// Flat AMA per exec point used
+    pub exec_point_price: i64,
+}
+
+pub const GAS_SCHEDULE_V0: GasSchedule = GasSchedule {
+    version: 0,
+    activation_epoch: 0,
+
+    op_default: 1,
+    op_control: 1,
+    op_call: 1,
+    op_memory: 1,
+
+    log_byte: 1000,
+    return_value_byte: 1000,
+    attach_base: 1000,
+    attach_byte: 100,
+    storage_read_base: 48 * 100,
+    storage_read_byte: 100,
+    storage_write_base: 48 * 1000,
+    storage_write_byte: 1000,
+    call_base: 48 * 1000,
+    call_byte: 1000,
+
+    tx_base_cost: 3 * 10_000_000,
+    tx_chunk_cost: 3 * 10_000_000,
+    tx_chunk_bytes: 256,
+    exec_point_price: 100,
+};
+
+/// Every schedule ever activated, ordered by `activation_epoch`
+pub const GAS_SCHEDULES: &[GasSchedule] = &[GAS_SCHEDULE_V0];
+
+impl Default for GasSchedule {
+    fn default() -> Self {
+        GAS_SCHEDULE_V0
+    }
+}
+
+impl GasSchedule {
+    pub fn for_epoch(epoch: u64) -> &'static GasSchedule {
+        GAS_SCHEDULES
+            .iter()
+            .rev()
+            .find(|schedule| epoch >= schedule.activation_epoch)
+            .unwrap_or(&GAS_SCHEDULES[0])
+    }
+
+    pub fn operator_cost(&self, operator: &Operator) -> u64 {
+        match operator {
+            Operator::Loop { .. }
+            | Operator::Block { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::Unreachable => self.op_control,
+
+            Operator::Call { .. } | Operator::CallIndirect { .. } => self.op_call,
+
+            Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Store { .. }
+            | Operator::I64Store { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::MemoryGrow { .. } => self.op_memory,
+
+            _ => self.op_default,
+        }
+    }
+
+    pub fn log(&self, bytes: u64) -> u64 {
+        bytes * self.log_byte
+    }
+
+    pub fn return_value(&self, bytes: u64) -> u64 {
+        bytes * self.return_value_byte
+    }
+
+    pub fn attach(&self, bytes: u64) -> u64 {
+        self.attach_base + bytes * self.attach_byte
+    }
+
+    pub fn storage_read(&self, bytes: u64) -> u64 {
+        self.storage_read_base + bytes * self.storage_read_byte
+    }
+
+    /// Charged once a read returns, for the bytes it hands back
+    pub fn storage_read_returned(&self, bytes: u64) -> u64 {
+        bytes * self.storage_read_byte
+    }
+
+    pub fn storage_write(&self, bytes: u64) -> u64 {
+        self.storage_write_base + bytes * self.storage_write_byte
+    }
+
+    pub fn call(&self, arg_bytes: u64) -> u64 {
+        self.call_base + arg_bytes * self.call_byte
+    }
+
+    /// Fee for a signed tx of `tx_bytes`
+    pub fn tx_cost(&self, tx_bytes: usize) -> i64 {
+        self.tx_base_cost + (tx_bytes / self.tx_chunk_bytes) as i64 * self.tx_chunk_cost
+    }
+
+    pub fn exec_fee(&self, exec_used: u64) -> i64 {
+        exec_used as i64 * self.exec_point_price
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_epoch() {
+        assert_eq!(GasSchedule::for_epoch(0), &GAS_SCHEDULE_V0);
+        assert_eq!(
+            GasSchedule::for_epoch(u64::MAX),
+            GAS_SCHEDULES.last().unwrap()
+        );
+        for pair in GAS_SCHEDULES.windows(2) {
+            assert!(pair[0].activation_epoch < pair[1].activation_epoch);
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+
+    #[test]
+    fn test_tx_cost() {
+        let schedule = GAS_SCHEDULE_V0;
+        assert_eq!(schedule.tx_cost(255), Coin::to_cents(3) as i64);
+        assert_eq!(schedule.tx_cost(256), Coin::to_cents(6) as i64);
+        assert_eq!(schedule.tx_cost(1024), Coin::to_cents(15) as i64);
+    }
+
+    #[test]
+    fn test_storage_priced_by_size() {
+        let schedule = GAS_SCHEDULE_V0;
+        assert_eq!(schedule.storage_read(0), 4_800);
+        assert_eq!(schedule.storage_write(10), 58_000);
+        assert!(schedule.storage_write(1) > schedule.storage_read(1));
+    }
+}
@@ -1,14 +1,22 @@
 pub mod base;
 pub mod coin;
+pub mod coin_symbol_registry;
+pub mod coin_symbol_reserved;
 pub mod contract;
 pub mod epoch;
+pub mod gas;
 pub mod sol;
+pub mod sol_bloom;
 pub mod wasm;
 pub mod wasm_safe;
 pub use base::*;
 pub use coin::*;
+pub use coin_symbol_registry::*;
+pub use coin_symbol_reserved::*;
 pub use contract::*;
 pub use epoch::*;
+pub use gas::*;
 pub use sol::*;
+pub use sol_bloom::*;
 pub use wasm::*;
 pub use wasm_safe::*;
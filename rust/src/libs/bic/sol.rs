@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::*;
+
 pub struct Sol {
     pub epoch : u64,
     pub pk : Vec<u8>,
@@ -75,63 +77,34 @@ impl Sol {
         }
     }
 
-    // pub fn verify(sol: &[u8], opts: Option<HashMap<&str, Vec<u8>>>) -> Result<bool, String> {
-    //     let epoch = u32::from_le_bytes(sol[0..4].try_into().unwrap());
-
-    //     if epoch >= 260 {
-    //         if sol.len() != Self::SOL_SIZE {
-    //             return Err("invalid_sol_seed_size".into());
-    //         }
-    //         let hash = opts
-    //             .as_ref()
-    //             .and_then(|o| o.get("hash"))
-    //             .cloned()
-    //             .unwrap_or_else(|| blake3::hash(sol).as_bytes().to_vec());
-
-    //         let vr_b3 = opts
-    //             .as_ref()
-    //             .and_then(|o| o.get("vr_b3"))
-    //             .cloned()
-    //             .unwrap_or_else(|| rand::random::<[u8; 32]>().to_vec());
-
-    //         Ok(Self::verify_hash(epoch, &hash) && Blake3::freivalds_e260(sol, &vr_b3))
-    //     } else if epoch >= 156 {
-    //         if sol.len() != Self::SOL_SIZE {
-    //             return Err("invalid_sol_seed_size".into());
-    //         }
-    //         let hash = opts
-    //             .as_ref()
-    //             .and_then(|o| o.get("hash"))
-    //             .cloned()
-    //             .unwrap_or_else(|| Blake3::hash(sol).as_bytes().to_vec());
-
-    //         Ok(Self::verify_hash(epoch, &hash) && Blake3::freivalds(sol))
-    //     } else if epoch >= 1 {
-    //         if sol.len() != 320 {
-    //             return Err("invalid_sol_seed_size".into());
-    //         }
-    //         Ok(Self::verify_cache("UPOW1", sol))
-    //     } else {
-    //         if sol.len() != 256 {
-    //             return Err("invalid_sol_seed_size".into());
-    //         }
-    //         Ok(Self::verify_cache("UPOW0", sol))
-    //     }
-    // }
+    /// Recomputes the proof of work behind a solution.
+    /// From epoch 156 the tensor product appended to the seed is checked exactly.
+    pub fn verify(sol: &[u8]) -> Result<bool, &'static str> {
+        if sol.len() < 4 {
+            return Err("invalid_sol_seed_size");
+        }
+        let epoch = u32::from_le_bytes(sol[0..4].try_into().unwrap()) as u64;
 
-    // fn verify_cache(module: &str, sol: &[u8]) -> bool {
-    //     // TODO: Replace with your ETS-like cache.
-    //     // Pseudocode:
-    //     // if cache says "valid" -> remove & return true
-    //     // else -> recalc with UPOW0/UPOW1::calculate
-    //     let epoch = u32::from_le_bytes(sol[0..4].try_into().unwrap());
-    //     let hash = match module {
-    //         "UPOW0" => upow0_calculate(sol),
-    //         "UPOW1" => upow1_calculate(sol),
-    //         _ => return false,
-    //     };
-    //     Self::verify_hash(epoch, &hash)
-    // }
+        if epoch >= 156 {
+            if sol.len() != Self::SOL_SIZE {
+                return Err("invalid_sol_seed_size");
+            }
+            let hash = blake3::hash(sol);
+            let (seed, tensor_c) = sol.split_at(Self::PREAMBLE_SIZE);
+            Ok(Self::verify_hash(epoch, hash.as_bytes())
+                && UPOW2::calculate_matmul(seed) == tensor_c)
+        } else if epoch >= 1 {
+            if sol.len() != 320 {
+                return Err("invalid_sol_seed_size");
+            }
+            Ok(Self::verify_hash(epoch, &UPOW1::calculate(sol)))
+        } else {
+            if sol.len() != 256 {
+                return Err("invalid_sol_seed_size");
+            }
+            Ok(Self::verify_hash(epoch, &UPOW0::calculate(sol)))
+        }
+    }
 }
 
 #[cfg(test)]
@@ -142,4 +115,12 @@ mod tests {
     fn test_sol_size() {
         assert_eq!(Sol::size(), 1264); // 240 + 1024
     }
+
+    #[test]
+    fn test_verify_rejects_bad_size() {
+        let mut sol = vec![0u8; Sol::SOL_SIZE - 1];
+        sol[0..4].copy_from_slice(&300u32.to_le_bytes());
+        assert_eq!(Sol::verify(&sol), Err("invalid_sol_seed_size"));
+        assert_eq!(Sol::verify(&[1, 2]), Err("invalid_sol_seed_size"));
+    }
 }
@@ -1,31 +1,50 @@
-use std::{
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
-};
+use std::sync::mpsc::{self, Receiver};
 
-use rocksdb::Env;
-use tokio::{
-    sync::mpsc,
-    time::{sleep, timeout},
-};
-
-use crate::{ConsensusKV, MapEnv, WASM_SAFE};
+use crate::*;
 
+/// Host requests sent from the wasm thread back to the thread owning the entry transaction
 #[derive(Debug)]
-pub enum Message {
+pub enum WasmMessage {
     RustRequestStorageKvGet {
         rpc_id: u64,
-        key: String,
+        key: Vec<u8>,
+    },
+    RustRequestStorageKvExists {
+        rpc_id: u64,
+        key: Vec<u8>,
+    },
+    RustRequestStorageKvGetPrev {
+        rpc_id: u64,
+        prefix: Vec<u8>,
+        key: Vec<u8>,
+    },
+    RustRequestStorageKvGetNext {
+        rpc_id: u64,
+        prefix: Vec<u8>,
+        key: Vec<u8>,
     },
     RustRequestStorageKvPut {
         rpc_id: u64,
         key: Vec<u8>,
         value: Vec<u8>,
     },
+    RustRequestStorageKvIncrement {
+        rpc_id: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    RustRequestStorageKvDelete {
+        rpc_id: u64,
+        key: Vec<u8>,
+    },
+    RustRequestStorageKvClear {
+        rpc_id: u64,
+        prefix: Vec<u8>,
+    },
     RustRequestCall {
         rpc_id: u64,
         exec_remaining: u64,
-        contract: String,
+        contract: Vec<u8>,
         function: String,
         args: Vec<Vec<u8>>,
         attached_symbol: Option<String>,
@@ -33,92 +52,355 @@ pub enum Message {
     },
     Result {
         error: Option<String>,
-        logs: Vec<String>,
+        logs: Vec<Vec<u8>>,
         exec_remaining: u64,
         retv: Option<Vec<u8>>,
     },
-    Timeout,
 }
 
+impl WasmMessage {
+    /// Request id of a storage write, these are refused for read-only calls
+    fn write_rpc_id(&self) -> Option<u64> {
+        match self {
+            WasmMessage::RustRequestStorageKvPut { rpc_id, .. }
+            | WasmMessage::RustRequestStorageKvIncrement { rpc_id, .. }
+            | WasmMessage::RustRequestStorageKvDelete { rpc_id, .. }
+            | WasmMessage::RustRequestStorageKvClear { rpc_id, .. } => Some(*rpc_id),
+            _ => None,
+        }
+    }
+}
+
+/// Caller state saved while a nested call runs, restored when it returns
 pub struct CallFrame {
-    pub pid: u64, // placeholder for spawned WASM
     pub rpc_id: u64,
-    pub last_account: String,
-    pub last_caller: String,
+    pub last_account: Option<Vec<u8>>,
+    pub last_caller: Option<Vec<u8>>,
+    pub last_attached_symbol: String,
+    pub last_attached_amount: i64,
 }
 
 pub struct WASM;
 
 impl WASM {
+    /// Nested calls allowed below the top level call
+    pub const MAX_CALL_DEPTH: usize = 8;
+
+    /// Runs `function` of a deployed contract. The wasm runs on its own thread while
+    /// this thread serves its storage requests from the entry transaction, so every
+    /// write lands in the kv journal and is reverted by Base if the call fails.
     pub fn call(
-        mapenv: MapEnv,
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
         wasmbytes: &[u8],
         function: &str,
-        args: &[String],
-    ) -> Result<Vec<u8>, String> {
-        // Convert references to owned data
-        let wasmbytes_owned = wasmbytes.to_vec(); // &[u8] -> Vec<u8>
-        let function_owned = function.to_string(); // &str -> String
-        let args_owned = args.to_vec(); // &[String] -> Vec<String>
-
-        WASM_SAFE::spawn(mapenv, wasmbytes_owned, function_owned, args_owned);
-        
-        Ok(vec![]) // Return empty vector as placeholder
+        args: &[Vec<u8>],
+    ) -> TxReturn {
+        Self::call_frame(kv, env, &mut vec![], wasmbytes, function, args)
     }
 
-    pub async fn wasm_loop(
-        mut env: MapEnv,
-        mut callstack: Vec<CallFrame>,
-        rx: &mut Receiver<Message>,
-        tx: Sender<Message>,
-    ) -> MapEnv {
-        let (tx, mut rx) = mpsc::channel::<Message>(100);
+    fn call_frame(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        callstack: &mut Vec<CallFrame>,
+        wasmbytes: &[u8],
+        function: &str,
+        args: &[Vec<u8>],
+    ) -> TxReturn {
+        let (tx, rx) = mpsc::channel::<WasmMessage>();
+
+        let result = std::thread::scope(|scope| {
+            let wasm_env = env.clone();
+            scope.spawn(move || WASM_SAFE::safe_call(&wasm_env, wasmbytes, function, args, tx));
+            Self::wasm_loop(kv, env, callstack, &rx)
+        });
+
+        let WasmMessage::Result {
+            error,
+            logs,
+            exec_remaining,
+            retv,
+        } = result
+        else {
+            unreachable!()
+        };
+        let exec_used = env
+            .call_exec_points_remaining
+            .saturating_sub(exec_remaining);
+        env.call_exec_points_remaining = exec_remaining;
+
+        TxReturn {
+            error: error.unwrap_or_else(|| "ok".to_string()),
+            result: retv,
+            logs,
+            exec_used,
+        }
+    }
 
+    /// Serves host requests until the wasm thread reports its result
+    fn wasm_loop(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        callstack: &mut Vec<CallFrame>,
+        rx: &Receiver<WasmMessage>,
+    ) -> WasmMessage {
         loop {
-            let msg = timeout(Duration::from_millis(1_000), rx.recv()).await;
-
-            match msg {
-                Ok(Some(msg)) => match msg {
-                    Message::RustRequestStorageKvGet { rpc_id, key } => {
-                        let value = ConsensusKV::kv_get(&key.as_bytes());
-                        // respond to WASM runtime
-                        continue;
-                    }
-                    Message::RustRequestStorageKvPut { rpc_id, key, value } => {
-                        ConsensusKV::kv_put(key, value);
-                        // respond to WASM runtime
-                        continue;
-                    }
-                    Message::RustRequestCall {
+            let Ok(msg) = rx.recv() else {
+                return WasmMessage::Result {
+                    error: Some("wasm_crashed".to_string()),
+                    logs: vec![],
+                    exec_remaining: 0,
+                    retv: None,
+                };
+            };
+
+            if let (true, Some(rpc_id)) = (env.readonly, msg.write_rpc_id()) {
+                let _ = WasmerRs::respond_to_rust_storage(rpc_id, Err("read_only".to_string()));
+                continue;
+            }
+
+            let _ = match msg {
+                WasmMessage::RustRequestStorageKvGet { rpc_id, key } => {
+                    let value = kv.kv_get(&key).map_err(|e| e.to_string());
+                    WasmerRs::respond_to_storage_kv_get(rpc_id, value)
+                }
+                WasmMessage::RustRequestStorageKvExists { rpc_id, key } => {
+                    let exists = kv.kv_exists(&key).map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage_kv_exists(rpc_id, exists)
+                }
+                WasmMessage::RustRequestStorageKvGetPrev {
+                    rpc_id,
+                    prefix,
+                    key,
+                } => {
+                    let kv_pair = kv.kv_get_prev(&prefix, &key).map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage_kv_get_prev_next(rpc_id, Self::unzip(kv_pair))
+                }
+                WasmMessage::RustRequestStorageKvGetNext {
+                    rpc_id,
+                    prefix,
+                    key,
+                } => {
+                    let kv_pair = kv.kv_get_next(&prefix, &key).map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage_kv_get_prev_next(rpc_id, Self::unzip(kv_pair))
+                }
+                WasmMessage::RustRequestStorageKvPut { rpc_id, key, value } => {
+                    let response = kv
+                        .kv_put(key, value)
+                        .map(|_| b"ok".to_vec())
+                        .map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage(rpc_id, response)
+                }
+                WasmMessage::RustRequestStorageKvIncrement { rpc_id, key, value } => {
+                    let response = match Self::parse_i128(&value) {
+                        Some(value) => kv
+                            .kv_increment(key, value)
+                            .map(|new_value| new_value.to_string().into_bytes())
+                            .map_err(|e| e.to_string()),
+                        None => Err("invalid_integer".to_string()),
+                    };
+                    WasmerRs::respond_to_rust_storage(rpc_id, response)
+                }
+                WasmMessage::RustRequestStorageKvDelete { rpc_id, key } => {
+                    let response = kv
+                        .kv_delete(key)
+                        .map(|_| b"ok".to_vec())
+                        .map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage(rpc_id, response)
+                }
+                WasmMessage::RustRequestStorageKvClear { rpc_id, prefix } => {
+                    let response = kv
+                        .kv_clear(&prefix)
+                        .map(|count| count.to_string().into_bytes())
+                        .map_err(|e| e.to_string());
+                    WasmerRs::respond_to_rust_storage(rpc_id, response)
+                }
+                WasmMessage::RustRequestCall {
+                    rpc_id,
+                    exec_remaining,
+                    contract,
+                    function,
+                    args,
+                    attached_symbol,
+                    attached_amount,
+                } => {
+                    let frame = CallFrame {
                         rpc_id,
-                        exec_remaining,
-                        contract,
-                        function,
-                        args,
-                        attached_symbol,
-                        attached_amount,
-                    } => {
-                        // preserve your Elixir nested logic for attached_amount, call_counter, bytecode, seed, etc.
-                        continue;
-                    }
-                    Message::Result {
-                        error,
-                        logs,
-                        exec_remaining,
-                        retv,
-                    } => {
-                        // preserve your Elixir result handling logic
-                        continue;
-                    }
-                    Message::Timeout => {
-                        // terminate any spawned instances
-                        break;
-                    }
-                },
-                _ => break,
+                        last_account: env.account_current.clone(),
+                        last_caller: env.account_caller.clone(),
+                        last_attached_symbol: std::mem::take(&mut env.attached_symbol),
+                        last_attached_amount: std::mem::take(&mut env.attached_amount),
+                    };
+                    env.call_exec_points_remaining = exec_remaining;
+
+                    callstack.push(frame);
+                    let result = Self::nested_call(
+                        kv,
+                        env,
+                        callstack,
+                        &contract,
+                        &function,
+                        &args,
+                        attached_symbol.zip(attached_amount),
+                    );
+                    let frame = callstack.pop().unwrap();
+
+                    env.account_current = frame.last_account;
+                    env.account_caller = frame.last_caller;
+                    env.attached_symbol = frame.last_attached_symbol;
+                    env.attached_amount = frame.last_attached_amount;
+
+                    WasmerRs::respond_to_rust_call(
+                        frame.rpc_id,
+                        result.error.into_bytes(),
+                        result.logs,
+                        env.call_exec_points_remaining,
+                        result.result,
+                    )
+                }
+                result @ WasmMessage::Result { .. } => return result,
+            };
+        }
+    }
+
+    /// Runs a contract called from another contract. The current contract becomes the
+    /// caller and pays the attachment out of its own balance. Exec points keep being
+    /// drawn from `env.call_exec_points_remaining`, which is left at what remains.
+    fn nested_call(
+        kv: &mut ConsensusKV,
+        env: &mut MapEnv,
+        callstack: &mut Vec<CallFrame>,
+        contract: &[u8],
+        function: &str,
+        args: &[Vec<u8>],
+        attachment: Option<(String, u64)>,
+    ) -> TxReturn {
+        let fail = |error: &str| TxReturn {
+            error: error.to_string(),
+            result: None,
+            logs: vec![],
+            exec_used: 0,
+        };
+
+        if callstack.len() > Self::MAX_CALL_DEPTH {
+            return fail("max_call_depth");
+        }
+        if !BlsRs::validate_public_key(contract) {
+            return fail("invalid_contract");
+        }
+        let bytecode = match Contract::bytecode(kv, contract) {
+            Ok(Some(bytecode)) => bytecode,
+            Ok(None) => return fail("account_has_no_bytecode"),
+            Err(err) => return fail(&err.to_string()),
+        };
+
+        if env.readonly && attachment.is_some() {
+            return fail("read_only");
+        }
+
+        let caller = env.account_current.clone().unwrap_or_default();
+        let attachment = match attachment {
+            Some((symbol, amount)) => match Coin::resolve_symbol(kv, symbol.as_bytes()) {
+                Ok(symbol) => Some((symbol, amount)),
+                Err(err) => return fail(&err.to_string()),
+            },
+            None => None,
+        };
+        if let Some((symbol, amount)) = &attachment {
+            let amount = *amount as i64;
+            match Coin::tx_balance(kv, &caller, symbol) {
+                Ok(balance) if amount > 0 && amount <= balance => {}
+                Ok(_) => return fail("attached_amount_insufficient_funds"),
+                Err(err) => return fail(&err.to_string()),
             }
+            let moved = kv
+                .kv_increment(Coin::balance_key(contract, symbol), amount)
+                .and_then(|_| kv.kv_increment(Coin::balance_key(&caller, symbol), -amount));
+            if let Err(err) = moved {
+                return fail(&err.to_string());
+            }
+        }
+
+        env.call_counter += 1;
+        let seed_bin = Base::seed_random(
+            &env.entry_vr,
+            env.tx_hash.as_deref().unwrap_or_default(),
+            "0",
+            &env.call_counter.to_string(),
+        );
+        env.seedf64 = f64::from_le_bytes(seed_bin[..8].try_into().unwrap());
+        env.seed = Some(seed_bin);
+
+        env.account_caller = Some(caller);
+        env.account_current = Some(contract.to_vec());
+        if let Some((symbol, amount)) = attachment {
+            env.attached_symbol = symbol;
+            env.attached_amount = amount as i64;
         }
 
-        env
+        Self::call_frame(kv, env, callstack, &bytecode, function, args)
+    }
+
+    fn unzip(
+        kv_pair: Result<Option<(Vec<u8>, Vec<u8>)>, String>,
+    ) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>), String> {
+        kv_pair.map(|kv_pair| match kv_pair {
+            Some((key, value)) => (Some(key), Some(value)),
+            None => (None, None),
+        })
+    }
+
+    fn parse_i128(value: &[u8]) -> Option<i128> {
+        std::str::from_utf8(value).ok()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads "k" and the entry after prefix "k"
+    const READ: &str = r#"(module
+        (import "env" "memory" (memory 1))
+        (import "env" "import_storage_kv_get" (func $get (param i32 i32) (result i32)))
+        (import "env" "import_storage_kv_get_next"
+            (func $next (param i32 i32 i32 i32) (result i32)))
+        (data (i32.const 1024) "k")
+        (func (export "get") (drop (call $get (i32.const 1024) (i32.const 1))))
+        (func (export "next")
+            (drop (call $next (i32.const 1024) (i32.const 1) (i32.const 1024) (i32.const 0)))))"#;
+
+    fn exec_used(kv: &mut ConsensusKV, account: &[u8], function: &str) -> u64 {
+        let wasmbytes = wasmer::wat2wasm(READ.as_bytes()).unwrap();
+        let mut env = Consensus::make_mapenv(&EntryGenesis::get());
+        env.account_current = Some(account.to_vec());
+        env.call_exec_points_remaining = env.call_exec_points;
+        let result = WASM::call(kv, &mut env, &wasmbytes, function, &[]);
+        assert!(result.is_ok(), "{}", result.error);
+        result.exec_used
+    }
+
+    #[test]
+    fn test_storage_read_priced_on_returned_bytes() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let (small, large) = (vec![11u8; 48], vec![12u8; 48]);
+        let key = |account: &[u8], key: &[u8]| [b"c:".as_slice(), account, b":", key].concat();
+        kv.kv_put(key(&small, b"k"), vec![1u8; 1]).unwrap();
+        kv.kv_put(key(&large, b"k"), vec![1u8; 101]).unwrap();
+        kv.kv_put(key(&small, b"k1"), vec![1u8; 1]).unwrap();
+        kv.kv_put(key(&large, b"k1"), vec![1u8; 101]).unwrap();
+
+        // the same instructions and key, only the returned value differs in size
+        let read_byte = GasSchedule::for_epoch(0).storage_read_byte;
+        assert_eq!(
+            exec_used(&mut kv, &large, "get") - exec_used(&mut kv, &small, "get"),
+            100 * read_byte
+        );
+        assert_eq!(
+            exec_used(&mut kv, &large, "next") - exec_used(&mut kv, &small, "next"),
+            100 * read_byte
+        );
     }
 }
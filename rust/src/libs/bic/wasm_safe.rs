@@ -1,3 +1,4 @@
+use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::sync::mpsc;
 
 use crate::*;
@@ -5,24 +6,40 @@ use crate::*;
 pub struct WASM_SAFE;
 
 impl WASM_SAFE {
-    pub async fn safe_call(
-        mapenv: MapEnv,
-        wasmbytes: Vec<u8>,
-        function: String,
-        args: Vec<String>,
+    /// Runs the contract and always answers with a `WasmMessage::Result`,
+    /// even if the runtime panics, so the serving loop never hangs
+    pub fn safe_call(
+        mapenv: &MapEnv,
+        wasmbytes: &[u8],
+        function: &str,
+        args: &[Vec<u8>],
+        parent_tx: mpsc::Sender<WasmMessage>,
     ) {
-        // WasmerRs::call(mapenv, wasmbytes, function, args);
-        // match wasmer_ex_call(parent_tx.clone(), mapenv, &wasmbytes, &function, &args).await {
-        //     WasmerResult::Error(reason) => {
-        //         let _ = parent_tx.send((reason, vec![], 0, None)).await;
-        //     }
-        //     WasmerResult::Ok => {
-        //         // Do nothing
-        //     }
-        // }
-    }
+        let exec_points = mapenv.call_exec_points_remaining;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            WasmerRs::call(mapenv, wasmbytes, function, args, parent_tx.clone())
+        }));
 
-    pub fn spawn(mapenv: MapEnv, wasmbytes: Vec<u8>, function: String, args: Vec<String>) {
-        tokio::spawn(Self::safe_call(mapenv, wasmbytes, function, args));
+        let msg = match result {
+            Ok(Ok((exec_remaining, logs, retv))) => WasmMessage::Result {
+                error: None,
+                logs,
+                exec_remaining,
+                retv,
+            },
+            Ok(Err((error, exec_remaining, logs))) => WasmMessage::Result {
+                error: Some(error),
+                logs,
+                exec_remaining,
+                retv: None,
+            },
+            Err(_) => WasmMessage::Result {
+                error: Some("wasm_panicked".to_string()),
+                logs: vec![],
+                exec_remaining: exec_points,
+                retv: None,
+            },
+        };
+        let _ = parent_tx.send(msg);
     }
-}
\ No newline at end of file
+}
@@ -43,53 +43,66 @@ impl Attestation {
         }
     }
 
-    // Validate the attestation structure & signature
-    // pub fn validate(&self) -> Result<(), AttestationError> {
-    //     if self.entry_hash.len() != 32 {
-    //         return Err(AttestationError::EntryHashInvalid);
-    //     }
-    //     if self.mutations_hash.len() != 32 {
-    //         return Err(AttestationError::MutationsHashInvalid);
-    //     }
-    //     if self.signer.len() != 48 {
-    //         return Err(AttestationError::SignerInvalid);
-    //     }
-
-    //     let mut msg = Vec::new();
-    //     msg.extend_from_slice(&self.entry_hash);
-    //     msg.extend_from_slice(&self.mutations_hash);
-
-    //     if !verify(
-    //?         &PublicKey::from_bytes(&self.signer).unwrap(),
-    //?         &self.signature,
-    //?         &msg,
-    //     ) {
-    //         return Err(AttestationError::InvalidSignature);
-    //     }
-
-    //     Ok(())
-    // }
-
-    // Validate against the chain (pseudo-code)
-    // pub fn validate_vs_chain(&self) -> bool {
-    //     if let Some(entry) = Fabric::entry_by_hash(&self.entry_hash) {
-    //         let chain_height = Consensus::chain_height();
-    //         if entry.header_unpacked.height <= chain_height {
-    //             if let Some(trainers) = Consensus::trainers_for_height(entry.height()) {
-    //                 return trainers.contains(&self.signer);
-    //             }
-    //         }
-    //     }
-    //     false
-    // }
+    /// Validate the attestation structure & signature
+    pub fn validate(&self) -> Result<(), AttestationError> {
+        if self.entry_hash.len() != 32 {
+            return Err(AttestationError::EntryHashInvalid);
+        }
+        if self.mutations_hash.len() != 32 {
+            return Err(AttestationError::MutationsHashInvalid);
+        }
+        if self.signer.len() != 48 {
+            return Err(AttestationError::SignerInvalid);
+        }
+
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&self.entry_hash);
+        msg.extend_from_slice(&self.mutations_hash);
+
+        if !BlsRs::verify(&self.signer, &self.signature, &msg, BLS12AggSig::DST_ATT) {
+            return Err(AttestationError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    /// The attested entry must be known, not ahead of our chain, and signed by one of its trainers
+    pub fn validate_vs_chain(&self) -> Result<(), AttestationError> {
+        let entry = Fabric::entry_by_hash(Some(&self.entry_hash))
+            .ok_or(AttestationError::EntryNotFound)?;
+        let height = entry.header_unpacked.height;
+
+        if height > Consensus::chain_height() {
+            return Err(AttestationError::TooFarInFuture);
+        }
+        if !Consensus::trainers_for_height(height).contains(&self.signer) {
+            return Err(AttestationError::SignerNotTrainer);
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, thiserror::Error)]
 pub enum AttestationError {
+    #[error("entry_hash_invalid")]
     EntryHashInvalid,
+    #[error("mutations_hash_invalid")]
     MutationsHashInvalid,
+    #[error("signer_invalid")]
     SignerInvalid,
+    #[error("invalid_signature")]
     InvalidSignature,
+    #[error("entry_not_found")]
+    EntryNotFound,
+    #[error("too_far_in_future")]
+    TooFarInFuture,
+    #[error("signer_not_trainer")]
+    SignerNotTrainer,
+    #[error("no_trainers")]
+    NoTrainers,
+    #[error("invalid_mask")]
+    InvalidMask,
 }
 
 #[cfg(test)]
@@ -132,6 +145,28 @@ mod attestation_tests {
         println!("Expected error: {:?}", result.err().unwrap());
     }
 
+    #[test]
+    fn test_validate_rejects_bad_lengths() {
+        let mut att = Attestation {
+            entry_hash: vec![0; 31],
+            mutations_hash: vec![0; 32],
+            signer: vec![0; 48],
+            signature: vec![0; 96],
+        };
+        assert_eq!(att.validate(), Err(AttestationError::EntryHashInvalid));
+
+        att.entry_hash = vec![0; 32];
+        att.mutations_hash = vec![0; 33];
+        assert_eq!(att.validate(), Err(AttestationError::MutationsHashInvalid));
+
+        att.mutations_hash = vec![0; 32];
+        att.signer = vec![0; 47];
+        assert_eq!(att.validate(), Err(AttestationError::SignerInvalid));
+
+        att.signer = vec![0; 48];
+        assert_eq!(att.validate(), Err(AttestationError::InvalidSignature));
+    }
+
     #[test]
     fn test_attestation_field_lengths() {
         let att = Attestation {
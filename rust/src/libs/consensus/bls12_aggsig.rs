@@ -22,37 +22,54 @@ impl BLS12AggSig {
     pub const DST_MOTION: &'static [u8] = b"AMADEUS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_MOTION_";
     pub const DST_NODE: &'static [u8] = b"AMADEUS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_NODE_";
 
-    pub fn new(trainers: &[Vec<u8>], pk: Vec<u8>, signature: Signature) -> AggSig {
-        let index_of_trainer = trainers
-            .iter()
-            .position(|t| *t == pk)
-            .expect("Trainer not found");
+    pub fn new(
+        trainers: &[Vec<u8>],
+        pk: Vec<u8>,
+        signature: Signature,
+    ) -> Result<AggSig, CryptoError> {
+        let index_of_trainer = Self::index_of_trainer(trainers, &pk)?;
         let mut mask = bitvec![0; trainers.len()];
         mask.set(index_of_trainer, true);
-        AggSig {
+        Ok(AggSig {
             mask,
             aggsig: signature,
-        }
+        })
     }
 
-    // pub fn add(agg: &AggSig, trainers: &[Vec<u8>], pk: Vec<u8>, signature: Signature) -> AggSig {
-    //     let index_of_trainer = trainers
-    //         .iter()
-    //         .position(|t| *t == pk)
-    //         .expect("Trainer not found");
+    /// Folds one more trainer signature into the aggregate, a no-op if the trainer already signed
+    pub fn add(
+        agg: &AggSig,
+        trainers: &[Vec<u8>],
+        pk: Vec<u8>,
+        signature: Signature,
+    ) -> Result<AggSig, CryptoError> {
+        let index_of_trainer = Self::index_of_trainer(trainers, &pk)?;
+
+        if agg.mask[index_of_trainer] {
+            return Ok(agg.clone());
+        }
+
+        let mut new_mask = agg.mask.clone();
+        new_mask.set(index_of_trainer, true);
+        let aggsig_bytes = BlsRs::aggregate_signatures(vec![
+            agg.aggsig.to_bytes().to_vec(),
+            signature.to_bytes().to_vec(),
+        ])?;
+        let new_aggsig =
+            Signature::from_bytes(&aggsig_bytes).map_err(|_| CryptoError::InvalidSignature)?;
+
+        Ok(AggSig {
+            mask: new_mask,
+            aggsig: new_aggsig,
+        })
+    }
 
-    //     if agg.mask[index_of_trainer] {
-    //         agg.clone()
-    //     } else {
-    //         let mut new_mask = agg.mask.clone();
-    //         new_mask.set(index_of_trainer, true);
-    //         let new_aggsig = aggregate_signatures(&[agg.aggsig.clone(), signature]);
-    //         AggSig {
-    //             mask: new_mask,
-    //             aggsig: new_aggsig,
-    //         }
-    //     }
-    // }
+    fn index_of_trainer(trainers: &[Vec<u8>], pk: &[u8]) -> Result<usize, CryptoError> {
+        trainers
+            .iter()
+            .position(|t| t == pk)
+            .ok_or(CryptoError::SignerNotTrainer)
+    }
 
     pub fn unmask_trainers(trainers: &[Vec<u8>], mask: &BitVec) -> Vec<Vec<u8>> {
         trainers
@@ -62,6 +79,21 @@ impl BLS12AggSig {
             .collect()
     }
 
+    /// Decode a packed mask (msb first, as sent on the wire) for `len` trainers
+    pub fn mask_from_bytes(bytes: &[u8], len: usize) -> BitVec {
+        (0..len)
+            .map(|i| i / 8 < bytes.len() && Util::get_bit(bytes, i))
+            .collect()
+    }
+
+    pub fn mask_to_bytes(mask: &BitVec) -> Vec<u8> {
+        let mut bytes = vec![0u8; mask.len().div_ceil(8)];
+        for i in mask.iter_ones() {
+            Util::set_bit(&mut bytes, i);
+        }
+        bytes
+    }
+
     pub fn score(
         trainers: &[Vec<u8>],
         mask: &BitVec,
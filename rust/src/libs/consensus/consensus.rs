@@ -1,9 +1,17 @@
-use rocksdb::{DB, WriteBatch};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rocksdb::{Direction, IteratorMode};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::*;
 
+/// Trainer sets per epoch as (effective_from_height, trainers), ascending
+pub static TRAINERS_CACHE: Lazy<DashMap<u64, Vec<(u64, Vec<Vec<u8>>)>>> =
+    Lazy::new(|| DashMap::new());
+
+const TRAINERS_HEIGHT_PREFIX: &[u8] = b"bic:epoch:trainers:height:";
+
 #[derive(Debug, Clone)]
 pub struct MapEnv {
     pub readonly: bool,
@@ -41,6 +49,37 @@ pub struct Consensus {
     pub score: Option<f64>,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum ConsensusError {
+    #[error("invalid_entry")]
+    InvalidEntry,
+    #[error("invalid_tx")]
+    InvalidTx,
+    #[error("rocksdb_error")]
+    RocksDB(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Kv(#[from] KvError),
+}
+
+#[derive(Debug, Clone)]
+pub struct ApplyResult {
+    pub error: String,
+    pub attestation_packed: Option<Vec<u8>>,
+    pub mutations_hash: Vec<u8>,
+    pub state_root: Hash32,
+    pub logs: Vec<TxReturn>,
+    pub muts: Vec<Mutation>,
+}
+
+/// Stored in the tx CF under the tx hash, the tx itself lives inside the
+/// packed entry at index_start
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TxResultBinary {
+    pub entry_hash: Vec<u8>,
+    pub result: TxReturn,
+    pub index_start: usize,
+}
+
 impl Consensus {
     pub fn unpack(data: &[u8]) -> Self {
         // In Rust, use bincode or serde_cbor instead of :erlang.binary_to_term
@@ -63,7 +102,7 @@ impl Consensus {
             entry_slot: header.slot,
             entry_prev_slot: header.prev_slot,
             entry_height: header.height,
-            entry_epoch: header.height / 100_000,
+            entry_epoch: header.height / Epoch::EPOCH_INTERVAL,
             entry_vr: header.vr.clone(),
             entry_vr_b3: blake3::hash(&header.vr).as_bytes().to_vec(),
             entry_dr: header.dr.clone(),
@@ -82,42 +121,132 @@ impl Consensus {
         }
     }
 
-    // pub fn validate_vs_chain(&mut self) -> Result<(), String> {
-    //     let to_sign = [self.entry_hash.clone(), self.mutations_hash.clone()].concat();
+    /// Verifies the aggregated trainer signature against the chain and fills in the score
+    pub fn validate_vs_chain(&mut self) -> Result<(), AttestationError> {
+        let to_sign = [self.entry_hash.as_slice(), self.mutations_hash.as_slice()].concat();
 
-    //     let entry = Fabric::entry_by_hash(&self.entry_hash).ok_or("invalid_entry")?;
+        let entry =
+            Fabric::entry_by_hash(Some(&self.entry_hash)).ok_or(AttestationError::EntryNotFound)?;
+        let height = entry.header_unpacked.height;
 
-    //     if entry.header_unpacked.height > Self::chain_height() {
-    //         return Err("too_far_in_future".into());
-    //     }
+        if height > Self::chain_height() {
+            return Err(AttestationError::TooFarInFuture);
+        }
 
-    //     let trainers = Self::trainers_for_height(entry.header_unpacked.height);
-    //     let score = BLS12AggSig::score(&trainers, &self.mask);
+        let trainers = Self::trainers_for_height(height);
+        if trainers.is_empty() {
+            return Err(AttestationError::NoTrainers);
+        }
+        if self.mask.len() != trainers.len().div_ceil(8) {
+            return Err(AttestationError::InvalidMask);
+        }
+        let mask = BLS12AggSig::mask_from_bytes(&self.mask, trainers.len());
 
-    //     let trainers_signed = BLS12AggSig::unmask_trainers(&trainers, &self.mask);
-    //     let aggpk = BlsEx::aggregate_public_keys(&trainers_signed);
+        let trainers_signed = BLS12AggSig::unmask_trainers(&trainers, &mask);
+        if trainers_signed.is_empty() {
+            return Err(AttestationError::InvalidMask);
+        }
+        let aggpk = BlsRs::aggregate_public_keys(trainers_signed)
+            .map_err(|_| AttestationError::SignerInvalid)?;
 
-    //     if !BlsEx::verify(&aggpk, &self.aggsig, &to_sign, BLS12AggSig::dst_att()) {
-    //         return Err("invalid_signature".into());
-    //     }
+        if !BlsRs::verify(&aggpk, &self.aggsig, &to_sign, BLS12AggSig::DST_ATT) {
+            return Err(AttestationError::InvalidSignature);
+        }
 
-    //     self.score = Some(score);
-    //     Ok(())
-    // }
+        let weights = trainers
+            .iter()
+            .map(|pk| (pk.clone(), ConsensusWeight::count(pk) as f64))
+            .collect();
+        self.score = Some(BLS12AggSig::score(&trainers, &mask, &weights));
+        Ok(())
+    }
 
-    // pub fn chain_height() -> u64 {
-    //      Self::chain_tip_entry().header_unpacked.height
-    // }
+    pub fn trainers_height_key(height: u64) -> Vec<u8> {
+        [TRAINERS_HEIGHT_PREFIX, format!("{:012}", height).as_bytes()].concat()
+    }
 
     pub fn trainers_for_height(height: u64) -> Vec<Vec<u8>> {
-        // TODO: implement DB access for trainers
-        vec![]
+        let epoch = height / Epoch::EPOCH_INTERVAL;
+
+        if let Some(schedule) = TRAINERS_CACHE.get(&epoch) {
+            return Self::trainers_in_schedule(&schedule, height);
+        }
+        // an empty schedule is not cached, the epoch's set may not be written yet
+        let schedule = Self::load_trainer_schedule(epoch);
+        if schedule.is_empty() {
+            return vec![];
+        }
+        let schedule = TRAINERS_CACHE.entry(epoch).or_insert_with(|| schedule);
+        Self::trainers_in_schedule(&schedule, height)
+    }
+
+    /// The set whose change took effect last at or before `height`
+    fn trainers_in_schedule(schedule: &[(u64, Vec<Vec<u8>>)], height: u64) -> Vec<Vec<u8>> {
+        schedule
+            .iter()
+            .rev()
+            .find(|(from_height, _)| *from_height <= height)
+            .map(|(_, trainers)| trainers.clone())
+            .unwrap_or_default()
     }
 
-    pub fn trainer_for_slot(height: u64, slot: u64) -> Vec<u8> {
+    pub fn trainer_for_slot(height: u64, slot: u64) -> Option<Vec<u8>> {
         let trainers = Self::trainers_for_height(height);
+        if trainers.is_empty() {
+            return None;
+        }
         let index = (slot % trainers.len() as u64) as usize;
-        trainers[index].clone()
+        Some(trainers[index].clone())
+    }
+
+    /// Drop cached trainer sets, call after trainer keys change or the chain rewinds
+    pub fn invalidate_trainers_cache() {
+        TRAINERS_CACHE.clear();
+    }
+
+    /// The set in effect at the start of the epoch followed by every mid-epoch
+    /// change (removals write a new bic:epoch:trainers:height:* key)
+    fn load_trainer_schedule(epoch: u64) -> Vec<(u64, Vec<Vec<u8>>)> {
+        let db = Fabric::db();
+        let cf = Fabric::cf_handle("contractstate");
+        let epoch_start = epoch * Epoch::EPOCH_INTERVAL;
+
+        let parse = |key: &[u8], value: &[u8]| -> Option<(u64, Vec<Vec<u8>>)> {
+            let height = std::str::from_utf8(key.strip_prefix(TRAINERS_HEIGHT_PREFIX)?)
+                .ok()?
+                .parse()
+                .ok()?;
+            Some((height, bincode::deserialize(value).ok()?))
+        };
+
+        let start_key = Self::trainers_height_key(epoch_start);
+        let mut schedule: Vec<(u64, Vec<Vec<u8>>)> = db
+            .iterator_cf(&cf, IteratorMode::From(&start_key, Direction::Reverse))
+            .map_while(|item| item.ok())
+            .take_while(|(key, _)| key.starts_with(TRAINERS_HEIGHT_PREFIX))
+            .find_map(|(key, value)| parse(&key, &value))
+            .into_iter()
+            .collect();
+
+        let next_key = Self::trainers_height_key(epoch_start + 1);
+        schedule.extend(
+            db.iterator_cf(&cf, IteratorMode::From(&next_key, Direction::Forward))
+                .map_while(|item| item.ok())
+                .take_while(|(key, _)| key.starts_with(TRAINERS_HEIGHT_PREFIX))
+                .filter_map(|(key, value)| parse(&key, &value))
+                .take_while(|(height, _)| *height < epoch_start + Epoch::EPOCH_INTERVAL),
+        );
+
+        if schedule.is_empty() {
+            let epoch_key = format!("bic:epoch:trainers:{}", epoch);
+            if let Some(trainers) = ConsensusKV::chain_get(epoch_key.as_bytes())
+                .and_then(|value| bincode::deserialize(&value).ok())
+            {
+                schedule.push((epoch_start, trainers));
+            }
+        }
+
+        schedule
     }
 
     pub fn chain_height() -> u64 {
@@ -127,174 +256,713 @@ impl Consensus {
     }
 
     pub fn chain_epoch() -> u64 {
-        Self::chain_tip_entry().header_unpacked.height / 100_000
+        Self::chain_tip_entry().header_unpacked.height / Epoch::EPOCH_INTERVAL
+    }
+
+    /// The tip fork choice prefers, which may sit on a branch we have not applied
+    pub fn best_tip() -> Entry {
+        ForkChoice::best_tip().unwrap_or_else(Self::chain_tip_entry)
     }
 
     pub fn chain_tip_entry() -> Entry {
-        Entry::unpack(None).unwrap()
-    }
-
-    // pub fn apply_entry_1(
-    //     next_entry: &Entry,
-    //     cf: &ColumnFamilies,
-    //     db: &DB,
-    // )  {
-    // ) -> Result<ApplyResult, &'static str> {
-    // // Start a RocksDB transaction
-    // let mut rtx = WriteBatch::default();
-
-    // // 1. Create mapenv from entry
-    // let mut mapenv = Self::make_mapenv(next_entry);
-
-    // // 2. Unpack transactions
-    // let txus: Vec<Txu> = next_entry.txs.iter().map(|tx| TX::unpack(tx)).collect();
-
-    // // 3. Pre-parallel call
-    // let (mut m_pre, mut m_rev_pre) = Base::call_txs_pre_parallel(mapenv, txus);
-
-    // // 4. Iterate transactions sequentially
-    // let mut m = m_pre.clone();
-    // let mut m_rev = m_rev_pre.clone();
-    // let mut logs = Vec::new();
-
-    // for (tx_idx, txu) in txus.iter().enumerate() {
-    //     let mut local_env = mapenv.clone();
-
-    //     local_env.tx_index = tx_idx.into();
-    //     local_env.tx_signer = Some(txu.tx.unwrap().signer);
-    //     local_env.tx_hash = txu.hash.clone().into();
-    //     local_env.account_origin = txu.tx.unwrap().signer.clone().into();
-    //     local_env.account_caller = txu.tx.unwrap().signer.clone().into();
-
-    // let (m3, m_rev3, m3_gas, m3_gas_rev, result) =
-    //     Base::call_tx_actions(local_env, txu.clone());
-
-    // if result.error == "ok" {
-    //     m.extend(m3.into_iter().chain(m3_gas.into_iter()));
-    //     m_rev.extend(m_rev3.into_iter().chain(m3_gas_rev.into_iter()));
-    //     logs.push(result);
-    // } else {
-    //     ConsensusKV::revert(&m_rev3);
-    //     m.extend(m3_gas);
-    //     m_rev.extend(m3_gas_rev);
-    //     logs.push(result);
-    // }
-    // }
-
-    // // 5. Call exit
-    // let (m_exit, m_exit_rev) = BIC::Base::call_exit(&mapenv);
-    // m.extend(m_exit);
-    // m_rev.extend(m_exit_rev);
-
-    // // 6. Compute mutations hash
-    // let mutations_hash = ConsensusKV::hash_mutations(&logs, &m);
-
-    // // 7. Attestation
-    // let attestation = Attestation::sign(&next_entry.hash, &mutations_hash);
-    // let attestation_packed = Attestation::pack(&attestation);
-    // db.put_cf(
-    //     &cf.my_attestation_for_entry,
-    //     &next_entry.hash,
-    //     &attestation_packed,
-    // )
-    // .unwrap();
-
-    // // 8. Trainer check
-    // let pk = AMACONFIG.trainer_pk.clone();
-    // let trainers = trainers_for_height(next_entry.height(), &cf, &db);
-    // let is_trainer = trainers.contains(&pk);
-
-    // // 9. Seen time
-    // let seen_time = SystemTime::now()
-    //     .duration_since(UNIX_EPOCH)
-    //     .unwrap()
-    //     .as_millis() as u64;
-    // db.put_cf(
-    //     &cf.my_seen_time_for_entry,
-    //     &next_entry.hash,
-    //     &bincode::serialize(&seen_time).unwrap(),
-    // )
-    // .unwrap();
-
-    // // 10. Update temporal tip and height
-    // db.put_cf(&cf.sysconf, b"temporal_tip", &next_entry.hash)
-    //     .unwrap();
-    // db.put_cf(
-    //     &cf.sysconf,
-    //     b"temporal_height",
-    //     &bincode::serialize(&next_entry.header_unpacked.height).unwrap(),
-    // )
-    // .unwrap();
-
-    // // 11. Reverse mutations
-    // db.put_cf(
-    //     &cf.muts_rev,
-    //     &next_entry.hash,
-    //     &bincode::serialize(&m_rev).unwrap(),
-    // )
-    // .unwrap();
-
-    // // 12. Store tx results
-    // let entry_packed = db.get_cf(&cf.default, &next_entry.hash).unwrap().unwrap();
-    // for (tx_packed, result) in next_entry.txs.iter().zip(logs.iter()) {
-    //     let txu = TX::unpack(tx_packed);
-    //     if let Some((index_start, _size)) = find_binary(entry_packed.as_slice(), tx_packed) {
-    //         let value = bincode::serialize(&TxResultBinary {
-    //             entry_hash: next_entry.hash.clone(),
-    //             result: result.clone(),
-    //             index_start,
-    //         })
-    //         .unwrap();
-    //         db.put_cf(&cf.tx, &txu.hash, &value).unwrap();
-
-    //         let nonce_padded = format!("{:0>20}", txu.tx.nonce);
-    //         db.put_cf(
-    //             &cf.tx_account_nonce,
-    //             format!("{}:{}", txu.tx.signer, nonce_padded),
-    //             &txu.hash,
-    //         )
-    //         .unwrap();
-
-    //         for receiver in TX::known_receivers(&txu) {
-    //             db.put_cf(
-    //                 &cf.tx_receiver_nonce,
-    //                 format!("{}:{}", receiver, nonce_padded),
-    //                 &txu.hash,
-    //             )
-    //             .unwrap();
-    //         }
-    //     }
-    // }
-
-    // // 13. Archival node
-    // if AMACONFIG.archival_node {
-    //     db.put_cf(&cf.muts, &next_entry.hash, &bincode::serialize(&m).unwrap())
-    //         .unwrap();
-    // }
-
-    // // 14. Commit transaction
-    // // RocksDB WriteBatch applied manually
-    // db.write(rtx).unwrap();
-
-    // // 15. Optional attestation dispatch
-    // let ap = if is_trainer {
-    //     FabricCoordinatorGen::send_add_attestation(&attestation);
-    //     Some(attestation_packed.clone())
-    // } else {
-    //     None
-    // };
-
-    // Ok(ApplyResult {
-    //     error: "ok".to_string(),
-    //     attestation_packed: ap,
-    //     mutations_hash,
-    //     logs,
-    //     muts: m,
-    // })
-
-    // }
+        Fabric::temporal_tip_entry().unwrap_or_else(EntryGenesis::get)
+    }
+
+    /// Applies the entry on top of the temporal tip in a single transaction.
+    /// Everything (state, receipts, indexes, tip) is committed at once or not at all.
+    pub fn apply_entry(next_entry: &Entry) -> Result<ApplyResult, ConsensusError> {
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+
+        let mapenv = Self::make_mapenv(next_entry);
+
+        let txus = next_entry
+            .txs
+            .iter()
+            .map(|tx_packed| TX::unpack(tx_packed).map_err(|_| ConsensusError::InvalidTx))
+            .collect::<Result<Vec<Txu>, _>>()?;
+
+        let (mut m, mut m_rev) = Base::call_txs_pre_parallel(&mut kv, &mapenv, &txus)?;
+
+        let mut logs = Vec::with_capacity(txus.len());
+        for (tx_idx, txu) in txus.iter().enumerate() {
+            // every tx starts from the entry env, nothing (attachments, seed, counters)
+            // carries over from the previous one
+            let mut tx_env = mapenv.clone();
+            tx_env.tx_index = tx_idx;
+            tx_env.tx_signer = Some(txu.tx.signer.clone());
+            tx_env.tx_nonce = Some(txu.tx.nonce as u64);
+            tx_env.tx_hash = Some(txu.hash.clone());
+            tx_env.account_origin = Some(txu.tx.signer.clone());
+            tx_env.account_caller = Some(txu.tx.signer.clone());
+
+            let (m3, m_rev3, m3_gas, m3_gas_rev, result) =
+                Base::call_tx_actions(&mut kv, &mut tx_env, txu)?;
+
+            m.extend(m3);
+            m.extend(m3_gas);
+            m_rev.extend(m_rev3);
+            m_rev.extend(m3_gas_rev);
+            logs.push(result);
+        }
+
+        let (m_exit, m_exit_rev) = Base::call_exit(&mut kv, &mapenv)?;
+        m.extend(m_exit);
+        m_rev.extend(m_exit_rev);
+
+        let mutations_hash = ConsensusKV::hash_mutations(&logs, &m);
+        let state_root = StateTree::update(&rtx, m.iter().map(Mutation::key))?;
+        rtx.put_cf(
+            &Fabric::cf_handle("state_root"),
+            &next_entry.hash,
+            state_root,
+        )?;
+
+        let entry_hash: [u8; 32] = next_entry
+            .hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| ConsensusError::InvalidEntry)?;
+        let mutations_hash_32: [u8; 32] = mutations_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| ConsensusError::InvalidEntry)?;
+        let attestation = Attestation::sign(entry_hash, mutations_hash_32);
+        let attestation_packed = attestation.pack();
+        rtx.put_cf(
+            &Fabric::cf_handle("my_attestation_for_entry"),
+            &next_entry.hash,
+            &attestation_packed,
+        )?;
+
+        let seen_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        rtx.put_cf(
+            &Fabric::cf_handle("my_seen_time_for_entry"),
+            &next_entry.hash,
+            bincode::serialize(&seen_time).unwrap(),
+        )?;
+
+        let sysconf = Fabric::cf_handle("sysconf");
+        rtx.put_cf(&sysconf, b"temporal_tip", &next_entry.hash)?;
+        rtx.put_cf(
+            &sysconf,
+            b"temporal_height",
+            bincode::serialize(&next_entry.header_unpacked.height).unwrap(),
+        )?;
+
+        rtx.put_cf(
+            &Fabric::cf_handle("muts_rev"),
+            &next_entry.hash,
+            bincode::serialize(&m_rev).unwrap(),
+        )?;
+
+        let entry_packed = Entry::pack(next_entry.clone());
+        let cf_tx = Fabric::cf_handle("tx");
+        let cf_tx_account_nonce = Fabric::cf_handle("tx_account_nonce");
+        let cf_tx_receiver_nonce = Fabric::cf_handle("tx_receiver_nonce");
+        for (tx_idx, ((tx_packed, txu), result)) in
+            next_entry.txs.iter().zip(&txus).zip(&logs).enumerate()
+        {
+            if let Some(contract) = Self::log_contract(txu, result) {
+                ContractLog::index(
+                    &rtx,
+                    contract,
+                    next_entry.header_unpacked.height,
+                    tx_idx as u32,
+                    &txu.hash,
+                    &result.logs,
+                )?;
+            }
+
+            // the tx was applied, so a missing receipt would leave its effects unindexed
+            let index_start =
+                find_binary(&entry_packed, tx_packed).ok_or(ConsensusError::InvalidTx)?;
+
+            let value = bincode::serialize(&TxResultBinary {
+                entry_hash: next_entry.hash.clone(),
+                result: result.clone(),
+                index_start,
+            })
+            .unwrap();
+            rtx.put_cf(&cf_tx, &txu.hash, value)?;
+
+            let nonce_padded = format!("{:0>20}", txu.tx.nonce);
+            rtx.put_cf(
+                &cf_tx_account_nonce,
+                Self::nonce_index_key(&txu.tx.signer, &nonce_padded),
+                &txu.hash,
+            )?;
+            for receiver in TX::known_receivers(txu) {
+                rtx.put_cf(
+                    &cf_tx_receiver_nonce,
+                    Self::nonce_index_key(&receiver, &nonce_padded),
+                    &txu.hash,
+                )?;
+            }
+        }
+
+        if AMACONFIG.archival_node {
+            rtx.put_cf(
+                &Fabric::cf_handle("muts"),
+                &next_entry.hash,
+                bincode::serialize(&m).unwrap(),
+            )?;
+        }
+
+        drop(kv);
+        rtx.commit()?;
+
+        if m.iter()
+            .any(|mutation| mutation.key().starts_with(b"bic:epoch:trainers:"))
+        {
+            Self::invalidate_trainers_cache();
+        }
+
+        let is_trainer = Self::trainers_for_height(next_entry.header_unpacked.height)
+            .contains(&AMACONFIG.trainer_pk());
+        if is_trainer {
+            FabricCoordinatorGen::send_add_attestation(&attestation);
+        }
+
+        Ok(ApplyResult {
+            error: "ok".to_string(),
+            attestation_packed: is_trainer.then_some(attestation_packed),
+            mutations_hash,
+            state_root,
+            logs,
+            muts: m,
+        })
+    }
+
+    /// Contract whose log index gets the tx logs, only wasm contracts emit logs
+    fn log_contract<'a>(txu: &'a Txu, result: &TxReturn) -> Option<&'a [u8]> {
+        let contract = txu.tx.actions.first()?.contract.as_bytes();
+        (!result.logs.is_empty() && BlsRs::validate_public_key(contract)).then_some(contract)
+    }
+
+    /// Key for the tx_account_nonce / tx_receiver_nonce indexes: account:nonce
+    pub fn nonce_index_key(account: &[u8], nonce_padded: &str) -> Vec<u8> {
+        [account, b":", nonce_padded.as_bytes()].concat()
+    }
+
+    /// Walks back from the temporal tip to the entry at `height`
+    pub fn chain_entry_at_height(height: u64) -> Option<Entry> {
+        let mut entry = Self::chain_tip_entry();
+        while entry.header_unpacked.height > height {
+            entry = Fabric::entry_by_hash(Some(&entry.header_unpacked.prev_hash))?;
+        }
+        (entry.header_unpacked.height == height).then_some(entry)
+    }
+
+    pub fn is_in_chain(hash: &[u8]) -> bool {
+        let Some(entry) = Fabric::entry_by_hash(Some(hash)) else {
+            return false;
+        };
+        Self::chain_entry_at_height(entry.header_unpacked.height)
+            .is_some_and(|chain_entry| chain_entry.hash == hash)
+    }
+
+    /// Undoes every entry from the temporal tip down to and including `target_hash`.
+    /// The entries stay in the fabric, only their effects and tx indexes are removed.
+    /// Returns false if the target is not on our chain or is already rooted.
+    pub fn chain_rewind(target_hash: &[u8]) -> Result<bool, ConsensusError> {
+        if !Self::is_in_chain(target_hash) {
+            return Ok(false);
+        }
+        let target =
+            Fabric::entry_by_hash(Some(target_hash)).ok_or(ConsensusError::InvalidEntry)?;
+        if target.header_unpacked.height <= Fabric::rooted_tip_height().unwrap_or(0) {
+            return Ok(false);
+        }
+
+        let rtx = Fabric::transaction();
+        let kv = ConsensusKV::new(&rtx);
+
+        let cf_tx = Fabric::cf_handle("tx");
+        let cf_tx_account_nonce = Fabric::cf_handle("tx_account_nonce");
+        let cf_tx_receiver_nonce = Fabric::cf_handle("tx_receiver_nonce");
+
+        let mut touched = vec![];
+        let mut current = Self::chain_tip_entry();
+        loop {
+            let m_rev = Fabric::muts_rev(&current.hash).ok_or(ConsensusError::InvalidEntry)?;
+            kv.revert(&m_rev)?;
+            touched.extend(m_rev.iter().map(|mutation| mutation.key().to_vec()));
+
+            for (tx_idx, tx_packed) in current.txs.iter().enumerate() {
+                let txu = TX::unpack(tx_packed).map_err(|_| ConsensusError::InvalidTx)?;
+                let nonce_padded = format!("{:0>20}", txu.tx.nonce);
+
+                if let Some(action) = txu.tx.actions.first() {
+                    ContractLog::unindex(
+                        &rtx,
+                        action.contract.as_bytes(),
+                        current.header_unpacked.height,
+                        tx_idx as u32,
+                    )?;
+                }
+
+                rtx.delete_cf(&cf_tx, &txu.hash)?;
+                rtx.delete_cf(
+                    &cf_tx_account_nonce,
+                    Self::nonce_index_key(&txu.tx.signer, &nonce_padded),
+                )?;
+                for receiver in TX::known_receivers(&txu) {
+                    rtx.delete_cf(
+                        &cf_tx_receiver_nonce,
+                        Self::nonce_index_key(&receiver, &nonce_padded),
+                    )?;
+                }
+            }
+
+            rtx.delete_cf(
+                &Fabric::cf_handle("my_attestation_for_entry"),
+                &current.hash,
+            )?;
+            rtx.delete_cf(&Fabric::cf_handle("muts_rev"), &current.hash)?;
+            rtx.delete_cf(&Fabric::cf_handle("muts"), &current.hash)?;
+            rtx.delete_cf(&Fabric::cf_handle("state_root"), &current.hash)?;
+
+            let prev = Fabric::entry_by_hash(Some(&current.header_unpacked.prev_hash))
+                .ok_or(ConsensusError::InvalidEntry)?;
+            let reached_target = current.hash == target_hash;
+            current = prev;
+            if reached_target {
+                break;
+            }
+        }
+
+        StateTree::update(&rtx, touched.iter().map(Vec::as_slice))?;
+
+        let sysconf = Fabric::cf_handle("sysconf");
+        rtx.put_cf(&sysconf, b"temporal_tip", &current.hash)?;
+        rtx.put_cf(
+            &sysconf,
+            b"temporal_height",
+            bincode::serialize(&current.header_unpacked.height).unwrap(),
+        )?;
+
+        drop(kv);
+        rtx.commit()?;
+        Self::invalidate_trainers_cache();
+        Ok(true)
+    }
+
+    /// Switches the temporal chain over to the branch ending in `tip_hash`.
+    /// Rewinds to the common ancestor and applies the branch entries in order.
+    /// If an entry of the new branch fails, the original branch is put back.
+    pub fn chain_switch(tip_hash: &[u8]) -> Result<Vec<ApplyResult>, ConsensusError> {
+        let mut branch = vec![];
+        let mut hash = tip_hash.to_vec();
+        while !Self::is_in_chain(&hash) {
+            let entry = Fabric::entry_by_hash(Some(&hash)).ok_or(ConsensusError::InvalidEntry)?;
+            hash = entry.header_unpacked.prev_hash.clone();
+            branch.push(entry);
+        }
+        branch.reverse();
+
+        let ancestor = Fabric::entry_by_hash(Some(&hash)).ok_or(ConsensusError::InvalidEntry)?;
+        let original = Self::chain_entries_above(ancestor.header_unpacked.height)?;
+        Self::rewind_above(&ancestor)?;
+
+        match Self::apply_branch(&branch) {
+            Ok(results) => Ok(results),
+            Err(err) => {
+                Self::rewind_above(&ancestor)?;
+                Self::apply_branch(&original)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Our chain entries above `height` up to the temporal tip, in order
+    fn chain_entries_above(height: u64) -> Result<Vec<Entry>, ConsensusError> {
+        let mut entries = vec![];
+        let mut entry = Self::chain_tip_entry();
+        while entry.header_unpacked.height > height {
+            let prev = Fabric::entry_by_hash(Some(&entry.header_unpacked.prev_hash))
+                .ok_or(ConsensusError::InvalidEntry)?;
+            entries.push(entry);
+            entry = prev;
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+
+    fn rewind_above(ancestor: &Entry) -> Result<(), ConsensusError> {
+        if let Some(fork_start) = Self::chain_entry_at_height(ancestor.header_unpacked.height + 1) {
+            if !Self::chain_rewind(&fork_start.hash)? {
+                return Err(ConsensusError::InvalidEntry);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_branch(branch: &[Entry]) -> Result<Vec<ApplyResult>, ConsensusError> {
+        let mut results = Vec::with_capacity(branch.len());
+        for entry in branch {
+            Entry::validate_next(&Self::chain_tip_entry(), entry)
+                .map_err(|_| ConsensusError::InvalidEntry)?;
+            results.push(Self::apply_entry(entry)?);
+        }
+        Ok(results)
+    }
+
+    pub fn chain_nonce(pk: &[u8]) -> Option<u128> {
+        ConsensusKV::chain_get(&Base::nonce_key(pk))
+            .and_then(|value| std::str::from_utf8(&value).ok()?.parse().ok())
+    }
+
+    /// Builds and signs the entry for `slot` on top of the temporal tip,
+    /// filled with valid txs from the pool up to the configured entry size
+    pub fn produce_entry(slot: u64) -> Entry {
+        let cur_entry = Self::chain_tip_entry();
+        let pk = AMACONFIG.trainer_pk();
+        let sk = AMACONFIG.trainer_sk();
+
+        let mut next_entry = Entry::build_next(&cur_entry, slot, &pk, &sk);
+        next_entry.txs = TXPool::grab_next_valid(AMACONFIG.entry_size);
+
+        Entry::sign(next_entry)
+    }
 
     pub fn chain_balance(pk: &Vec<u8>, symbol: Option<String>) -> u64 {
         0
     }
 }
+
+fn find_binary(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRODUCER_SK: [u8; 64] = [7u8; 64];
+    const SENDER_SK: [u8; 64] = [11u8; 64];
+
+    /// Signed entry on top of `prev`, stored in the fabric but not applied
+    fn next_entry(prev: &Entry, txs: Vec<Vec<u8>>) -> Entry {
+        let pk = BlsRs::get_public_key(&PRODUCER_SK).unwrap();
+        let slot = prev.header_unpacked.slot + 1;
+        let mut entry = Entry::build_next(prev, slot, &pk, &PRODUCER_SK);
+        entry.header_unpacked.txs_hash = blake3::hash(&txs.concat()).as_bytes().to_vec();
+        entry.txs = txs;
+        entry.hash = Entry::hash_header(&entry.header_unpacked);
+        entry.signature = BlsRs::sign(&PRODUCER_SK, &entry.hash, BLS12AggSig::DST_ENTRY).unwrap();
+        Fabric::insert_entry(&entry, 0).unwrap();
+        entry
+    }
+
+    fn transfer_tx(nonce: u128, receiver: &[u8], amount: &str) -> Vec<u8> {
+        let args = vec![receiver.to_vec(), amount.as_bytes().to_vec()];
+        TX::build(
+            &SENDER_SK,
+            "Coin",
+            "transfer",
+            args,
+            Some(nonce),
+            None,
+            None,
+        )
+    }
+
+    /// Writes committed contract state outside of any entry, keeping the state tree in step
+    fn commit_state(key: &[u8], value: Option<&[u8]>) {
+        let rtx = Fabric::transaction();
+        let cf = Fabric::cf_handle("contractstate");
+        match value {
+            Some(value) => rtx.put_cf(&cf, key, value).unwrap(),
+            None => rtx.delete_cf(&cf, key).unwrap(),
+        }
+        StateTree::update(&rtx, [key]).unwrap();
+        rtx.commit().unwrap();
+    }
+
+    fn dump(cf: &str) -> Vec<(Box<[u8]>, Box<[u8]>)> {
+        Fabric::db()
+            .iterator_cf(&Fabric::cf_handle(cf), IteratorMode::Start)
+            .map(|item| item.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_entry() {
+        let _guard = Fabric::init_for_test();
+        let sender = BlsRs::get_public_key(&SENDER_SK).unwrap();
+        let receiver = BlsRs::get_public_key(&[12u8; 64]).unwrap();
+        let funded = Coin::to_flat(10).to_string();
+        commit_state(&Coin::balance_key(&sender, "AMA"), Some(funded.as_bytes()));
+
+        let tx_packed = transfer_tx(1, &receiver, "1000");
+        let txu = TX::unpack(&tx_packed).unwrap();
+        let entry = next_entry(&Consensus::chain_tip_entry(), vec![tx_packed.clone()]);
+        let result = Consensus::apply_entry(&entry).unwrap();
+
+        assert_eq!(result.logs.len(), 1);
+        assert!(result.logs[0].is_ok(), "{}", result.logs[0].error);
+        assert_eq!(Fabric::temporal_tip(), Some(entry.hash.clone()));
+        assert_eq!(Coin::balance(&receiver, "AMA"), 1000);
+        assert_eq!(Consensus::chain_nonce(&sender), Some(1));
+
+        // the reverse journal undoes the transfer: the new balance is deleted and the
+        // sender's funded balance put back
+        let m_rev = Fabric::muts_rev(&entry.hash).unwrap();
+        let reverse_of = |key: &[u8]| m_rev.iter().find(|m| m.key() == key).cloned();
+        assert!(matches!(
+            reverse_of(&Coin::balance_key(&receiver, "AMA")),
+            Some(Mutation::Delete { .. })
+        ));
+        match reverse_of(&Coin::balance_key(&sender, "AMA")) {
+            Some(Mutation::Put { value, .. }) => assert_eq!(value, funded.as_bytes()),
+            other => panic!("unexpected reverse mutation {:?}", other),
+        }
+
+        // the fee went to the entry signer
+        let producer = BlsRs::get_public_key(&PRODUCER_SK).unwrap();
+        assert_eq!(Coin::balance(&producer, "AMA"), Base::exec_cost(0, &txu));
+
+        // the receipt points back into the packed entry
+        let receipt = Fabric::db()
+            .get_cf(&Fabric::cf_handle("tx"), &txu.hash)
+            .unwrap()
+            .unwrap();
+        let receipt: TxResultBinary = bincode::deserialize(&receipt).unwrap();
+        assert_eq!(receipt.entry_hash, entry.hash);
+        assert!(receipt.result.is_ok());
+        let entry_packed = Entry::pack(entry.clone());
+        assert!(entry_packed[receipt.index_start..].starts_with(&tx_packed));
+        let nonce_key = Consensus::nonce_index_key(&sender, &format!("{:0>20}", 1));
+        let indexed = Fabric::db()
+            .get_cf(&Fabric::cf_handle("tx_account_nonce"), nonce_key)
+            .unwrap();
+        assert_eq!(indexed, Some(txu.hash.clone()));
+
+        // the recorded root commits to the new balance
+        assert_eq!(
+            StateTree::root_for_entry(&entry.hash),
+            Some(result.state_root)
+        );
+        let proof = StateTree::prove_balance(&receiver, "AMA").unwrap();
+        assert_eq!(proof.value.as_deref(), Some(b"1000".as_slice()));
+        assert!(proof.verify(&result.state_root));
+
+        assert!(Consensus::chain_rewind(&entry.hash).unwrap());
+        commit_state(&Coin::balance_key(&sender, "AMA"), None);
+    }
+
+    #[test]
+    fn test_apply_entry_resets_tx_env() {
+        let _guard = Fabric::init_for_test();
+        let sender = BlsRs::get_public_key(&SENDER_SK).unwrap();
+        let receiver = BlsRs::get_public_key(&[16u8; 64]).unwrap();
+        let funded = Coin::to_flat(10).to_string();
+        commit_state(&Coin::balance_key(&sender, "AMA"), Some(funded.as_bytes()));
+
+        // init stores the attached amount it sees under "a"
+        let wat = r#"(module
+            (import "env" "memory" (memory 1))
+            (import "env" "attached_amount_ptr" (global $amount i32))
+            (import "env" "import_storage_kv_put" (func $put (param i32 i32 i32 i32) (result i32)))
+            (data (i32.const 1024) "a")
+            (func (export "init")
+                (drop (call $put (i32.const 1024) (i32.const 1)
+                    (i32.add (global.get $amount) (i32.const 4))
+                    (i32.load (global.get $amount)))))
+            (func (export "noop")))"#;
+        let wasmbytes = wasmer::wat2wasm(wat.as_bytes()).unwrap().to_vec();
+
+        let attached = TX::build(
+            &SENDER_SK,
+            "Coin",
+            "transfer",
+            vec![receiver.clone(), b"1000".to_vec()],
+            Some(1),
+            Some("AMA".to_string()),
+            Some(5),
+        );
+        let deploy = TX::build(
+            &SENDER_SK,
+            "Contract",
+            "deploy",
+            vec![wasmbytes],
+            Some(2),
+            None,
+            None,
+        );
+        let entry = next_entry(&Consensus::chain_tip_entry(), vec![attached, deploy]);
+        let result = Consensus::apply_entry(&entry).unwrap();
+        assert!(result.logs.iter().all(TxReturn::is_ok), "{:?}", result.logs);
+
+        // the deploy carries no attachment, the transfer's must not leak into it
+        let key = [b"c:".as_slice(), &sender, b":a"].concat();
+        assert_eq!(ConsensusKV::chain_get(&key), Some(b"0".to_vec()));
+
+        assert!(Consensus::chain_rewind(&entry.hash).unwrap());
+        commit_state(&Coin::balance_key(&sender, "AMA"), None);
+    }
+
+    #[test]
+    fn test_chain_rewind_restores_state() {
+        let _guard = Fabric::init_for_test();
+        let sender = BlsRs::get_public_key(&SENDER_SK).unwrap();
+        let receiver = BlsRs::get_public_key(&[13u8; 64]).unwrap();
+        let producer = BlsRs::get_public_key(&PRODUCER_SK).unwrap();
+        let trainers_key = Consensus::trainers_height_key(0);
+        let funded = Coin::to_flat(10).to_string();
+        commit_state(&Coin::balance_key(&sender, "AMA"), Some(funded.as_bytes()));
+        commit_state(
+            &trainers_key,
+            Some(&bincode::serialize(&vec![producer]).unwrap()),
+        );
+
+        let tip = Consensus::chain_tip_entry();
+        let state_before = dump("contractstate");
+        let tree_before = dump("state_tree");
+
+        let first = next_entry(&tip, vec![transfer_tx(1, &receiver, "1000")]);
+        Consensus::apply_entry(&first).unwrap();
+        let second_tx = transfer_tx(2, &receiver, "2000");
+        let second = next_entry(&first, vec![second_tx.clone()]);
+        Consensus::apply_entry(&second).unwrap();
+        assert_eq!(Coin::balance(&receiver, "AMA"), 3000);
+
+        assert!(!Consensus::trainers_for_height(1).is_empty());
+        assert!(TRAINERS_CACHE.contains_key(&0));
+
+        assert!(Consensus::chain_rewind(&first.hash).unwrap());
+        assert_eq!(Fabric::temporal_tip(), Some(tip.hash.clone()));
+        assert_eq!(dump("contractstate"), state_before);
+        assert_eq!(dump("state_tree"), tree_before);
+        assert!(!TRAINERS_CACHE.contains_key(&0));
+        for entry in [&first, &second] {
+            assert!(Fabric::muts_rev(&entry.hash).is_none());
+            assert!(StateTree::root_for_entry(&entry.hash).is_none());
+        }
+        let second_hash = TX::unpack(&second_tx).unwrap().hash;
+        let receipt = Fabric::db()
+            .get_cf(&Fabric::cf_handle("tx"), second_hash)
+            .unwrap();
+        assert!(receipt.is_none());
+
+        // an entry that is no longer on our chain cannot be rewound to
+        assert!(!Consensus::chain_rewind(&first.hash).unwrap());
+
+        commit_state(&Coin::balance_key(&sender, "AMA"), None);
+        commit_state(&trainers_key, None);
+        Consensus::invalidate_trainers_cache();
+    }
+
+    #[test]
+    fn test_chain_switch() {
+        let _guard = Fabric::init_for_test();
+        let sender = BlsRs::get_public_key(&SENDER_SK).unwrap();
+        let receiver_a = BlsRs::get_public_key(&[14u8; 64]).unwrap();
+        let receiver_b = BlsRs::get_public_key(&[15u8; 64]).unwrap();
+        let funded = Coin::to_flat(10).to_string();
+        commit_state(&Coin::balance_key(&sender, "AMA"), Some(funded.as_bytes()));
+
+        let tip = Consensus::chain_tip_entry();
+        let state_before = dump("contractstate");
+
+        let a1 = next_entry(&tip, vec![transfer_tx(1, &receiver_a, "1000")]);
+        Consensus::apply_entry(&a1).unwrap();
+        let b1 = next_entry(&tip, vec![transfer_tx(1, &receiver_b, "1000")]);
+        let b2 = next_entry(&b1, vec![]);
+
+        let results = Consensus::chain_switch(&b2.hash).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(Fabric::temporal_tip(), Some(b2.hash.clone()));
+        assert!(!Consensus::is_in_chain(&a1.hash));
+        assert_eq!(Coin::balance(&receiver_a, "AMA"), 0);
+        assert_eq!(Coin::balance(&receiver_b, "AMA"), 1000);
+
+        assert!(Consensus::chain_rewind(&b1.hash).unwrap());
+        assert_eq!(dump("contractstate"), state_before);
+
+        commit_state(&Coin::balance_key(&sender, "AMA"), None);
+    }
+
+    #[test]
+    fn test_chain_switch_failure_restores_branch() {
+        let _guard = Fabric::init_for_test();
+        let sender = BlsRs::get_public_key(&SENDER_SK).unwrap();
+        let receiver_a = BlsRs::get_public_key(&[17u8; 64]).unwrap();
+        let receiver_b = BlsRs::get_public_key(&[18u8; 64]).unwrap();
+        let funded = Coin::to_flat(10).to_string();
+        commit_state(&Coin::balance_key(&sender, "AMA"), Some(funded.as_bytes()));
+
+        let tip = Consensus::chain_tip_entry();
+        let a1 = next_entry(&tip, vec![transfer_tx(1, &receiver_a, "1000")]);
+        Consensus::apply_entry(&a1).unwrap();
+        let a2 = next_entry(&a1, vec![]);
+        Consensus::apply_entry(&a2).unwrap();
+        let state_on_a = dump("contractstate");
+
+        // b1 applies, b2 does not follow it and fails validate_next halfway through
+        let b1 = next_entry(&tip, vec![transfer_tx(1, &receiver_b, "1000")]);
+        let pk = BlsRs::get_public_key(&PRODUCER_SK).unwrap();
+        let mut b2 = Entry::build_next(&b1, b1.header_unpacked.slot + 1, &pk, &PRODUCER_SK);
+        b2.header_unpacked.prev_slot = -1;
+        b2.header_unpacked.txs_hash = blake3::hash(&[]).as_bytes().to_vec();
+        b2.hash = Entry::hash_header(&b2.header_unpacked);
+        b2.signature = BlsRs::sign(&PRODUCER_SK, &b2.hash, BLS12AggSig::DST_ENTRY).unwrap();
+        Fabric::insert_entry(&b2, 0).unwrap();
+
+        assert!(Consensus::chain_switch(&b2.hash).is_err());
+        assert_eq!(Fabric::temporal_tip(), Some(a2.hash.clone()));
+        assert!(Consensus::is_in_chain(&a1.hash));
+        assert!(!Consensus::is_in_chain(&b1.hash));
+        assert_eq!(Coin::balance(&receiver_a, "AMA"), 1000);
+        assert_eq!(Coin::balance(&receiver_b, "AMA"), 0);
+        assert_eq!(dump("contractstate"), state_on_a);
+
+        assert!(Consensus::chain_rewind(&a1.hash).unwrap());
+        commit_state(&Coin::balance_key(&sender, "AMA"), None);
+    }
+
+    #[test]
+    fn test_trainers_for_height() {
+        let _guard = Fabric::init_for_test();
+        Consensus::invalidate_trainers_cache();
+        let epoch = 7;
+        let start = epoch * Epoch::EPOCH_INTERVAL;
+        let set_a = vec![vec![1u8; 48]];
+        let set_b = vec![vec![2u8; 48]];
+
+        // nothing written yet: empty, and not cached so a later write is seen
+        assert!(Consensus::trainers_for_height(start + 1).is_empty());
+        assert!(!TRAINERS_CACHE.contains_key(&epoch));
+
+        let key_a = Consensus::trainers_height_key(start + 10);
+        commit_state(&key_a, Some(&bincode::serialize(&set_a).unwrap()));
+        // heights before the first change do not borrow the later set
+        assert!(Consensus::trainers_for_height(start + 5).is_empty());
+        assert_eq!(Consensus::trainers_for_height(start + 10), set_a);
+        assert!(TRAINERS_CACHE.contains_key(&epoch));
+
+        let key_b = Consensus::trainers_height_key(start + 20);
+        commit_state(&key_b, Some(&bincode::serialize(&set_b).unwrap()));
+        Consensus::invalidate_trainers_cache();
+        assert_eq!(Consensus::trainers_for_height(start + 19), set_a);
+        assert_eq!(Consensus::trainers_for_height(start + 20), set_b);
+        // the next epoch starts from the last set in effect
+        assert_eq!(
+            Consensus::trainers_for_height(start + Epoch::EPOCH_INTERVAL),
+            set_b
+        );
+
+        commit_state(&key_a, None);
+        commit_state(&key_b, None);
+        Consensus::invalidate_trainers_cache();
+    }
+}
@@ -1,11 +1,18 @@
 use blake3;
-use rocksdb::{MultiThreaded, Transaction, TransactionDB, WriteOptions};
+use rocksdb::{Direction, IteratorMode};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
 
 use crate::*;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, thiserror::Error)]
+pub enum KvError {
+    #[error("rocksdb_error")]
+    RocksDB(#[from] rocksdb::Error),
+    #[error("integer_overflow")]
+    IntegerOverflow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Mutation {
     Put {
         key: Vec<u8>,
@@ -25,163 +32,369 @@ pub enum Mutation {
     },
 }
 
-pub struct ConsensusKV;
-
-impl ConsensusKV {
-    pub fn kv_put(key: Vec<u8>, value: Vec<u8>) -> Result<(), rocksdb::Error> {
-        // let fabric = FABRIC_DB.read().unwrap();
-        // let fabric = fabric.as_ref().expect("Fabric not initialized");
-
-        // let old_value = fabric.db.get(&key)?.unwrap_or_default();
-        // let exists = !old_value.is_empty();
-
-        // MUTATIONS.with(|mutations| {
-        //     mutations.borrow_mut().push(Mutation::Put {
-        //         key: key.clone(),
-        //         value: value.clone(),
-        //     });
-        // });
-        // if exists {
-        //     MUTATIONS_REVERSE.with(|mutations_reverse| {
-        //         mutations_reverse.borrow_mut().push(Mutation::Put {
-        //             key: key.clone(),
-        //             value: old_value.clone(),
-        //         })
-        //     });
-        // } else {
-        //     MUTATIONS_REVERSE.with(|mutations_reverse| {
-        //         mutations_reverse
-        //             .borrow_mut()
-        //             .push(Mutation::Delete { key: key.clone() })
-        //     });
-        // }
-
-        // fabric.db.put(&key, value)?;
-        Ok(())
+impl Mutation {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Mutation::Put { key, .. }
+            | Mutation::Delete { key }
+            | Mutation::SetBit { key, .. }
+            | Mutation::ClearBit { key, .. } => key,
+        }
     }
+}
 
-    pub fn kv_increment(key: Vec<u8>, value: i64) -> Result<(), rocksdb::Error> {
-        // pub fn kv_increment(key: Vec<u8>, value: i64) -> Result<i64, rocksdb::Error> {
-        // let fabric = FABRIC_DB.read().unwrap();
-        // let fabric = fabric.as_ref().expect("Fabric not initialized");
-
-        // let old_value = fabric
-        //     .db
-        //     .get(&key)?
-        //     .unwrap_or_else(|| 0i64.to_be_bytes().to_vec());
-        // let exists = !old_value.is_empty();
-
-        // let old_int = i64::from_be_bytes(old_value.clone().try_into().unwrap());
-        // let new_value = old_int + value;
-        // let new_bytes = new_value.to_be_bytes().to_vec();
-
-        // MUTATIONS.with(|mutations| {
-        //     mutations.borrow_mut().push(Mutation::Put {
-        //         key: key.clone(),
-        //         value: new_bytes.clone(),
-        //     });
-        // });
-        // if exists {
-        //     MUTATIONS_REVERSE.with(|mutations_reverse| {
-        //         mutations_reverse.borrow_mut().push(Mutation::Put {
-        //             key: key.clone(),
-        //             value: old_value.clone(),
-        //         });
-        //     });
-        // } else {
-        //     MUTATIONS_REVERSE.with(|mutations_reverse| {
-        //         mutations_reverse
-        //             .borrow_mut()
-        //             .push(Mutation::Delete { key: key.clone() });
-        //     });
-        // }
-
-        // fabric.db.put(&key, new_bytes.clone())?;
-        // Ok(new_value)
-        Ok(())
+/// Contract state access bound to an open RocksDB transaction.
+/// Every write is journaled as a forward and a reverse `Mutation`.
+pub struct ConsensusKV<'a> {
+    pub tx: &'a RocksTx,
+    pub mutations: Vec<Mutation>,
+    pub mutations_reverse: Vec<Mutation>,
+}
+
+impl<'a> ConsensusKV<'a> {
+    pub fn new(tx: &'a RocksTx) -> Self {
+        Self {
+            tx,
+            mutations: vec![],
+            mutations_reverse: vec![],
+        }
     }
 
-    pub fn kv_delete(
-        tx: &mut Transaction<TransactionDB<MultiThreaded>>,
-        mutations: &mut Vec<Mutation>,
-        mutations_reverse: &mut Vec<Mutation>,
-        key: Vec<u8>,
-    ) -> Result<(), rocksdb::Error> {
-        if let Some(value) = tx.get(&key)? {
-            mutations.push(Mutation::Delete { key: key.clone() });
-            mutations_reverse.push(Mutation::Put {
+    /// Take the journaled mutations, leaving the journal empty
+    pub fn take_mutations(&mut self) -> (Vec<Mutation>, Vec<Mutation>) {
+        (
+            std::mem::take(&mut self.mutations),
+            std::mem::take(&mut self.mutations_reverse),
+        )
+    }
+
+    pub fn kv_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+        self.tx.get_cf(&cf, key)
+    }
+
+    pub fn kv_put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+
+        let old_value = self.tx.get_cf(&cf, &key)?;
+
+        self.mutations.push(Mutation::Put {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        match old_value {
+            Some(old_value) => self.mutations_reverse.push(Mutation::Put {
+                key: key.clone(),
+                value: old_value,
+            }),
+            None => self
+                .mutations_reverse
+                .push(Mutation::Delete { key: key.clone() }),
+        }
+
+        self.tx.put_cf(&cf, &key, value)
+    }
+
+    /// Integers are stored as decimal strings, same as the Elixir node.
+    /// Math is done in i128 so token balances with many decimals do not overflow.
+    pub fn kv_increment(&mut self, key: Vec<u8>, value: impl Into<i128>) -> Result<i128, KvError> {
+        let old_int = match self.kv_get(&key)? {
+            Some(old_value) => Self::parse_i128(&old_value),
+            None => 0,
+        };
+
+        let new_value = old_int
+            .checked_add(value.into())
+            .ok_or(KvError::IntegerOverflow)?;
+        self.kv_put(key, new_value.to_string().into_bytes())?;
+        Ok(new_value)
+    }
+
+    pub fn kv_delete(&mut self, key: Vec<u8>) -> Result<(), rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+
+        if let Some(value) = self.tx.get_cf(&cf, &key)? {
+            self.mutations.push(Mutation::Delete { key: key.clone() });
+            self.mutations_reverse.push(Mutation::Put {
                 key: key.clone(),
                 value,
             });
         }
-        tx.delete(&key)?;
+        self.tx.delete_cf(&cf, &key)?;
+        Ok(())
+    }
+
+    /// Undo a list of reverse mutations (applied back to front)
+    pub fn revert(&self, mutations_reverse: &[Mutation]) -> Result<(), rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+
+        for mut_item in mutations_reverse.iter().rev() {
+            match mut_item {
+                Mutation::Put { key, value } => self.tx.put_cf(&cf, key, value)?,
+                Mutation::Delete { key } => self.tx.delete_cf(&cf, key)?,
+                Mutation::ClearBit { key, bit_idx } => {
+                    if let Some(mut page) = self.tx.get_cf(&cf, key)? {
+                        page[bit_idx / 8] &= !(1 << (7 - bit_idx % 8));
+                        self.tx.put_cf(&cf, key, page)?;
+                    }
+                }
+                Mutation::SetBit {
+                    key,
+                    bit_idx,
+                    bloomsize,
+                } => {
+                    let mut page = self
+                        .tx
+                        .get_cf(&cf, key)?
+                        .unwrap_or_else(|| vec![0u8; bloomsize.div_ceil(8)]);
+                    Util::set_bit(&mut page, *bit_idx);
+                    self.tx.put_cf(&cf, key, page)?;
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn kv_get(key: &[u8]) -> Option<Vec<u8>> {
+    /// Stored integer clamped to i64, for counters and AMA amounts
+    pub fn parse_int(value: &[u8]) -> i64 {
+        Self::parse_i128(value).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    pub fn parse_i128(value: &[u8]) -> i128 {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|s| s.parse::<i128>().ok())
+            .unwrap_or(0)
+    }
+
+    /// Read committed contract state outside of any transaction
+    pub fn chain_get(key: &[u8]) -> Option<Vec<u8>> {
         let fabric = FABRIC_DB.read().unwrap();
         let fabric = fabric.as_ref().expect("Fabric not initialized");
+        let cf = fabric.db.cf_handle("contractstate")?;
 
-        fabric.db.get(key).unwrap()
+        fabric.db.get_cf(&cf, key).unwrap()
     }
 
-    // pub fn kv_set_bit(
-    //     tx: &mut Transaction<TransactionDB<MultiThreaded>>,
-    //     mutations: &mut Vec<Mutation>,
-    //     mutations_reverse: &mut Vec<Mutation>,
-    //     key: Vec<u8>,
-    //     bit_idx: usize,
-    //     bloomsize: usize,
-    // ) -> Result<bool, rocksdb::Error> {
-    //     let old_value: Vec<u8> = tx.get(&key)?.unwrap_or_else(|| vec![0u8; bloomsize]);
-    //     let mut bits = BitVec::<Msb0, u8>::from_vec(old_value.clone());
+    /// All keys under `prefix` in key order, returned with the prefix stripped
+    pub fn kv_get_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+        let mut items = vec![];
 
-    //     if bits.get(bit_idx).copied().unwrap_or(false) {
-    //         return Ok(false);
-    //     }
+        let iter = self
+            .tx
+            .iterator_cf(&cf, IteratorMode::From(prefix, Direction::Forward));
+        for item in iter {
+            let (key, value) = item?;
+            let Some(suffix) = key.strip_prefix(prefix) else {
+                break;
+            };
+            items.push((suffix.to_vec(), value.to_vec()));
+        }
+        Ok(items)
+    }
 
-    //     bits.set(bit_idx, true);
-    //     let new_bytes = bits.into_vec();
-
-    //     mutations.push(Mutation::SetBit {
-    //         key: key.clone(),
-    //         bit_idx,
-    //         bloomsize,
-    //     });
-    //     mutations_reverse.push(Mutation::ClearBit {
-    //         key: key.clone(),
-    //         bit_idx,
-    //     });
-
-    //     tx.put(&key, new_bytes)?;
-    //     Ok(true)
-    // }
+    pub fn kv_exists(&self, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        Ok(self.kv_get(key)?.is_some())
+    }
+
+    /// First key under `prefix` sorting after `prefix ++ key`, with the prefix stripped
+    pub fn kv_get_next(
+        &self,
+        prefix: &[u8],
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, rocksdb::Error> {
+        self.kv_get_adjacent(prefix, key, Direction::Forward)
+    }
+
+    /// Last key under `prefix` sorting before `prefix ++ key`, with the prefix stripped
+    pub fn kv_get_prev(
+        &self,
+        prefix: &[u8],
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, rocksdb::Error> {
+        self.kv_get_adjacent(prefix, key, Direction::Reverse)
+    }
+
+    fn kv_get_adjacent(
+        &self,
+        prefix: &[u8],
+        key: &[u8],
+        direction: Direction,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+        let start = [prefix, key].concat();
+
+        for item in self
+            .tx
+            .iterator_cf(&cf, IteratorMode::From(&start, direction))
+        {
+            let (found, value) = item?;
+            if *found == *start {
+                continue;
+            }
+            return Ok(found
+                .strip_prefix(prefix)
+                .map(|suffix| (suffix.to_vec(), value.to_vec())));
+        }
+        Ok(None)
+    }
+
+    /// Delete every key under `prefix`, journaling each delete
+    pub fn kv_clear(&mut self, prefix: &[u8]) -> Result<usize, rocksdb::Error> {
+        let keys = self.kv_get_prefix(prefix)?;
+        for (suffix, _) in &keys {
+            self.kv_delete([prefix, suffix].concat())?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Set one bit of a bloom page, creating the page zeroed if missing.
+    /// Returns false if the bit was already set (nothing is journaled then).
+    pub fn kv_set_bit(
+        &mut self,
+        key: Vec<u8>,
+        bit_idx: usize,
+        bloomsize: usize,
+    ) -> Result<bool, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
 
-    pub fn hash_mutations(mutations: &[Mutation]) -> Vec<u8> {
-        let bin = bincode::serialize(mutations).unwrap();
+        let old_page = self.tx.get_cf(&cf, &key)?;
+        let mut page = old_page
+            .clone()
+            .unwrap_or_else(|| vec![0u8; bloomsize.div_ceil(8)]);
+        if Util::get_bit(&page, bit_idx) {
+            return Ok(false);
+        }
+        Util::set_bit(&mut page, bit_idx);
+
+        self.mutations.push(Mutation::SetBit {
+            key: key.clone(),
+            bit_idx,
+            bloomsize,
+        });
+        // a page we created is removed again rather than left zeroed
+        match old_page {
+            Some(_) => self.mutations_reverse.push(Mutation::ClearBit {
+                key: key.clone(),
+                bit_idx,
+            }),
+            None => self
+                .mutations_reverse
+                .push(Mutation::Delete { key: key.clone() }),
+        }
+
+        self.tx.put_cf(&cf, &key, page)?;
+        Ok(true)
+    }
+
+    /// Clear one bit of a bloom page. Returns false if the page is missing or the
+    /// bit was not set (nothing is journaled then).
+    pub fn kv_clear_bit(
+        &mut self,
+        key: Vec<u8>,
+        bit_idx: usize,
+        bloomsize: usize,
+    ) -> Result<bool, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+
+        let Some(mut page) = self.tx.get_cf(&cf, &key)? else {
+            return Ok(false);
+        };
+        if bit_idx / 8 >= page.len() || !Util::get_bit(&page, bit_idx) {
+            return Ok(false);
+        }
+        page[bit_idx / 8] &= !(1 << (7 - bit_idx % 8));
+
+        self.mutations.push(Mutation::ClearBit {
+            key: key.clone(),
+            bit_idx,
+        });
+        self.mutations_reverse.push(Mutation::SetBit {
+            key: key.clone(),
+            bit_idx,
+            bloomsize,
+        });
+
+        self.tx.put_cf(&cf, &key, page)?;
+        Ok(true)
+    }
+
+    pub fn kv_get_bit(&self, key: &[u8], bit_idx: usize) -> Result<bool, rocksdb::Error> {
+        Ok(self
+            .kv_get(key)?
+            .is_some_and(|page| bit_idx / 8 < page.len() && Util::get_bit(&page, bit_idx)))
+    }
+
+    /// blake3 of the VanillaSer encoding of `[tx_results, mutations]`, both lists.
+    ///
+    /// A tx result is the map
+    /// `%{error: bytes, result: nil | bytes, logs: [bytes], exec_used: int}`.
+    /// A mutation is one of
+    /// - `%{op: "put", key: bytes, value: bytes}`
+    /// - `%{op: "delete", key: bytes}`
+    /// - `%{op: "set_bit", key: bytes, value: bit_idx, bloomsize: int}`
+    /// - `%{op: "clear_bit", key: bytes, value: bit_idx}`
+    ///
+    /// Map keys are byte strings, see `VanillaSer` for the byte layout.
+    /// The golden vectors in the tests below are the reference for other nodes.
+    pub fn hash_mutations(logs: &[TxReturn], mutations: &[Mutation]) -> Vec<u8> {
+        let bin = Self::encode_mutations(logs, mutations);
         blake3::hash(&bin).as_bytes().to_vec()
     }
 
-    // pub fn revert(
-    //     tx: &mut Transaction<TransactionDB<MultiThreaded>>,
-    //     mutations_reverse: &[Mutation],
-    // ) -> Result<(), rocksdb::Error> {
-    //     for mut_item in mutations_reverse.iter().rev() {
-    //         match mut_item {
-    //             Mutation::Put { key, value } => tx.put(key, value)?,
-    //             Mutation::Delete { key } => tx.delete(key)?,
-    //             Mutation::ClearBit { key, bit_idx } => {
-    //                 if let Some(old_value) = tx.get(key)? {
-    //                     let mut bits = BitVec::<Msb0, u8>::from_vec(old_value);
-    //                     bits.set(*bit_idx, false);
-    //                     tx.put(key, bits.into_vec())?;
-    //                 }
-    //             }
-    //             _ => {}
-    //         }
-    //     }
-    //     Ok(())
-    // }
+    pub fn encode_mutations(logs: &[TxReturn], mutations: &[Mutation]) -> Vec<u8> {
+        let logs = logs.iter().map(Self::tx_return_term).collect();
+        let mutations = mutations.iter().map(Self::mutation_term).collect();
+        VanillaSer::encode(&VanillaValue::List(vec![
+            VanillaValue::List(logs),
+            VanillaValue::List(mutations),
+        ]))
+    }
+
+    fn tx_return_term(ret: &TxReturn) -> VanillaValue {
+        let result = match &ret.result {
+            Some(result) => result.as_slice().into(),
+            None => VanillaValue::Nil,
+        };
+        let logs = ret.logs.iter().map(|log| log.as_slice().into()).collect();
+        VanillaValue::Map(vec![
+            ("error".into(), ret.error.as_str().into()),
+            ("result".into(), result),
+            ("logs".into(), VanillaValue::List(logs)),
+            ("exec_used".into(), (ret.exec_used as i128).into()),
+        ])
+    }
+
+    fn mutation_term(mutation: &Mutation) -> VanillaValue {
+        let pairs = match mutation {
+            Mutation::Put { key, value } => vec![
+                ("op".into(), "put".into()),
+                ("key".into(), key.as_slice().into()),
+                ("value".into(), value.as_slice().into()),
+            ],
+            Mutation::Delete { key } => vec![
+                ("op".into(), "delete".into()),
+                ("key".into(), key.as_slice().into()),
+            ],
+            Mutation::SetBit {
+                key,
+                bit_idx,
+                bloomsize,
+            } => vec![
+                ("op".into(), "set_bit".into()),
+                ("key".into(), key.as_slice().into()),
+                ("value".into(), (*bit_idx as i128).into()),
+                ("bloomsize".into(), (*bloomsize as i128).into()),
+            ],
+            Mutation::ClearBit { key, bit_idx } => vec![
+                ("op".into(), "clear_bit".into()),
+                ("key".into(), key.as_slice().into()),
+                ("value".into(), (*bit_idx as i128).into()),
+            ],
+        };
+        VanillaValue::Map(pairs)
+    }
 
     // pub fn merge_nested(
     //     left: HashMap<String, serde_json::Value>,
@@ -206,3 +419,170 @@ impl ConsensusKV {
     //     merged
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_int() {
+        assert_eq!(ConsensusKV::parse_int(b"-42"), -42);
+        assert_eq!(ConsensusKV::parse_int(b"not a number"), 0);
+        assert_eq!(ConsensusKV::parse_int(b"100000000000000000000"), i64::MAX);
+        assert_eq!(
+            ConsensusKV::parse_i128(b"100000000000000000000"),
+            100_000_000_000_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_increment_overflow() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let key = b"test:overflow".to_vec();
+
+        kv.kv_increment(key.clone(), i128::MAX).unwrap();
+        assert!(matches!(
+            kv.kv_increment(key.clone(), 1),
+            Err(KvError::IntegerOverflow)
+        ));
+        assert_eq!(kv.kv_increment(key.clone(), -1).unwrap(), i128::MAX - 1);
+        let (mutations, _) = kv.take_mutations();
+        assert_eq!(mutations.len(), 2);
+    }
+
+    #[test]
+    fn test_revert_restores_state() {
+        let _guard = Fabric::init_for_test();
+        let rtx = Fabric::transaction();
+        let mut kv = ConsensusKV::new(&rtx);
+        let bloomsize = SolBloom::PAGE_SIZE;
+
+        kv.kv_put(b"test:put".to_vec(), b"old".to_vec()).unwrap();
+        kv.kv_put(b"test:delete".to_vec(), b"gone".to_vec())
+            .unwrap();
+        kv.kv_increment(b"test:counter".to_vec(), 5).unwrap();
+        kv.kv_set_bit(b"test:page".to_vec(), 3, bloomsize).unwrap();
+        kv.kv_put(b"test:clear:a".to_vec(), b"a".to_vec()).unwrap();
+        kv.kv_put(b"test:clear:b".to_vec(), b"b".to_vec()).unwrap();
+        kv.take_mutations();
+        let before = kv.kv_get_prefix(b"").unwrap();
+
+        kv.kv_put(b"test:put".to_vec(), b"new".to_vec()).unwrap();
+        kv.kv_put(b"test:fresh".to_vec(), b"fresh".to_vec())
+            .unwrap();
+        kv.kv_delete(b"test:delete".to_vec()).unwrap();
+        kv.kv_delete(b"test:missing".to_vec()).unwrap();
+        kv.kv_increment(b"test:counter".to_vec(), -7).unwrap();
+        kv.kv_increment(b"test:fresh_counter".to_vec(), 1).unwrap();
+        kv.kv_set_bit(b"test:page".to_vec(), 9, bloomsize).unwrap();
+        kv.kv_clear_bit(b"test:page".to_vec(), 3, bloomsize)
+            .unwrap();
+        kv.kv_set_bit(b"test:fresh_page".to_vec(), 1, bloomsize)
+            .unwrap();
+        assert_eq!(kv.kv_clear(b"test:clear:").unwrap(), 2);
+        // the same key touched twice is undone back to front
+        kv.kv_put(b"test:put".to_vec(), b"newer".to_vec()).unwrap();
+        let (_, mutations_reverse) = kv.take_mutations();
+        assert_ne!(kv.kv_get_prefix(b"").unwrap(), before);
+
+        kv.revert(&mutations_reverse).unwrap();
+        assert_eq!(kv.kv_get_prefix(b"").unwrap(), before);
+    }
+
+    fn tx_return(error: &str, result: Option<&[u8]>, logs: &[&[u8]], exec_used: u64) -> TxReturn {
+        TxReturn {
+            error: error.to_string(),
+            result: result.map(|r| r.to_vec()),
+            logs: logs.iter().map(|log| log.to_vec()).collect(),
+            exec_used,
+        }
+    }
+
+    fn balance_key(pk_byte: u8) -> Vec<u8> {
+        [b"bic:coin:balance:".as_slice(), &[pk_byte; 48], b":AMA"].concat()
+    }
+
+    fn golden(logs: &[TxReturn], mutations: &[Mutation]) -> String {
+        hex::encode(ConsensusKV::hash_mutations(logs, mutations))
+    }
+
+    #[test]
+    fn test_encode_mutations_golden() {
+        assert_eq!(
+            hex::encode(ConsensusKV::encode_mutations(&[], &[])),
+            "06010206000600"
+        );
+
+        let logs = [tx_return("ok", None, &[], 7)];
+        let mutations = [Mutation::Delete { key: b"k".to_vec() }];
+        assert_eq!(
+            hex::encode(ConsensusKV::encode_mutations(&logs, &mutations)),
+            concat!(
+                "0601020601010701040501046c6f677306000501056572726f720501026f6b050106726573",
+                "756c7400050109657865635f757365640301070601010701020501026f7005010664656c65",
+                "74650501036b65790501016b",
+            )
+        );
+    }
+
+    #[test]
+    fn test_hash_mutations_golden() {
+        assert_eq!(
+            golden(&[], &[]),
+            "94703714636f60204516be701a242b4967256af3c0ddf08f2c148a479f5be97e"
+        );
+
+        let logs = [tx_return("ok", None, &[], 7)];
+        let mutations = [Mutation::Delete { key: b"k".to_vec() }];
+        assert_eq!(
+            golden(&logs, &mutations),
+            "72d4a37bff669083586876453718c773af33023e5c1c4c904a1a84cf8897740d"
+        );
+
+        let logs = [tx_return("ok", None, &[], 0)];
+        let mutations = [
+            Mutation::Put {
+                key: balance_key(1),
+                value: b"999".to_vec(),
+            },
+            Mutation::Put {
+                key: balance_key(2),
+                value: b"1".to_vec(),
+            },
+        ];
+        assert_eq!(
+            golden(&logs, &mutations),
+            "d17bdc297883f8e7b93282d3aa0cf6b817f9c00d91665c26967b084411072d6e"
+        );
+
+        let logs = [
+            tx_return(
+                "ok",
+                Some([42].as_slice()),
+                &[b"hello".as_slice(), b""],
+                1234,
+            ),
+            tx_return("insufficient_funds", None, &[], 0),
+        ];
+        let mutations = [
+            Mutation::Delete {
+                key: b"c:k".to_vec(),
+            },
+            Mutation::SetBit {
+                key: b"bic:epoch:segment_vr".to_vec(),
+                bit_idx: 9,
+                bloomsize: 65536,
+            },
+            Mutation::ClearBit {
+                key: b"bic:epoch:segment_vr".to_vec(),
+                bit_idx: 9,
+            },
+        ];
+        assert_eq!(
+            golden(&logs, &mutations),
+            "ab8d0b7ad7e4bce1ebef06bf60accee742ee18430d36f4f1708c82cd9410d773"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use rocksdb::{Direction, IteratorMode};
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+/// A log emitted through `import_log`, as stored in the `contract_log` index.
+/// Logs of nested calls are indexed under the contract the tx called.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContractLog {
+    pub contract: Vec<u8>,
+    pub height: u64,
+    pub tx_index: u32,
+    pub log_index: u32,
+    pub tx_hash: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl ContractLog {
+    /// contract ++ height ++ tx_index ++ log_index, numbers big endian so keys
+    /// sort by chain order. Contract accounts are fixed size public keys.
+    pub fn key(contract: &[u8], height: u64, tx_index: u32, log_index: u32) -> Vec<u8> {
+        [
+            contract,
+            &height.to_be_bytes(),
+            &tx_index.to_be_bytes(),
+            &log_index.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn tx_prefix(contract: &[u8], height: u64, tx_index: u32) -> Vec<u8> {
+        [contract, &height.to_be_bytes(), &tx_index.to_be_bytes()].concat()
+    }
+
+    /// Indexes the logs of one applied tx, inside the entry transaction
+    pub fn index(
+        rtx: &RocksTx,
+        contract: &[u8],
+        height: u64,
+        tx_index: u32,
+        tx_hash: &[u8],
+        logs: &[Vec<u8>],
+    ) -> Result<(), rocksdb::Error> {
+        let cf = Fabric::cf_handle("contract_log");
+        for (log_index, data) in logs.iter().enumerate() {
+            let log = ContractLog {
+                contract: contract.to_vec(),
+                height,
+                tx_index,
+                log_index: log_index as u32,
+                tx_hash: tx_hash.to_vec(),
+                data: data.clone(),
+            };
+            let key = Self::key(contract, height, tx_index, log.log_index);
+            rtx.put_cf(&cf, key, bincode::serialize(&log).unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Removes the logs of one tx again when its entry is rewound
+    pub fn unindex(
+        rtx: &RocksTx,
+        contract: &[u8],
+        height: u64,
+        tx_index: u32,
+    ) -> Result<(), rocksdb::Error> {
+        let cf = Fabric::cf_handle("contract_log");
+        let prefix = Self::tx_prefix(contract, height, tx_index);
+
+        for item in rtx.iterator_cf(&cf, IteratorMode::From(&prefix, Direction::Forward)) {
+            let (key, _) = item?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            rtx.delete_cf(&cf, key)?;
+        }
+        Ok(())
+    }
+
+    /// Logs of `contract` with `from_height <= height <= to_height` in chain order,
+    /// optionally only those whose data starts with `topic`
+    pub fn query(
+        contract: &[u8],
+        from_height: u64,
+        to_height: u64,
+        topic: Option<&[u8]>,
+        limit: usize,
+    ) -> Vec<ContractLog> {
+        let db = Fabric::db();
+        let cf = Fabric::cf_handle("contract_log");
+        let start = [contract, &from_height.to_be_bytes()].concat();
+
+        db.iterator_cf(&cf, IteratorMode::From(&start, Direction::Forward))
+            .map_while(|item| item.ok())
+            .take_while(|(key, _)| key.starts_with(contract))
+            .filter_map(|(_, value)| bincode::deserialize::<ContractLog>(&value).ok())
+            .take_while(|log| log.height <= to_height)
+            .filter(|log| topic.is_none_or(|topic| log.data.starts_with(topic)))
+            .take(limit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_sorts_by_chain_order() {
+        let contract = [7u8; 48];
+        let keys = [
+            ContractLog::key(&contract, 1, 0, 1),
+            ContractLog::key(&contract, 1, 1, 0),
+            ContractLog::key(&contract, 2, 0, 0),
+            ContractLog::key(&contract, 256, 0, 0),
+        ];
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(keys[0].len(), 48 + 8 + 4 + 4);
+    }
+
+    fn data(logs: Vec<ContractLog>) -> Vec<Vec<u8>> {
+        logs.into_iter().map(|log| log.data).collect()
+    }
+
+    fn logs(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_query() {
+        let _guard = Fabric::init_for_test();
+        let (contract, other) = ([91u8; 48], [92u8; 48]);
+        let indexed = [
+            (contract, 1, 0, logs(&["mint:a", "burn:b"])),
+            (contract, 2, 0, logs(&["mint:c"])),
+            (contract, 2, 1, logs(&["mint:d"])),
+            (contract, 3, 0, logs(&["mint:e"])),
+            (other, 2, 0, logs(&["mint:x"])),
+        ];
+        let rtx = Fabric::transaction();
+        for (account, height, tx_index, tx_logs) in &indexed {
+            ContractLog::index(&rtx, account, *height, *tx_index, &[0u8; 32], tx_logs).unwrap();
+        }
+        rtx.commit().unwrap();
+
+        // both bounds are inclusive and other contracts never show up
+        let found = ContractLog::query(&contract, 2, 2, None, 10);
+        assert_eq!(data(found), logs(&["mint:c", "mint:d"]));
+        let found = ContractLog::query(&contract, 0, 1, None, 10);
+        assert_eq!(data(found), logs(&["mint:a", "burn:b"]));
+        assert!(ContractLog::query(&contract, 4, u64::MAX, None, 10).is_empty());
+
+        let found = ContractLog::query(&contract, 0, u64::MAX, Some(b"mint"), 10);
+        assert_eq!(data(found), logs(&["mint:a", "mint:c", "mint:d", "mint:e"]));
+        let found = ContractLog::query(&contract, 0, u64::MAX, Some(b"mint"), 2);
+        assert_eq!(data(found), logs(&["mint:a", "mint:c"]));
+
+        // a rewound tx takes its logs with it, the rest of the height stays
+        let rtx = Fabric::transaction();
+        ContractLog::unindex(&rtx, &contract, 2, 0).unwrap();
+        rtx.commit().unwrap();
+        let found = ContractLog::query(&contract, 2, 2, None, 10);
+        assert_eq!(data(found), logs(&["mint:d"]));
+        assert_eq!(ContractLog::query(&other, 2, 2, None, 10).len(), 1);
+
+        let rtx = Fabric::transaction();
+        for (account, height, tx_index, _) in &indexed {
+            ContractLog::unindex(&rtx, account, *height, *tx_index).unwrap();
+        }
+        rtx.commit().unwrap();
+        assert!(ContractLog::query(&contract, 0, u64::MAX, None, 10).is_empty());
+    }
+}
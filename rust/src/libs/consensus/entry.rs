@@ -51,6 +51,10 @@ pub struct Entry {
 
 // Entry methods
 impl Entry {
+    pub const MAX_TXS: usize = 100;
+    /// Share of trainers that must sign a masked (special meeting) entry
+    pub const SPECIAL_MEETING_QUORUM: f64 = 0.67;
+
     pub fn unpack(entry_packed: Option<&[u8]>) -> Option<Self> {
         match entry_packed {
             None => None,
@@ -71,19 +75,19 @@ impl Entry {
         writer
     }
 
+    pub fn hash_header(header: &EntryHeader) -> Vec<u8> {
+        blake3::hash(&to_vec(header).unwrap()).as_bytes().to_vec()
+    }
+
     pub fn sign(mut entry_unpacked: Entry) -> Entry {
-        let sk = &CONFIG.ama.trainer_sk.clone();
+        let sk = AMACONFIG.trainer_sk();
         // txs_hash = hash of concatenated txs
         let txs_concat: Vec<u8> = entry_unpacked.txs.concat();
         let txs_hash = blake3::hash(&txs_concat).as_bytes().to_vec();
         entry_unpacked.header_unpacked.txs_hash = txs_hash.clone();
 
-        let mut header_bin: Vec<u8> = vec![];
-
-        let header_bin = to_vec(&entry_unpacked.header_unpacked).unwrap();
-
-        let hash = blake3::hash(&header_bin).as_bytes().to_vec();
-        let signature = BlsRs::sign(sk, &hash, b"BLS12AggSig_dst_entry").unwrap();
+        let hash = Self::hash_header(&entry_unpacked.header_unpacked);
+        let signature = BlsRs::sign(&sk, &hash, BLS12AggSig::DST_ENTRY).unwrap();
 
         Entry {
             header_unpacked: entry_unpacked.header_unpacked.clone(),
@@ -95,19 +99,23 @@ impl Entry {
     }
 
     pub fn validate_signature(entry_unpacked: &Entry) -> Result<(), &'static str> {
-        let hash = blake3::hash(&to_vec(&entry_unpacked.header_unpacked).unwrap())
-            .as_bytes()
-            .to_vec();
+        let hash = Self::hash_header(&entry_unpacked.header_unpacked);
         if let Some(mask) = &entry_unpacked.mask {
-            // Placeholder for masked BLS validation
+            // special meeting entries are signed by a quorum of trainers instead of the slot owner
             let trainers = Consensus::trainers_for_height(entry_unpacked.header_unpacked.height);
-            let _trainers_signed = trainers; // unmask logic
-            let agg_pk = BlsRs::aggregate_public_keys(_trainers_signed).unwrap();
+            let mask = BLS12AggSig::mask_from_bytes(mask, trainers.len());
+            let trainers_signed = BLS12AggSig::unmask_trainers(&trainers, &mask);
+            let quorum = trainers.len() as f64 * Self::SPECIAL_MEETING_QUORUM;
+            if trainers_signed.is_empty() || (trainers_signed.len() as f64) < quorum {
+                return Err("invalid_amount_of_signatures");
+            }
+            let agg_pk =
+                BlsRs::aggregate_public_keys(trainers_signed).map_err(|_| "invalid_signature")?;
             if !BlsRs::verify(
                 &agg_pk,
                 &entry_unpacked.signature,
                 &hash,
-                b"BLS12AggSig_dst_entry",
+                BLS12AggSig::DST_ENTRY,
             ) {
                 return Err("invalid_signature");
             }
@@ -116,7 +124,7 @@ impl Entry {
                 &entry_unpacked.header_unpacked.signer,
                 &entry_unpacked.signature,
                 &hash,
-                b"BLS12AggSig_dst_entry",
+                BLS12AggSig::DST_ENTRY,
             ) {
                 return Err("invalid_signature");
             }
@@ -127,7 +135,7 @@ impl Entry {
     pub fn validate_entry(e: &Entry) -> Result<(), EntryError> {
         let eh = &e.header_unpacked;
 
-        if e.txs.len() > 100 {
+        if e.txs.len() > Self::MAX_TXS {
             return Err(EntryError::TooManyTxs);
         }
 
@@ -198,8 +206,6 @@ impl Entry {
         for tx in &next_entry.txs {
             let txu = TX::unpack(tx).unwrap();
             // Validate nonce, balance, epoch, etc. Placeholder
-
-            
         }
 
         Ok(())
@@ -232,7 +238,7 @@ impl Entry {
     }
 
     pub fn epoch(&self) -> u64 {
-        self.header_unpacked.height / 100_000
+        self.header_unpacked.height / Epoch::EPOCH_INTERVAL
     }
 
     pub fn height(&self) -> u64 {
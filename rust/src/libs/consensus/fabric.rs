@@ -1,8 +1,8 @@
 use anyhow::Result;
 use once_cell::sync::Lazy;
 use rocksdb::{
-    BoundColumnFamily, ColumnFamilyDescriptor, MultiThreaded, Options, TransactionDB,
-    TransactionDBOptions,
+    BoundColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, MultiThreaded, Options,
+    Transaction, TransactionDB, TransactionDBOptions,
 };
 use std::{
     collections::HashMap,
@@ -14,6 +14,9 @@ use crate::*;
 
 pub static FABRIC_DB: Lazy<RwLock<Option<Arc<Fabric>>>> = Lazy::new(|| RwLock::new(None));
 
+/// RocksDB transaction over the fabric DB (the DB handle is leaked, hence 'static)
+pub type RocksTx = Transaction<'static, TransactionDB<MultiThreaded>>;
+
 pub struct Fabric {
     pub db: &'static Arc<TransactionDB<MultiThreaded>>,
     pub cf: HashMap<&'static str, Arc<BoundColumnFamily<'static>>>,
@@ -46,6 +49,11 @@ impl Fabric {
         path.push("db");
         path.push("fabric");
 
+        Self::open(path)
+    }
+
+    /// Opens (or creates) the fabric DB at `path` and installs it as the global handle
+    pub fn open(path: PathBuf) -> Result<()> {
         // RocksDB options
         let mut opts = Options::default();
         opts.create_if_missing(true);
@@ -56,7 +64,7 @@ impl Fabric {
         let txn_opts = TransactionDBOptions::default();
 
         // Column family names
-        let cf_names: [&'static str; 14] = [
+        let cf_names: [&'static str; 17] = [
             "default",
             "entry_by_height",
             "entry_by_slot",
@@ -71,6 +79,9 @@ impl Fabric {
             "muts",
             "muts_rev",
             "sysconf",
+            "contract_log",
+            "state_tree",
+            "state_root",
         ];
 
         // CF descriptors
@@ -106,10 +117,31 @@ impl Fabric {
         // Store globally
         *FABRIC_DB.write().unwrap() = Some(Arc::new(fabric));
 
+        StateTree::build_if_missing()?;
+
         println!("Fabric initialized at {:?}", path);
         Ok(())
     }
 
+    /// Opens a throwaway fabric under the temp dir, once per test binary, with the
+    /// genesis entry stored. DB backed tests share it and hold the returned guard so
+    /// they run one at a time; each test leaves the chain as it found it.
+    #[cfg(test)]
+    pub fn init_for_test() -> std::sync::MutexGuard<'static, ()> {
+        static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        static OPENED: std::sync::Once = std::sync::Once::new();
+
+        let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        OPENED.call_once(|| {
+            let path = std::env::temp_dir()
+                .join(format!("amadeus_fabric_test_{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self::open(path).expect("open test fabric");
+            Self::insert_entry(&EntryGenesis::get(), 0).expect("insert genesis");
+        });
+        guard
+    }
+
     pub fn close() {
         let mut fabric_guard = FABRIC_DB.write().unwrap();
 
@@ -122,6 +154,46 @@ impl Fabric {
         }
     }
 
+    /// Static handle to the underlying TransactionDB
+    pub fn db() -> &'static Arc<TransactionDB<MultiThreaded>> {
+        let fabric = FABRIC_DB.read().unwrap();
+        let fabric = fabric.as_ref().expect("Fabric not initialized");
+
+        fabric.db
+    }
+
+    pub fn cf_handle(name: &str) -> Arc<BoundColumnFamily<'static>> {
+        Self::db()
+            .cf_handle(name)
+            .unwrap_or_else(|| panic!("{} CF not found", name))
+    }
+
+    /// Start a new RocksDB transaction on the fabric DB
+    pub fn transaction() -> RocksTx {
+        Self::db().transaction()
+    }
+
+    pub fn temporal_tip() -> Option<Vec<u8>> {
+        let fabric_guard = FABRIC_DB.read().unwrap();
+        let fabric = fabric_guard.as_ref()?;
+
+        let cf = fabric.db.cf_handle("sysconf")?;
+
+        match fabric.db.get_cf(&cf, b"temporal_tip") {
+            Ok(Some(data)) => Some(data),
+            Ok(None) => None,
+            Err(err) => {
+                eprintln!("RocksDB get error: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn temporal_tip_entry() -> Option<Entry> {
+        let hash = Self::temporal_tip();
+        Self::entry_by_hash(hash.as_deref())
+    }
+
     pub fn rooted_tip() -> Option<Vec<u8>> {
         let fabric_guard = FABRIC_DB.read().unwrap();
         let fabric = fabric_guard.as_ref()?; // return None if DB not initialized
@@ -183,4 +255,114 @@ impl Fabric {
             }
         }
     }
+
+    /// "{height}:" ++ hash, the key layout of entry_by_height and entry_by_slot
+    pub fn index_key(num: u64, hash: &[u8]) -> Vec<u8> {
+        [format!("{}:", num).as_bytes(), hash].concat()
+    }
+
+    /// Stores a validated entry and its height/slot indexes, does not apply it
+    pub fn insert_entry(entry: &Entry, seen_time: i64) -> Result<(), rocksdb::Error> {
+        let rtx = Self::transaction();
+        let header = &entry.header_unpacked;
+
+        let cf_default = Self::cf_handle("default");
+        if rtx.get_cf(&cf_default, &entry.hash)?.is_none() {
+            rtx.put_cf(&cf_default, &entry.hash, Entry::pack(entry.clone()))?;
+            rtx.put_cf(
+                &Self::cf_handle("entry_by_height"),
+                Self::index_key(header.height, &entry.hash),
+                &entry.hash,
+            )?;
+            rtx.put_cf(
+                &Self::cf_handle("entry_by_slot"),
+                Self::index_key(header.slot, &entry.hash),
+                &entry.hash,
+            )?;
+            rtx.put_cf(
+                &Self::cf_handle("my_seen_time_for_entry"),
+                &entry.hash,
+                bincode::serialize(&(seen_time as u64)).unwrap(),
+            )?;
+        }
+
+        rtx.commit()
+    }
+
+    pub fn entries_by_height(height: u64) -> Vec<Entry> {
+        let cf = Self::cf_handle("entry_by_height");
+        let prefix = format!("{}:", height).into_bytes();
+
+        Self::db()
+            .iterator_cf(&cf, IteratorMode::From(&prefix, Direction::Forward))
+            .map_while(|item| item.ok())
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(_, hash)| Self::entry_by_hash(Some(&hash)))
+            .collect()
+    }
+
+    /// Aggregated consensus per mutations_hash for an entry
+    pub fn consensuses_by_entryhash(hash: &[u8]) -> HashMap<Vec<u8>, Consensus> {
+        Self::db()
+            .get_cf(&Self::cf_handle("consensus_by_entryhash"), hash)
+            .ok()
+            .flatten()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn best_consensus_by_entryhash(hash: &[u8]) -> Option<Consensus> {
+        Self::consensuses_by_entryhash(hash)
+            .into_values()
+            .max_by(|a, b| a.score.unwrap_or(0.0).total_cmp(&b.score.unwrap_or(0.0)))
+    }
+
+    /// Keeps the highest scoring aggregate per mutations_hash, the caller validates it first
+    pub fn insert_consensus(consensus: &Consensus) -> Result<(), rocksdb::Error> {
+        let rtx = Self::transaction();
+        let cf = Self::cf_handle("consensus_by_entryhash");
+
+        let mut consensuses: HashMap<Vec<u8>, Consensus> = rtx
+            .get_for_update_cf(&cf, &consensus.entry_hash, true)?
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default();
+
+        let existing_score = consensuses
+            .get(&consensus.mutations_hash)
+            .and_then(|existing| existing.score);
+        if existing_score.is_some_and(|score| score >= consensus.score.unwrap_or(0.0)) {
+            return Ok(());
+        }
+
+        consensuses.insert(consensus.mutations_hash.clone(), consensus.clone());
+        rtx.put_cf(
+            &cf,
+            &consensus.entry_hash,
+            bincode::serialize(&consensuses).unwrap(),
+        )?;
+        rtx.commit()
+    }
+
+    pub fn my_attestation(hash: &[u8]) -> Option<Attestation> {
+        let data = Self::db()
+            .get_cf(&Self::cf_handle("my_attestation_for_entry"), hash)
+            .ok()??;
+        Attestation::unpack(&data).ok()
+    }
+
+    /// Unix millis at which we first saw the entry
+    pub fn my_seen_time(hash: &[u8]) -> Option<u64> {
+        let data = Self::db()
+            .get_cf(&Self::cf_handle("my_seen_time_for_entry"), hash)
+            .ok()??;
+        bincode::deserialize(&data).ok()
+    }
+
+    /// Reverse mutations recorded when the entry was applied
+    pub fn muts_rev(hash: &[u8]) -> Option<Vec<Mutation>> {
+        let data = Self::db()
+            .get_cf(&Self::cf_handle("muts_rev"), hash)
+            .ok()??;
+        bincode::deserialize(&data).ok()
+    }
 }
@@ -1,3 +1,267 @@
+use blst::min_pk::Signature;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::*;
+
+static COORDINATOR_SENDER: OnceCell<UnboundedSender<CoordinatorMessage>> = OnceCell::new();
+static COORDINATOR_STATE: Lazy<Mutex<CoordinatorState>> =
+    Lazy::new(|| Mutex::new(CoordinatorState::default()));
+
+#[derive(Debug)]
+pub enum CoordinatorMessage {
+    AddAttestation(Attestation),
+    AttestationBulk(Vec<Vec<u8>>),
+}
+
+/// Attestations collected for one entry, aggregated per mutations_hash
+pub struct EntryAttestations {
+    pub trainers: Vec<Vec<u8>>,
+    pub signers: HashSet<Vec<u8>>,
+    pub aggsigs: HashMap<Vec<u8>, AggSig>,
+}
+
+#[derive(Default)]
+pub struct CoordinatorState {
+    /// height -> entry_hash -> attestations
+    pub heights: BTreeMap<u64, HashMap<Vec<u8>, EntryAttestations>>,
+}
+
 pub struct FabricCoordinatorGen {
-    
-}
\ No newline at end of file
+    receiver: UnboundedReceiver<CoordinatorMessage>,
+}
+
+impl FabricCoordinatorGen {
+    /// Attestations further than this behind the temporal tip are dropped
+    pub const HEIGHT_WINDOW: u64 = 1_000;
+
+    pub fn start_link() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        let _ = COORDINATOR_SENDER.set(sender);
+
+        FabricCoordinatorGen { receiver }
+    }
+
+    pub fn send_add_attestation(attestation: &Attestation) {
+        if let Some(sender) = COORDINATOR_SENDER.get() {
+            let _ = sender.send(CoordinatorMessage::AddAttestation(attestation.clone()));
+        }
+    }
+
+    pub fn send_attestation_bulk(attestations_packed: Vec<Vec<u8>>) {
+        if let Some(sender) = COORDINATOR_SENDER.get() {
+            let _ = sender.send(CoordinatorMessage::AttestationBulk(attestations_packed));
+        }
+    }
+
+    pub async fn run(&mut self) {
+        while let Some(msg) = self.receiver.recv().await {
+            match msg {
+                CoordinatorMessage::AddAttestation(attestation) => {
+                    Self::add_attestation(attestation);
+                }
+                CoordinatorMessage::AttestationBulk(attestations_packed) => {
+                    for packed in attestations_packed {
+                        if let Ok(attestation) = Attestation::unpack(&packed) {
+                            Self::add_attestation(attestation);
+                        }
+                    }
+                }
+            }
+            Self::prune(Consensus::chain_height());
+        }
+    }
+
+    /// Validates, dedups and aggregates one attestation, then stores the
+    /// improved consensus for its entry
+    pub fn add_attestation(attestation: Attestation) -> Option<Consensus> {
+        attestation.validate().ok()?;
+        attestation.validate_vs_chain().ok()?;
+
+        let entry = Fabric::entry_by_hash(Some(&attestation.entry_hash))?;
+        let height = entry.header_unpacked.height;
+        if height + Self::HEIGHT_WINDOW < Consensus::chain_height() {
+            return None;
+        }
+        let signature = Signature::from_bytes(&attestation.signature).ok()?;
+
+        let mut state = COORDINATOR_STATE.lock().unwrap();
+        let entry_atts = state
+            .heights
+            .entry(height)
+            .or_default()
+            .entry(attestation.entry_hash.clone())
+            .or_insert_with(|| EntryAttestations {
+                trainers: Consensus::trainers_for_height(height),
+                signers: HashSet::new(),
+                aggsigs: HashMap::new(),
+            });
+
+        if entry_atts.signers.contains(&attestation.signer) {
+            return None;
+        }
+
+        let trainers = &entry_atts.trainers;
+        let agg = match entry_atts.aggsigs.get(&attestation.mutations_hash) {
+            Some(agg) => BLS12AggSig::add(agg, trainers, attestation.signer.clone(), signature),
+            None => BLS12AggSig::new(trainers, attestation.signer.clone(), signature),
+        }
+        .ok()?;
+        // only a signer whose signature made it into an aggregate counts as seen
+        entry_atts.signers.insert(attestation.signer.clone());
+
+        let weights = trainers
+            .iter()
+            .map(|pk| (pk.clone(), ConsensusWeight::count(pk) as f64))
+            .collect();
+        let consensus = Consensus {
+            entry_hash: attestation.entry_hash.clone(),
+            mutations_hash: attestation.mutations_hash.clone(),
+            mask: BLS12AggSig::mask_to_bytes(&agg.mask),
+            aggsig: agg.aggsig.to_bytes().to_vec(),
+            score: Some(BLS12AggSig::score(trainers, &agg.mask, &weights)),
+        };
+        entry_atts.aggsigs.insert(attestation.mutations_hash.clone(), agg);
+        drop(state);
+
+        Fabric::insert_consensus(&consensus).ok()?;
+        Some(consensus)
+    }
+
+    fn prune(chain_height: u64) {
+        let min_height = chain_height.saturating_sub(Self::HEIGHT_WINDOW);
+        let mut state = COORDINATOR_STATE.lock().unwrap();
+        state.heights = state.heights.split_off(&min_height);
+    }
+
+    /// Trainers that attested the entry, grouped by the mutations_hash they signed
+    pub fn attesters(entry_hash: &[u8]) -> HashMap<Vec<u8>, Vec<Vec<u8>>> {
+        let state = COORDINATOR_STATE.lock().unwrap();
+
+        state
+            .heights
+            .values()
+            .find_map(|entries| entries.get(entry_hash))
+            .map(|entry_atts| {
+                entry_atts
+                    .aggsigs
+                    .iter()
+                    .map(|(mutations_hash, agg)| {
+                        let signers = BLS12AggSig::unmask_trainers(&entry_atts.trainers, &agg.mask);
+                        (mutations_hash.clone(), signers)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Heights currently tracked with the number of attestations per entry
+    pub fn window() -> Vec<(u64, Vec<(Vec<u8>, usize)>)> {
+        let state = COORDINATOR_STATE.lock().unwrap();
+
+        state
+            .heights
+            .iter()
+            .map(|(height, entries)| {
+                let counts = entries
+                    .iter()
+                    .map(|(entry_hash, atts)| (entry_hash.clone(), atts.signers.len()))
+                    .collect();
+                (*height, counts)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRAINER_SKS: [[u8; 64]; 3] = [[41u8; 64], [42u8; 64], [43u8; 64]];
+
+    fn attest(sk: &[u8], entry_hash: &[u8], mutations_hash: &[u8]) -> Attestation {
+        let msg = [entry_hash, mutations_hash].concat();
+        Attestation {
+            entry_hash: entry_hash.to_vec(),
+            mutations_hash: mutations_hash.to_vec(),
+            signer: BlsRs::get_public_key(sk).unwrap(),
+            signature: BlsRs::sign(sk, &msg, BLS12AggSig::DST_ATT).unwrap(),
+        }
+    }
+
+    fn add(sk: &[u8], entry_hash: &[u8], mutations_hash: &[u8]) -> Option<Consensus> {
+        FabricCoordinatorGen::add_attestation(attest(sk, entry_hash, mutations_hash))
+    }
+
+    fn reset(entry_hash: &[u8]) {
+        COORDINATOR_STATE.lock().unwrap().heights.clear();
+        Consensus::invalidate_trainers_cache();
+        let cf = Fabric::cf_handle("consensus_by_entryhash");
+        Fabric::db().delete_cf(&cf, entry_hash).unwrap();
+    }
+
+    #[test]
+    fn test_add_attestation() {
+        let _guard = Fabric::init_for_test();
+        let genesis = Consensus::chain_tip_entry();
+        reset(&genesis.hash);
+        let trainers: Vec<Vec<u8>> = TRAINER_SKS
+            .iter()
+            .map(|sk| BlsRs::get_public_key(sk).unwrap())
+            .collect();
+        TRAINERS_CACHE.insert(0, vec![(0, trainers.clone())]);
+        let (m1, m2) = ([1u8; 32], [2u8; 32]);
+
+        let first = add(&TRAINER_SKS[0], &genesis.hash, &m1).unwrap();
+        assert_eq!(first.mask, vec![0b1000_0000]);
+
+        // the same trainer is only counted once, whatever it signs next
+        assert!(add(&TRAINER_SKS[0], &genesis.hash, &m1).is_none());
+        assert!(add(&TRAINER_SKS[0], &genesis.hash, &m2).is_none());
+
+        // signatures on the same mutations_hash fold into one aggregate
+        let second = add(&TRAINER_SKS[1], &genesis.hash, &m1).unwrap();
+        assert_eq!(second.mask, vec![0b1100_0000]);
+        assert_eq!(second.score, Some(2.0 / 3.0));
+        let agg_pk = BlsRs::aggregate_public_keys(trainers[..2].to_vec()).unwrap();
+        let msg = [genesis.hash.as_slice(), &m1].concat();
+        assert!(BlsRs::verify(&agg_pk, &second.aggsig, &msg, BLS12AggSig::DST_ATT));
+
+        // a diverging mutations_hash gets its own aggregate
+        let third = add(&TRAINER_SKS[2], &genesis.hash, &m2).unwrap();
+        assert_eq!(third.mask, vec![0b0010_0000]);
+
+        let attesters = FabricCoordinatorGen::attesters(&genesis.hash);
+        assert_eq!(attesters[m1.as_slice()], trainers[..2].to_vec());
+        assert_eq!(attesters[m2.as_slice()], vec![trainers[2].clone()]);
+        assert_eq!(FabricCoordinatorGen::window(), vec![(0, vec![(genesis.hash.clone(), 3)])]);
+
+        // signers outside the trainer set are rejected
+        assert!(add(&[44u8; 64], &genesis.hash, &m1).is_none());
+
+        reset(&genesis.hash);
+    }
+
+    #[test]
+    fn test_prune() {
+        let _guard = Fabric::init_for_test();
+        let window = FabricCoordinatorGen::HEIGHT_WINDOW;
+        {
+            let mut state = COORDINATOR_STATE.lock().unwrap();
+            state.heights.clear();
+            for height in [0, 4, 5, 6, window + 5] {
+                state.heights.insert(height, HashMap::new());
+            }
+        }
+
+        // heights still within the window behind the chain are kept
+        FabricCoordinatorGen::prune(window + 5);
+        let heights: Vec<u64> = FabricCoordinatorGen::window().iter().map(|(h, _)| *h).collect();
+        assert_eq!(heights, vec![5, 6, window + 5]);
+
+        FabricCoordinatorGen::prune(window * 3);
+        assert!(FabricCoordinatorGen::window().is_empty());
+    }
+}
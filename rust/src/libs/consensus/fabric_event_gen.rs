@@ -1,3 +1,28 @@
-pub struct FabricEventGen {
-    
-}
\ No newline at end of file
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone)]
+pub enum FabricEvent {
+    /// The entry became final, everything up to it can no longer be rewound
+    Rooted {
+        entry_hash: Vec<u8>,
+        mutations_hash: Vec<u8>,
+        height: u64,
+    },
+}
+
+static FABRIC_EVENTS: Lazy<broadcast::Sender<FabricEvent>> =
+    Lazy::new(|| broadcast::channel(1024).0);
+
+pub struct FabricEventGen {}
+
+impl FabricEventGen {
+    pub fn subscribe() -> broadcast::Receiver<FabricEvent> {
+        FABRIC_EVENTS.subscribe()
+    }
+
+    /// Fire and forget, slow subscribers lag instead of blocking consensus
+    pub fn emit(event: FabricEvent) {
+        let _ = FABRIC_EVENTS.send(event);
+    }
+}
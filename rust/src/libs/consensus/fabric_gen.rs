@@ -1,3 +1,165 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::time::interval;
+use tracing::{error, info};
+
+use crate::*;
+
+#[derive(Debug)]
+pub enum FabricMessage {
+    TickSlot,
+}
+
+/// Produces an entry whenever the next slot belongs to our trainer key
 pub struct FabricGen {
-    
-}
\ No newline at end of file
+    sender: UnboundedSender<FabricMessage>,
+    receiver: UnboundedReceiver<FabricMessage>,
+    socket_sender: mpsc::Sender<(Vec<IpAddr>, Vec<u8>)>,
+    last_produced_slot: Option<u64>,
+}
+
+impl FabricGen {
+    pub const TICK_MS: u64 = 50;
+    /// Minimum time between seeing the tip and producing on top of it
+    pub const SLOT_MS: u64 = 500;
+
+    pub fn start_link(socket_sender: mpsc::Sender<(Vec<IpAddr>, Vec<u8>)>) -> Self {
+        let (sender, receiver) = unbounded_channel();
+
+        FabricGen {
+            sender,
+            receiver,
+            socket_sender,
+            last_produced_slot: None,
+        }
+    }
+
+    pub fn sender(&self) -> UnboundedSender<FabricMessage> {
+        self.sender.clone()
+    }
+
+    pub async fn run(&mut self) {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_millis(Self::TICK_MS));
+            loop {
+                interval.tick().await;
+                if sender.send(FabricMessage::TickSlot).is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(msg) = self.receiver.recv().await {
+            match msg {
+                FabricMessage::TickSlot => {
+                    if let Err(err) = self.tick_slot() {
+                        error!("entry production failed: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn tick_slot(&mut self) -> Result<(), ConsensusError> {
+        Rooting::proc_rooting()?;
+
+        if !FabricSyncAttestGen::is_quorum_in_epoch() {
+            return Ok(());
+        }
+
+        let best_tip = Consensus::best_tip();
+        if Fabric::temporal_tip().is_some_and(|tip| tip != best_tip.hash) {
+            Consensus::chain_switch(&best_tip.hash)?;
+        }
+
+        let cur_entry = Consensus::chain_tip_entry();
+        let next_slot = cur_entry.header_unpacked.slot + 1;
+        let next_height = cur_entry.header_unpacked.height + 1;
+
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let seen_time = Fabric::my_seen_time(&cur_entry.hash).unwrap_or(0);
+        if now < seen_time + Self::SLOT_MS {
+            return Ok(());
+        }
+
+        if !self.may_produce(next_height, next_slot, &AMACONFIG.trainer_pk()) {
+            return Ok(());
+        }
+
+        self.last_produced_slot = Some(next_slot);
+        self.produce(next_slot)
+    }
+
+    /// Whether `pk` owns `slot` at `height` and has not signed an entry for it yet
+    fn may_produce(&self, height: u64, slot: u64, pk: &[u8]) -> bool {
+        // never sign two entries for the same slot
+        if self.last_produced_slot.is_some_and(|last| last >= slot) {
+            return false;
+        }
+        Consensus::trainer_for_slot(height, slot).as_deref() == Some(pk)
+    }
+
+    fn produce(&self, slot: u64) -> Result<(), ConsensusError> {
+        let entry = Consensus::produce_entry(slot);
+        info!(
+            "producing entry height {} slot {} txs {}",
+            entry.header_unpacked.height,
+            slot,
+            entry.txs.len()
+        );
+
+        Fabric::insert_entry(&entry, chrono::Utc::now().timestamp_millis())?;
+        Consensus::apply_entry(&entry)?;
+        TXPool::delete_packed(&entry.txs);
+
+        NodeGen::broadcast(
+            BroadcastKind::Entry,
+            "trainers",
+            Entry::pack(entry),
+            self.socket_sender.clone(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fabric_gen() -> FabricGen {
+        FabricGen::start_link(mpsc::channel(1).0)
+    }
+
+    #[test]
+    fn test_may_produce() {
+        let _guard = Fabric::init_for_test();
+        let pk_a = BlsRs::get_public_key(&[21u8; 64]).unwrap();
+        let pk_b = BlsRs::get_public_key(&[22u8; 64]).unwrap();
+        Consensus::invalidate_trainers_cache();
+        TRAINERS_CACHE.insert(0, vec![(0, vec![pk_a.clone(), pk_b.clone()])]);
+
+        // slots rotate over the trainer set
+        let mut fabric_gen = fabric_gen();
+        assert!(fabric_gen.may_produce(1, 2, &pk_a));
+        assert!(!fabric_gen.may_produce(1, 2, &pk_b));
+        assert!(fabric_gen.may_produce(1, 3, &pk_b));
+        assert!(!fabric_gen.may_produce(1, 3, &pk_a));
+
+        // a slot we already signed, or an older one after a rewind, is never signed again
+        fabric_gen.last_produced_slot = Some(4);
+        assert!(!fabric_gen.may_produce(5, 4, &pk_a));
+        assert!(!fabric_gen.may_produce(3, 2, &pk_a));
+        assert!(fabric_gen.may_produce(7, 6, &pk_a));
+
+        // no trainers known for the height, nobody produces
+        Consensus::invalidate_trainers_cache();
+        TRAINERS_CACHE.insert(0, vec![(10, vec![pk_a.clone()])]);
+        assert!(!fabric_gen.may_produce(9, 8, &pk_a));
+        assert!(fabric_gen.may_produce(10, 8, &pk_a));
+
+        Consensus::invalidate_trainers_cache();
+    }
+}
@@ -52,35 +52,49 @@ impl FabricSnapshot {
     //     }
     // }
 
-    // fn delete_entry_and_metadata(entry: &Entry, fabric: &Fabric) {
-    //     let height = entry.height;
-    //     let hash = &entry.hash;
-    //     let wo = WriteOptions::default();
+    /// Removes an entry and everything keyed by its hash from the fabric
+    pub fn delete_entry_and_metadata(entry: &Entry) -> Result<(), rocksdb::Error> {
+        let rtx = Fabric::transaction();
+        let hash = &entry.hash;
+        let header = &entry.header_unpacked;
+
+        rtx.delete_cf(&Fabric::cf_handle("default"), hash)?;
+        rtx.delete_cf(
+            &Fabric::cf_handle("entry_by_height"),
+            Fabric::index_key(header.height, hash),
+        )?;
+        rtx.delete_cf(
+            &Fabric::cf_handle("entry_by_slot"),
+            Fabric::index_key(header.slot, hash),
+        )?;
+        for cf in [
+            "consensus_by_entryhash",
+            "my_seen_time_for_entry",
+            "my_attestation_for_entry",
+            "muts",
+            "muts_rev",
+            "state_root",
+        ] {
+            rtx.delete_cf(&Fabric::cf_handle(cf), hash)?;
+        }
 
-    //     fabric.db.delete_cf(&fabric.cf.default, hash).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.entry_by_height, format!("{}:{:?}", height, hash)).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.entry_by_slot, format!("{}:{:?}", height, hash)).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.consensus_by_entryhash, hash).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.my_attestation_for_entry, hash).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.muts, hash).unwrap();
-    //     fabric.db.delete_cf(&fabric.cf.muts_rev, hash).unwrap();
-    // }
+        rtx.commit()
+    }
+
+    /// Drops a list of temporal entries (oldest first), rewinding the chain
+    /// first for any of them that we already applied
+    pub fn backstep_temporal(list: &[Vec<u8>]) -> Result<(), ConsensusError> {
+        for hash in list.iter().rev() {
+            if Consensus::is_in_chain(hash) && !Consensus::chain_rewind(hash)? {
+                return Err(ConsensusError::InvalidEntry);
+            }
+            if let Some(entry) = Fabric::entry_by_hash(Some(hash)) {
+                Self::delete_entry_and_metadata(&entry)?;
+            }
+        }
+        Ok(())
+    }
 
-    // pub fn backstep_temporal(list: Vec<Vec<u8>>) {
-    //     let guard = FABRIC_DB.read().unwrap();
-    //     let fabric = guard.as_ref().unwrap();
-
-    //     for hash in list.into_iter().rev() {
-    //         let entry = Fabric::entry_by_hash(&hash);
-    //         let in_chain = Consensus::is_in_chain(&hash);
-    //         if in_chain {
-    //             assert!(Consensus::chain_rewind(&hash));
-    //         }
-    //         if let Some(e) = entry {
-    //             Self::delete_entry_and_metadata(&e, fabric);
-    //         }
-    //     }
-    // }
     pub async fn download_latest() -> Result<(), Box<dyn std::error::Error>> {
         let height: u64 = AMACONFIG.snapshot_height;
         let padded = format!("{:012}", height);
@@ -1,10 +1,164 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::*;
+
+/// Sync status derived from the tips peers advertise in their pings
 pub struct FabricSyncAttestGen {}
 
 impl FabricSyncAttestGen {
-    pub fn has_quorum() {}
-    pub fn is_in_epoch() {}
+    /// Latest fresh temporal height of each current trainer. Peers that are not trainers
+    /// do not count, and a trainer reachable over several IPs counts once.
+    fn temporal_heights() -> Vec<u64> {
+        let trainers = Consensus::trainers_for_height(Consensus::chain_height() + 1);
+        let mut latest: HashMap<Vec<u8>, (Instant, u64)> = HashMap::new();
+        for tips in PEER_TIPS.iter() {
+            if tips.seen_at.elapsed() >= FabricSyncGen::PEER_TIP_TTL
+                || !trainers.contains(&tips.signer)
+            {
+                continue;
+            }
+            let seen = latest
+                .entry(tips.signer.clone())
+                .or_insert((tips.seen_at, tips.temporal_height));
+            if tips.seen_at > seen.0 {
+                *seen = (tips.seen_at, tips.temporal_height);
+            }
+        }
+        latest.into_values().map(|(_, height)| height).collect()
+    }
+
+    pub fn highest_temporal_height() -> Option<u64> {
+        Self::temporal_heights().into_iter().max()
+    }
+
+    /// Enough fresh trainer peers to form a quorum at all
+    pub fn has_quorum() -> bool {
+        AMACONFIG.offline || Self::temporal_heights().len() >= AMACONFIG.quorum as usize
+    }
+
+    /// Highest temporal height that at least a quorum of peers has reached
+    pub fn quorum_temporal_height() -> Option<u64> {
+        let mut heights = Self::temporal_heights();
+        heights.sort_unstable_by(|a, b| b.cmp(a));
+        heights.get((AMACONFIG.quorum as usize).max(1) - 1).copied()
+    }
+
+    /// A quorum of peers, and at least `trustfactor` of all fresh peers,
+    /// sit within one entry of our temporal tip
+    pub fn is_quorum_synced_off_by_1() -> bool {
+        if AMACONFIG.offline {
+            return true;
+        }
+
+        Self::synced_off_by_1(
+            &Self::temporal_heights(),
+            Consensus::chain_height(),
+            AMACONFIG.quorum as usize,
+            AMACONFIG.trustfactor,
+        )
+    }
+
+    fn synced_off_by_1(heights: &[u64], our_height: u64, quorum: usize, trustfactor: f64) -> bool {
+        if heights.len() < quorum || heights.is_empty() {
+            return false;
+        }
+
+        let agreeing = heights
+            .iter()
+            .filter(|height| height.abs_diff(our_height) <= 1)
+            .count();
+
+        agreeing >= quorum && agreeing as f64 / heights.len() as f64 >= trustfactor
+    }
+
+    /// Our temporal tip is in the same epoch as the quorum of peers
+    pub fn is_in_epoch() -> bool {
+        if AMACONFIG.offline {
+            return true;
+        }
+
+        Self::quorum_temporal_height()
+            .is_some_and(|height| height / Epoch::EPOCH_INTERVAL == Consensus::chain_epoch())
+    }
 
     pub fn is_quorum_in_epoch() -> bool {
-        true //  temporarily
+        Self::has_quorum() && Self::is_in_epoch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn seed_tips(n: u8, signer: u8, temporal_height: u64, age: Duration) {
+        PEER_TIPS.insert(
+            IpAddr::from([10, 0, 1, n]),
+            PeerTips {
+                signer: vec![signer; 48],
+                temporal_hash: vec![0; 32],
+                temporal_height,
+                rooted_hash: vec![0; 32],
+                rooted_height: 0,
+                seen_at: Instant::now() - age,
+            },
+        );
+    }
+
+    #[test]
+    fn test_synced_off_by_1() {
+        let _guard = Fabric::init_for_test();
+        PEER_TIPS.clear();
+        let trainers = (0..6u8).map(|n| vec![n; 48]).collect();
+        TRAINERS_CACHE.insert(0, vec![(0, trainers)]);
+        for (n, height) in [9, 10, 11, 12].into_iter().enumerate() {
+            seed_tips(n as u8, n as u8, height, Duration::ZERO);
+        }
+        // a stale peer far ahead does not count against us
+        seed_tips(
+            9,
+            4,
+            500,
+            FabricSyncGen::PEER_TIP_TTL + Duration::from_secs(1),
+        );
+        // neither do peers that are not trainers
+        seed_tips(10, 42, 500, Duration::ZERO);
+        seed_tips(11, 43, 500, Duration::ZERO);
+
+        let mut heights = FabricSyncAttestGen::temporal_heights();
+        heights.sort_unstable();
+        assert_eq!(heights, vec![9, 10, 11, 12]);
+        assert_eq!(FabricSyncAttestGen::highest_temporal_height(), Some(12));
+
+        // one behind and one ahead agree, two ahead does not: 3 of 4 peers
+        let synced = |our_height, quorum, trustfactor| {
+            FabricSyncAttestGen::synced_off_by_1(&heights, our_height, quorum, trustfactor)
+        };
+        assert!(synced(10, 3, 0.75));
+        assert!(!synced(10, 4, 0.75));
+        assert!(!synced(10, 3, 0.8));
+        // at 11 every peer is within one
+        assert!(synced(11, 4, 1.0));
+        // at 13 only the peer at 12 agrees
+        assert!(synced(13, 1, 0.25));
+        assert!(!synced(13, 2, 0.25));
+        assert!(!synced(20, 1, 0.0));
+
+        // fewer fresh peers than the quorum never counts as synced
+        assert!(!FabricSyncAttestGen::synced_off_by_1(&heights, 10, 5, 0.0));
+        assert!(!FabricSyncAttestGen::synced_off_by_1(&[], 10, 0, 0.0));
+
+        // more IPs for one trainer only count its latest tip
+        seed_tips(12, 5, 500, Duration::from_secs(2));
+        seed_tips(13, 5, 501, Duration::from_secs(1));
+        seed_tips(14, 5, 12, Duration::ZERO);
+        let mut heights = FabricSyncAttestGen::temporal_heights();
+        heights.sort_unstable();
+        assert_eq!(heights, vec![9, 10, 11, 12, 12]);
+
+        PEER_TIPS.clear();
+        Consensus::invalidate_trainers_cache();
     }
 }
@@ -0,0 +1,600 @@
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::time::interval;
+use tracing::error;
+
+use crate::*;
+
+/// Latest verified ping tips per peer
+pub static PEER_TIPS: Lazy<DashMap<IpAddr, PeerTips>> = Lazy::new(|| DashMap::new());
+
+#[derive(Debug, Clone)]
+pub struct PeerTips {
+    /// Node key the ping was signed with
+    pub signer: Vec<u8>,
+    pub temporal_hash: Vec<u8>,
+    pub temporal_height: u64,
+    pub rooted_hash: Vec<u8>,
+    pub rooted_height: u64,
+    pub seen_at: Instant,
+}
+
+#[derive(Debug)]
+pub enum SyncMessage {
+    Tick,
+    Entry {
+        peer: IpAddr,
+        entry_packed: Vec<u8>,
+        consensus_packed: Option<Vec<u8>>,
+        attestation_packed: Option<Vec<u8>>,
+    },
+}
+
+struct InFlight {
+    peer: IpAddr,
+    since: Instant,
+}
+
+struct SyncedEntry {
+    peer: IpAddr,
+    entry: Entry,
+    consensus_packed: Option<Vec<u8>>,
+    attestation_packed: Option<Vec<u8>>,
+    received: Instant,
+}
+
+/// Catches a lagging node up by requesting missing heights from peers ahead of us
+pub struct FabricSyncGen {
+    sender: UnboundedSender<SyncMessage>,
+    receiver: UnboundedReceiver<SyncMessage>,
+    outbound: UnboundedSender<(IpAddr, NodeMsg)>,
+    in_flight: HashMap<u64, InFlight>,
+    buffer: BTreeMap<u64, Vec<SyncedEntry>>,
+    /// Parents of a competing branch asked for by hash, with the time of the request
+    solicited: HashMap<Vec<u8>, Instant>,
+    peer_failures: HashMap<IpAddr, u32>,
+}
+
+impl FabricSyncGen {
+    pub const TICK_MS: u64 = 500;
+    /// Heights asked from one peer in a single catchup request
+    pub const BATCH: u64 = 20;
+    pub const MAX_IN_FLIGHT: u64 = 400;
+    pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+    pub const PEER_TIP_TTL: Duration = Duration::from_secs(30);
+    /// Peers that timed out or sent garbage this many times are skipped
+    pub const MAX_FAILURES: u32 = 3;
+    pub const MAX_SERVE_HEIGHTS: usize = 100;
+    /// Distinct entries kept per buffered height
+    pub const MAX_CANDIDATES: usize = 4;
+    /// Buffered entries that could not be applied in this time are dropped
+    pub const BUFFER_TTL: Duration = Duration::from_secs(30);
+
+    pub fn start_link(outbound: UnboundedSender<(IpAddr, NodeMsg)>) -> Self {
+        let (sender, receiver) = unbounded_channel();
+
+        FabricSyncGen {
+            sender,
+            receiver,
+            outbound,
+            in_flight: HashMap::new(),
+            buffer: BTreeMap::new(),
+            solicited: HashMap::new(),
+            peer_failures: HashMap::new(),
+        }
+    }
+
+    pub fn sender(&self) -> UnboundedSender<SyncMessage> {
+        self.sender.clone()
+    }
+
+    pub async fn run(&mut self) {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_millis(Self::TICK_MS));
+            loop {
+                interval.tick().await;
+                if sender.send(SyncMessage::Tick).is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(msg) = self.receiver.recv().await {
+            match msg {
+                SyncMessage::Tick => self.tick(),
+                SyncMessage::Entry {
+                    peer,
+                    entry_packed,
+                    consensus_packed,
+                    attestation_packed,
+                } => {
+                    self.handle_entry(peer, entry_packed, consensus_packed, attestation_packed);
+                    self.drain();
+                }
+            }
+        }
+    }
+
+    /// Record the tips a peer advertised in its ping, ignoring ones with a bad signature.
+    /// `signer` is the node key the ping message was signed with.
+    pub fn observe_ping(peer: IpAddr, signer: &[u8], temporal: &Tip, rooted: &Tip) {
+        let (Some(temporal_hash), Some(rooted_hash)) = (
+            Self::verified_tip_hash(temporal),
+            Self::verified_tip_hash(rooted),
+        ) else {
+            return;
+        };
+
+        PEER_TIPS.insert(
+            peer,
+            PeerTips {
+                signer: signer.to_vec(),
+                temporal_hash,
+                temporal_height: temporal.header_unpacked.height,
+                rooted_hash,
+                rooted_height: rooted.header_unpacked.height,
+                seen_at: Instant::now(),
+            },
+        );
+    }
+
+    fn verified_tip_hash(tip: &Tip) -> Option<Vec<u8>> {
+        let hash = Entry::hash_header(&tip.header_unpacked);
+        let entry = Entry {
+            signature: tip.signature.clone(),
+            hash: hash.clone(),
+            header_unpacked: tip.header_unpacked.clone(),
+            txs: vec![],
+            mask: tip.mask.clone(),
+        };
+        Entry::validate_signature(&entry).ok()?;
+        Some(hash)
+    }
+
+    fn tick(&mut self) {
+        PEER_TIPS.retain(|_, tips| tips.seen_at.elapsed() < Self::PEER_TIP_TTL);
+        self.solicited
+            .retain(|_, since| since.elapsed() < Self::REQUEST_TIMEOUT);
+        self.buffer.retain(|_, candidates| {
+            candidates.retain(|synced| synced.received.elapsed() < Self::BUFFER_TTL);
+            !candidates.is_empty()
+        });
+
+        // requests that took too long count against the peer and get reassigned
+        let timed_out: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(height, req)| {
+                req.since.elapsed() > Self::REQUEST_TIMEOUT && !self.buffer.contains_key(height)
+            })
+            .map(|(height, _)| *height)
+            .collect();
+        for height in timed_out {
+            if let Some(req) = self.in_flight.remove(&height) {
+                *self.peer_failures.entry(req.peer).or_default() += 1;
+            }
+        }
+
+        let our_height = Consensus::chain_height();
+        let Some(target) = PEER_TIPS.iter().map(|tips| tips.temporal_height).max() else {
+            return;
+        };
+        if target <= our_height {
+            return;
+        }
+
+        let missing: Vec<u64> = (our_height + 1..=target.min(our_height + Self::MAX_IN_FLIGHT))
+            .filter(|height| {
+                !self.in_flight.contains_key(height) && !self.buffer.contains_key(height)
+            })
+            .collect();
+
+        let mut peers: Vec<(IpAddr, PeerTips)> = PEER_TIPS
+            .iter()
+            .filter(|item| {
+                self.peer_failures.get(item.key()).copied().unwrap_or(0) < Self::MAX_FAILURES
+            })
+            .map(|item| (*item.key(), item.value().clone()))
+            .collect();
+        if peers.is_empty() {
+            // everyone misbehaved at some point, start over rather than stall
+            self.peer_failures.clear();
+            return;
+        }
+        peers.sort_by_key(|(peer, _)| self.peer_failures.get(peer).copied().unwrap_or(0));
+
+        for (i, chunk) in missing.chunks(Self::BATCH as usize).enumerate() {
+            let last = *chunk.last().unwrap();
+            let capable: Vec<&(IpAddr, PeerTips)> = peers
+                .iter()
+                .filter(|(_, tips)| tips.temporal_height >= last)
+                .collect();
+            if capable.is_empty() {
+                continue;
+            }
+            let (peer, tips) = capable[i % capable.len()];
+
+            // rooted heights come with their final consensus, temporal ones need attestations too
+            let msg = if last <= tips.rooted_height {
+                NodeProto::catchup_bi(chunk.to_vec())
+            } else {
+                NodeProto::catchup_tri(chunk.to_vec())
+            };
+            if self.outbound.send((*peer, msg)).is_err() {
+                return;
+            }
+            for height in chunk {
+                self.in_flight.insert(
+                    *height,
+                    InFlight {
+                        peer: *peer,
+                        since: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Buffers an entry we asked the peer for. Anything unrequested is dropped so a peer
+    /// cannot grow the buffer, solicited parents go straight into the fabric.
+    fn handle_entry(
+        &mut self,
+        peer: IpAddr,
+        entry_packed: Vec<u8>,
+        consensus_packed: Option<Vec<u8>>,
+        attestation_packed: Option<Vec<u8>>,
+    ) {
+        let entry = Entry::unpack(Some(&entry_packed))
+            .filter(|entry| Entry::validate_entry(entry).is_ok())
+            .filter(|entry| Entry::validate_signature(entry).is_ok());
+        let Some(entry) = entry else {
+            *self.peer_failures.entry(peer).or_default() += 1;
+            return;
+        };
+
+        let height = entry.header_unpacked.height;
+        if height <= Fabric::rooted_tip_height().unwrap_or(0) {
+            return;
+        }
+
+        if self.solicited.remove(&entry.hash).is_some() {
+            if !Self::validate_producer(&entry) {
+                *self.peer_failures.entry(peer).or_default() += 1;
+                return;
+            }
+            let seen_time = chrono::Utc::now().timestamp_millis();
+            if Fabric::insert_entry(&entry, seen_time).is_ok() {
+                self.solicit_parent(peer, &entry);
+            }
+            return;
+        }
+
+        if !self
+            .in_flight
+            .get(&height)
+            .is_some_and(|req| req.peer == peer)
+        {
+            return;
+        }
+        let candidates = self.buffer.entry(height).or_default();
+        if candidates.len() < Self::MAX_CANDIDATES
+            && candidates.iter().all(|c| c.entry.hash != entry.hash)
+        {
+            candidates.push(SyncedEntry {
+                peer,
+                entry,
+                consensus_packed,
+                attestation_packed,
+                received: Instant::now(),
+            });
+        }
+    }
+
+    /// Signed by the trainer owning the slot, or a special meeting quorum for masked entries
+    fn validate_producer(entry: &Entry) -> bool {
+        let header = &entry.header_unpacked;
+        Entry::validate_signature(entry).is_ok()
+            && (entry.mask.is_some()
+                || Consensus::trainer_for_slot(header.height, header.slot)
+                    .is_some_and(|trainer| trainer == header.signer))
+    }
+
+    /// Asks the peer for the parent of `entry` when the fabric does not have it yet.
+    /// Returns true while the parent is missing.
+    fn solicit_parent(&mut self, peer: IpAddr, entry: &Entry) -> bool {
+        let prev_hash = &entry.header_unpacked.prev_hash;
+        if Fabric::entry_by_hash(Some(prev_hash)).is_some() {
+            return false;
+        }
+        if !self.solicited.contains_key(prev_hash)
+            && self.solicited.len() < Self::MAX_IN_FLIGHT as usize
+            && self
+                .outbound
+                .send((peer, NodeProto::solicit_entry(prev_hash.clone())))
+                .is_ok()
+        {
+            self.solicited.insert(prev_hash.clone(), Instant::now());
+        }
+        true
+    }
+
+    /// Insert buffered entries in height order, applying the ones that extend our tip.
+    /// An entry on another branch switches the chain over to it once its parents are known.
+    fn drain(&mut self) {
+        loop {
+            let tip = Consensus::chain_tip_entry();
+            let next_height = tip.header_unpacked.height + 1;
+            self.buffer = self.buffer.split_off(&next_height);
+            self.in_flight.retain(|height, _| *height >= next_height);
+
+            let Some(candidates) = self.buffer.remove(&next_height) else {
+                break;
+            };
+            // applied or not, the height is done with, tick asks again if it is still missing
+            self.in_flight.remove(&next_height);
+
+            let mut applied = false;
+            let mut waiting = vec![];
+            for synced in candidates {
+                if !Self::validate_producer(&synced.entry) {
+                    *self.peer_failures.entry(synced.peer).or_default() += 1;
+                    continue;
+                }
+                let seen_time = chrono::Utc::now().timestamp_millis();
+                if Fabric::insert_entry(&synced.entry, seen_time).is_err() {
+                    continue;
+                }
+                if !applied {
+                    if synced.entry.header_unpacked.prev_hash == tip.hash {
+                        applied = Entry::validate_next(&tip, &synced.entry).is_ok()
+                            && Consensus::apply_entry(&synced.entry).is_ok();
+                    } else if self.solicit_parent(synced.peer, &synced.entry) {
+                        waiting.push(synced);
+                        continue;
+                    } else {
+                        applied = Consensus::chain_switch(&synced.entry.hash).is_ok();
+                    }
+                }
+
+                if let Some(mut consensus) = synced
+                    .consensus_packed
+                    .and_then(|packed| bincode::deserialize::<Consensus>(&packed).ok())
+                {
+                    if consensus.validate_vs_chain().is_ok() {
+                        let _ = Fabric::insert_consensus(&consensus);
+                    }
+                }
+                if let Some(attestation_packed) = synced.attestation_packed {
+                    FabricCoordinatorGen::send_attestation_bulk(vec![attestation_packed]);
+                }
+            }
+
+            if !applied {
+                // retried once the missing parents arrive, or dropped after BUFFER_TTL
+                if !waiting.is_empty() {
+                    self.buffer.insert(next_height, waiting);
+                }
+                break;
+            }
+        }
+
+        if let Err(err) = Rooting::proc_rooting() {
+            error!("rooting after sync failed: {}", err);
+        }
+    }
+
+    /// Answers catchup and solicit requests from other peers
+    pub fn serve(msg: &NodeMsg) -> Vec<NodeMsg> {
+        match msg {
+            NodeMsg::CatchupEntry { heights, .. } => Self::serve_heights(heights, false, false),
+            NodeMsg::CatchupBi { heights, .. } => Self::serve_heights(heights, true, false),
+            NodeMsg::CatchupTri { heights, .. } => Self::serve_heights(heights, true, true),
+            NodeMsg::CatchupAttestation { hashes, .. } => {
+                let attestations_packed: Vec<Vec<u8>> = hashes
+                    .iter()
+                    .take(Self::MAX_SERVE_HEIGHTS)
+                    .filter_map(|hash| Fabric::my_attestation(hash))
+                    .map(|attestation| attestation.pack())
+                    .collect();
+                if attestations_packed.is_empty() {
+                    return vec![];
+                }
+                vec![NodeMsg::AttestationBulk {
+                    op: "attestation_bulk".to_string(),
+                    attestations_packed,
+                }]
+            }
+            NodeMsg::SolicitEntry { hash, .. } => Fabric::entry_by_hash(Some(hash))
+                .map(|entry| Self::entry_reply(entry, true, true))
+                .into_iter()
+                .collect(),
+            NodeMsg::SolicitEntry2 { .. } => {
+                vec![Self::entry_reply(Consensus::best_tip(), true, true)]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn serve_heights(
+        heights: &[u64],
+        with_consensus: bool,
+        with_attestation: bool,
+    ) -> Vec<NodeMsg> {
+        heights
+            .iter()
+            .take(Self::MAX_SERVE_HEIGHTS)
+            .flat_map(|height| Fabric::entries_by_height(*height))
+            .map(|entry| Self::entry_reply(entry, with_consensus, with_attestation))
+            .collect()
+    }
+
+    fn entry_reply(entry: Entry, with_consensus: bool, with_attestation: bool) -> NodeMsg {
+        let consensus_packed = with_consensus
+            .then(|| Fabric::best_consensus_by_entryhash(&entry.hash))
+            .flatten()
+            .map(|consensus| consensus.pack());
+        let attestation_packed = with_attestation
+            .then(|| Fabric::my_attestation(&entry.hash))
+            .flatten()
+            .map(|attestation| attestation.pack());
+
+        NodeProto::entry_reply(Entry::pack(entry), consensus_packed, attestation_packed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    fn seed_tips(peer: IpAddr, temporal_height: u64, rooted_height: u64, age: Duration) {
+        PEER_TIPS.insert(
+            peer,
+            PeerTips {
+                signer: vec![0; 48],
+                temporal_hash: vec![0; 32],
+                temporal_height,
+                rooted_hash: vec![0; 32],
+                rooted_height,
+                seen_at: Instant::now() - age,
+            },
+        );
+    }
+
+    fn sent(outbound: &mut UnboundedReceiver<(IpAddr, NodeMsg)>) -> Vec<(IpAddr, NodeMsg)> {
+        std::iter::from_fn(|| outbound.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_catchup_targets() {
+        let _guard = Fabric::init_for_test();
+        PEER_TIPS.clear();
+        let (outbound, mut outbound_rx) = unbounded_channel();
+        let mut sync = FabricSyncGen::start_link(outbound);
+        assert_eq!(Consensus::chain_height(), 0);
+
+        // nobody ahead of us, nothing to ask for
+        seed_tips(peer(1), 0, 0, Duration::ZERO);
+        sync.tick();
+        assert!(sent(&mut outbound_rx).is_empty());
+
+        // a stale tip is forgotten before picking the target
+        seed_tips(
+            peer(2),
+            500,
+            500,
+            FabricSyncGen::PEER_TIP_TTL + Duration::from_secs(1),
+        );
+        sync.tick();
+        assert!(sent(&mut outbound_rx).is_empty());
+        assert!(!PEER_TIPS.contains_key(&peer(2)));
+
+        // peer 3 has failed too often, so the heights only it holds are left unassigned
+        seed_tips(peer(1), 40, 30, Duration::ZERO);
+        seed_tips(peer(3), 60, 60, Duration::ZERO);
+        sync.peer_failures
+            .insert(peer(3), FabricSyncGen::MAX_FAILURES);
+        sync.tick();
+
+        let msgs = sent(&mut outbound_rx);
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs.iter().all(|(to, _)| *to == peer(1)));
+        // rooted batches go out as catchup_bi, the rest also needs attestations
+        assert!(matches!(
+            &msgs[0].1,
+            NodeMsg::CatchupBi { heights, .. } if *heights == (1..=20).collect::<Vec<u64>>()
+        ));
+        assert!(matches!(
+            &msgs[1].1,
+            NodeMsg::CatchupTri { heights, .. } if *heights == (21..=40).collect::<Vec<u64>>()
+        ));
+        assert!((1..=40).all(|height| sync.in_flight.contains_key(&height)));
+        assert!(!sync.in_flight.contains_key(&41));
+
+        // heights already in flight are not requested twice
+        sync.tick();
+        assert!(sent(&mut outbound_rx).is_empty());
+
+        PEER_TIPS.clear();
+    }
+
+    const TRAINER_SK: [u8; 64] = [21u8; 64];
+    const OUTSIDER_SK: [u8; 64] = [22u8; 64];
+
+    fn signed_next(prev: &Entry, slot: u64, sk: &[u8]) -> Entry {
+        let pk = BlsRs::get_public_key(sk).unwrap();
+        let mut entry = Entry::build_next(prev, slot, &pk, sk);
+        entry.header_unpacked.txs_hash = blake3::hash(&[]).as_bytes().to_vec();
+        entry.hash = Entry::hash_header(&entry.header_unpacked);
+        entry.signature = BlsRs::sign(sk, &entry.hash, BLS12AggSig::DST_ENTRY).unwrap();
+        entry
+    }
+
+    fn request(sync: &mut FabricSyncGen, height: u64, peer: IpAddr) {
+        let since = Instant::now();
+        sync.in_flight.insert(height, InFlight { peer, since });
+    }
+
+    fn deliver(sync: &mut FabricSyncGen, peer: IpAddr, entry: &Entry) {
+        sync.handle_entry(peer, Entry::pack(entry.clone()), None, None);
+        sync.drain();
+    }
+
+    #[test]
+    fn test_synced_entries() {
+        let _guard = Fabric::init_for_test();
+        let trainer = BlsRs::get_public_key(&TRAINER_SK).unwrap();
+        TRAINERS_CACHE.insert(0, vec![(0, vec![trainer])]);
+        let (outbound, mut outbound_rx) = unbounded_channel();
+        let mut sync = FabricSyncGen::start_link(outbound);
+        let genesis = Consensus::chain_tip_entry();
+
+        // nothing is buffered unless it was asked for, and only from the peer asked
+        let a1 = signed_next(&genesis, 1, &TRAINER_SK);
+        deliver(&mut sync, peer(1), &a1);
+        request(&mut sync, 1, peer(2));
+        deliver(&mut sync, peer(1), &a1);
+        assert_eq!(Consensus::chain_height(), 0);
+        assert!(sync.buffer.is_empty());
+
+        // a validly signed entry from a key that does not own the slot is refused
+        let forged = signed_next(&genesis, 1, &OUTSIDER_SK);
+        deliver(&mut sync, peer(2), &forged);
+        assert_eq!(Consensus::chain_height(), 0);
+        assert_eq!(sync.peer_failures.get(&peer(2)), Some(&1));
+        assert!(!sync.in_flight.contains_key(&1));
+
+        request(&mut sync, 1, peer(1));
+        deliver(&mut sync, peer(1), &a1);
+        assert_eq!(Fabric::temporal_tip(), Some(a1.hash.clone()));
+
+        // peer 3 is on a longer branch: its parent is solicited, then the chain switches
+        let b1 = signed_next(&genesis, 2, &TRAINER_SK);
+        let b2 = signed_next(&b1, 3, &TRAINER_SK);
+        request(&mut sync, 2, peer(3));
+        deliver(&mut sync, peer(3), &b2);
+        assert_eq!(Fabric::temporal_tip(), Some(a1.hash.clone()));
+        let msgs = sent(&mut outbound_rx);
+        assert!(matches!(
+            msgs.as_slice(),
+            [(to, NodeMsg::SolicitEntry { hash, .. })] if *to == peer(3) && *hash == b1.hash
+        ));
+
+        deliver(&mut sync, peer(3), &b1);
+        assert_eq!(Fabric::temporal_tip(), Some(b2.hash.clone()));
+        assert!(!Consensus::is_in_chain(&a1.hash));
+        assert!(sync.buffer.is_empty() && sync.solicited.is_empty());
+
+        assert!(Consensus::chain_rewind(&b1.hash).unwrap());
+        Consensus::invalidate_trainers_cache();
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+
+use crate::*;
+
+/// A leaf of the temporal tree above the rooted tip
+#[derive(Debug, Clone)]
+pub struct TipCandidate {
+    pub entry: Entry,
+    pub score: f64,
+    pub seen_time: u64,
+}
+
+pub struct ForkChoice;
+
+impl ForkChoice {
+    /// All entries descending from the rooted tip that nothing builds on yet
+    pub fn candidates() -> Vec<TipCandidate> {
+        let Some(rooted) = Fabric::rooted_tip_entry() else {
+            return vec![];
+        };
+
+        let mut leaves = vec![];
+        let mut frontier = vec![rooted];
+        while !frontier.is_empty() {
+            let height = frontier[0].header_unpacked.height + 1;
+            let next: Vec<Entry> = Fabric::entries_by_height(height)
+                .into_iter()
+                .filter(|e| frontier.iter().any(|p| p.hash == e.header_unpacked.prev_hash))
+                .collect();
+
+            for parent in frontier {
+                if !next.iter().any(|e| e.header_unpacked.prev_hash == parent.hash) {
+                    leaves.push(parent);
+                }
+            }
+            frontier = next;
+        }
+
+        leaves.into_iter().map(Self::candidate).collect()
+    }
+
+    pub fn candidate(entry: Entry) -> TipCandidate {
+        let score = Fabric::best_consensus_by_entryhash(&entry.hash)
+            .and_then(|consensus| consensus.score)
+            .unwrap_or(0.0);
+        let seen_time = Fabric::my_seen_time(&entry.hash).unwrap_or(u64::MAX);
+
+        TipCandidate {
+            entry,
+            score,
+            seen_time,
+        }
+    }
+
+    /// Higher score, then higher height, then first seen; hash breaks exact ties
+    pub fn rank(a: &TipCandidate, b: &TipCandidate) -> Ordering {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| b.entry.header_unpacked.height.cmp(&a.entry.header_unpacked.height))
+            .then_with(|| a.seen_time.cmp(&b.seen_time))
+            .then_with(|| a.entry.hash.cmp(&b.entry.hash))
+    }
+
+    pub fn best_tip() -> Option<Entry> {
+        let mut candidates = Self::candidates();
+        candidates.sort_by(Self::rank);
+        candidates.into_iter().next().map(|candidate| candidate.entry)
+    }
+}
+
+#[cfg(test)]
+mod fork_choice_tests {
+    use super::*;
+
+    fn candidate(hash: u8, height: u64, score: f64, seen_time: u64) -> TipCandidate {
+        TipCandidate {
+            entry: Entry {
+                signature: vec![],
+                hash: vec![hash; 32],
+                header_unpacked: EntryHeader {
+                    slot: height,
+                    height,
+                    prev_slot: height as i64 - 1,
+                    prev_hash: vec![],
+                    signer: vec![],
+                    dr: vec![],
+                    vr: vec![],
+                    txs_hash: vec![],
+                },
+                txs: vec![],
+                mask: None,
+            },
+            score,
+            seen_time,
+        }
+    }
+
+    fn best(mut candidates: Vec<TipCandidate>) -> Vec<u8> {
+        candidates.sort_by(ForkChoice::rank);
+        candidates[0].entry.hash.clone()
+    }
+
+    #[test]
+    fn test_score_wins_over_height() {
+        let a = candidate(1, 10, 0.8, 100);
+        let b = candidate(2, 11, 0.0, 50);
+        assert_eq!(best(vec![b, a]), vec![1; 32]);
+    }
+
+    #[test]
+    fn test_height_then_seen_time() {
+        let a = candidate(1, 10, 0.0, 100);
+        let b = candidate(2, 11, 0.0, 200);
+        assert_eq!(best(vec![a.clone(), b]), vec![2; 32]);
+
+        let c = candidate(3, 10, 0.0, 50);
+        assert_eq!(best(vec![a, c]), vec![3; 32]);
+    }
+
+    #[test]
+    fn test_hash_breaks_ties() {
+        let a = candidate(9, 10, 0.5, 100);
+        let b = candidate(4, 10, 0.5, 100);
+        assert_eq!(best(vec![a, b]), vec![4; 32]);
+    }
+}
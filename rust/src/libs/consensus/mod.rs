@@ -3,6 +3,7 @@ pub mod bls12_aggsig;
 pub mod consensus;
 pub mod consensus_kv;
 pub mod consensus_weight;
+pub mod contract_log;
 pub mod entry;
 pub mod entry_genesis;
 pub mod fabric;
@@ -12,13 +13,18 @@ pub mod fabric_gen;
 pub mod fabric_snapshot;
 pub mod fabric_sync_attest_gen;
 pub mod fabric_sync_gen;
+pub mod fork_choice;
+pub mod rooting;
 pub mod special_meeting_attest_gen;
+pub mod special_meeting_gen;
+pub mod state_tree;
 pub mod tx;
 pub use attestation::*;
 pub use bls12_aggsig::*;
 pub use consensus::*;
 pub use consensus_kv::*;
 pub use consensus_weight::*;
+pub use contract_log::*;
 pub use entry::*;
 pub use entry_genesis::*;
 pub use fabric::*;
@@ -28,5 +34,9 @@ pub use fabric_gen::*;
 pub use fabric_snapshot::*;
 pub use fabric_sync_attest_gen::*;
 pub use fabric_sync_gen::*;
+pub use fork_choice::*;
+pub use rooting::*;
 pub use special_meeting_attest_gen::*;
+pub use special_meeting_gen::*;
+pub use state_tree::*;
 pub use tx::*;
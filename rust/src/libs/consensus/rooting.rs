@@ -0,0 +1,74 @@
+use tracing::warn;
+
+use crate::*;
+
+pub struct Rooting;
+
+impl Rooting {
+    /// Fraction of trainer weight needed to root an entry.
+    /// Never below 2/3, raised by the trustfactor and, for small trainer
+    /// sets, by the configured quorum of signers.
+    pub fn threshold(trainer_count: usize) -> f64 {
+        let trust = AMACONFIG.trustfactor.clamp(2.0 / 3.0, 1.0);
+        if trainer_count == 0 {
+            return trust;
+        }
+        let quorum = (AMACONFIG.quorum as usize).min(trainer_count) as f64 / trainer_count as f64;
+        trust.max(quorum)
+    }
+
+    /// Roots as many entries on top of the rooted tip as have reached the
+    /// threshold for the same mutations_hash we computed ourselves
+    pub fn proc_rooting() -> Result<Vec<Entry>, ConsensusError> {
+        let mut rooted = vec![];
+
+        while let Some(rooted_height) = Fabric::rooted_tip_height() {
+            let Some(entry) = Consensus::chain_entry_at_height(rooted_height + 1) else {
+                break;
+            };
+            let Some(consensus) = Fabric::best_consensus_by_entryhash(&entry.hash) else {
+                break;
+            };
+
+            let trainers = Consensus::trainers_for_height(entry.header_unpacked.height);
+            if consensus.score.unwrap_or(0.0) < Self::threshold(trainers.len()) {
+                break;
+            }
+
+            let my_attestation = Fabric::my_attestation(&entry.hash);
+            if my_attestation.is_none_or(|att| att.mutations_hash != consensus.mutations_hash) {
+                warn!(
+                    "consensus at height {} disagrees with our mutations_hash",
+                    entry.header_unpacked.height
+                );
+                break;
+            }
+
+            Self::set_rooted_tip(&entry, &consensus)?;
+            FabricEventGen::emit(FabricEvent::Rooted {
+                entry_hash: entry.hash.clone(),
+                mutations_hash: consensus.mutations_hash.clone(),
+                height: entry.header_unpacked.height,
+            });
+            rooted.push(entry);
+        }
+
+        Ok(rooted)
+    }
+
+    /// Persists the final consensus and moves the rooted tip in one transaction
+    fn set_rooted_tip(entry: &Entry, consensus: &Consensus) -> Result<(), rocksdb::Error> {
+        let rtx = Fabric::transaction();
+        let packed = consensus.pack();
+
+        rtx.put_cf(&Fabric::cf_handle("consensus"), &entry.hash, &packed)?;
+
+        let cf = Fabric::cf_handle("consensus_by_entryhash");
+        let mut consensuses = Fabric::consensuses_by_entryhash(&entry.hash);
+        consensuses.insert(consensus.mutations_hash.clone(), consensus.clone());
+        rtx.put_cf(&cf, &entry.hash, bincode::serialize(&consensuses).unwrap())?;
+
+        rtx.put_cf(&Fabric::cf_handle("sysconf"), b"rooted_tip", &entry.hash)?;
+        rtx.commit()
+    }
+}
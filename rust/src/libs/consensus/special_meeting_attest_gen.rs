@@ -0,0 +1,155 @@
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::*;
+
+/// trainer -> height -> slots it missed while the chain waited on it at that height
+static MISSED_SLOTS: Lazy<Mutex<HashMap<Vec<u8>, BTreeMap<u64, u64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Decides whether this trainer co-signs a slashing motion proposed by another trainer
+pub struct SpecialMeetingAttestGen {}
+
+impl SpecialMeetingAttestGen {
+    /// Every this long the chain waits on one slot owner counts as a slot it missed
+    pub const STALL_MS: u64 = 30_000;
+    /// Missed slots within the window that make a trainer delinquent
+    pub const MISSED_SLOTS_THRESHOLD: u64 = 3;
+    /// Heights behind the tip over which missed slots are counted
+    pub const MISSED_SLOTS_WINDOW: u64 = 1_000;
+
+    /// The owner of the next slot, if the chain is waiting on it and it kept missing its
+    /// slots. One slow entry is not enough, only repeated or long stalls are.
+    pub fn delinquent_trainer() -> Option<Vec<u8>> {
+        let tip = Consensus::chain_tip_entry();
+        let seen_time = Fabric::my_seen_time(&tip.hash)?;
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let stalled = now.saturating_sub(seen_time) / Self::STALL_MS;
+        if stalled == 0 {
+            return None;
+        }
+
+        let height = tip.header_unpacked.height + 1;
+        let trainer = Consensus::trainer_for_slot(height, tip.header_unpacked.slot + 1)?;
+        (Self::note_missed(&trainer, height, stalled) >= Self::MISSED_SLOTS_THRESHOLD)
+            .then_some(trainer)
+    }
+
+    /// Records that `trainer` has missed `missed` slots at `height` so far and returns
+    /// its missed slots within the window ending at `height`
+    fn note_missed(trainer: &[u8], height: u64, missed: u64) -> u64 {
+        let mut missed_slots = MISSED_SLOTS.lock().unwrap();
+        let window_start = height.saturating_sub(Self::MISSED_SLOTS_WINDOW);
+        missed_slots.retain(|_, heights| {
+            heights.retain(|h, _| *h > window_start);
+            !heights.is_empty()
+        });
+
+        let heights = missed_slots.entry(trainer.to_vec()).or_default();
+        let at_height = heights.entry(height).or_default();
+        *at_height = (*at_height).max(missed);
+        heights.values().sum()
+    }
+
+    /// Signs the business if our own view agrees with it, the reply carries our pk and signature
+    pub fn maybe_attest(
+        business_op: &str,
+        business_args: &HashMap<String, Vec<u8>>,
+    ) -> Option<NodeMsg> {
+        let pk = AMACONFIG.trainer_pk();
+        let sk = AMACONFIG.trainer_sk();
+        let height = Consensus::chain_height() + 1;
+        if !Consensus::trainers_for_height(height).contains(&pk) {
+            return None;
+        }
+
+        let signature = match business_op {
+            "slash_trainer_tx" => {
+                let epoch: u64 = std::str::from_utf8(business_args.get("epoch")?)
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                let malicious_pk = business_args.get("malicious_pk")?;
+
+                if epoch != Consensus::chain_epoch()
+                    || Self::delinquent_trainer().as_ref() != Some(malicious_pk)
+                {
+                    return None;
+                }
+                Self::sign_motion(&sk, epoch, malicious_pk)?
+            }
+            "slash_trainer_entry" => {
+                let entry = Entry::unpack(Some(business_args.get("entry_packed")?))?;
+                Self::validate_slash_entry(&entry)?;
+                Self::sign_entry(&sk, &entry)?
+            }
+            _ => return None,
+        };
+
+        let mut reply_args = business_args.clone();
+        reply_args.insert("pk".to_string(), pk);
+        reply_args.insert("signature".to_string(), signature);
+
+        Some(NodeMsg::SpecialBusinessReply {
+            op: "special_business_reply".to_string(),
+            business_op: business_op.to_string(),
+            business_args: reply_args,
+        })
+    }
+
+    pub fn sign_motion(sk: &[u8], epoch: u64, malicious_pk: &[u8]) -> Option<Vec<u8>> {
+        let msg = Epoch::slash_trainer_msg(epoch, malicious_pk);
+        BlsRs::sign(sk, &msg, BLS12AggSig::DST_MOTION).ok()
+    }
+
+    /// Signed over the header hash, the same message Entry::validate_signature checks
+    pub fn sign_entry(sk: &[u8], entry: &Entry) -> Option<Vec<u8>> {
+        let hash = Entry::hash_header(&entry.header_unpacked);
+        BlsRs::sign(sk, &hash, BLS12AggSig::DST_ENTRY).ok()
+    }
+
+    /// The proposed entry must extend our tip with exactly one slash of the trainer we see stalling
+    fn validate_slash_entry(entry: &Entry) -> Option<()> {
+        let tip = Consensus::chain_tip_entry();
+        Entry::validate_entry(entry).ok()?;
+        Entry::validate_next(&tip, entry).ok()?;
+        if entry.txs.len() != 1 {
+            return None;
+        }
+
+        let txu = TX::validate(&entry.txs[0], true).ok()?;
+        let action = txu.tx.actions.first()?;
+        let malicious_pk = action.args.get(1)?;
+        (Self::delinquent_trainer().as_ref() == Some(malicious_pk)).then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_missed() {
+        let (slow, absent) = (vec![31u8; 48], vec![32u8; 48]);
+
+        // a single slow entry stays below the threshold however often it is looked at
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&slow, 10, 1), 1);
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&slow, 10, 1), 1);
+        // a trainer that keeps missing its slots adds up
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&slow, 50, 1), 2);
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&slow, 90, 1), 3);
+
+        // as does one that never shows up for its slot
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&absent, 100, 1), 1);
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&absent, 100, 3), 3);
+
+        // misses older than the window are forgotten
+        let later = 10 + SpecialMeetingAttestGen::MISSED_SLOTS_WINDOW;
+        assert_eq!(SpecialMeetingAttestGen::note_missed(&slow, later, 1), 3);
+        assert_eq!(
+            SpecialMeetingAttestGen::note_missed(&slow, later + 40, 0),
+            2
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use blst::min_pk::Signature;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::*;
+
+static MEETING_SENDER: OnceCell<UnboundedSender<MeetingMessage>> = OnceCell::new();
+
+#[derive(Debug)]
+pub enum MeetingMessage {
+    Tick,
+    Reply {
+        business_op: String,
+        business_args: HashMap<String, Vec<u8>>,
+    },
+}
+
+enum MeetingPhase {
+    /// Collecting DST_MOTION signatures over the slash motion
+    Tx,
+    /// Collecting entry signatures over the masked entry carrying the slash tx
+    Entry(Entry),
+}
+
+struct Meeting {
+    epoch: u64,
+    malicious_pk: Vec<u8>,
+    trainers: Vec<Vec<u8>>,
+    phase: MeetingPhase,
+    agg: Option<AggSig>,
+    started: Instant,
+}
+
+/// Runs the two round special meeting that slashes a trainer who stalls the chain:
+/// first a quorum signs the slash motion, then a quorum signs the entry carrying it
+pub struct SpecialMeetingGen {
+    sender: UnboundedSender<MeetingMessage>,
+    receiver: UnboundedReceiver<MeetingMessage>,
+    socket_sender: mpsc::Sender<(Vec<IpAddr>, Vec<u8>)>,
+    meeting: Option<Meeting>,
+}
+
+impl SpecialMeetingGen {
+    pub const TICK_MS: u64 = 1_000;
+    pub const MEETING_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn start_link(socket_sender: mpsc::Sender<(Vec<IpAddr>, Vec<u8>)>) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        let _ = MEETING_SENDER.set(sender.clone());
+
+        SpecialMeetingGen {
+            sender,
+            receiver,
+            socket_sender,
+            meeting: None,
+        }
+    }
+
+    /// Route a SpecialBusinessReply from another trainer into the running meeting
+    pub fn send_reply(business_op: String, business_args: HashMap<String, Vec<u8>>) {
+        if let Some(sender) = MEETING_SENDER.get() {
+            let _ = sender.send(MeetingMessage::Reply {
+                business_op,
+                business_args,
+            });
+        }
+    }
+
+    pub async fn run(&mut self) {
+        let sender = self.sender.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_millis(Self::TICK_MS));
+            loop {
+                interval.tick().await;
+                if sender.send(MeetingMessage::Tick).is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(msg) = self.receiver.recv().await {
+            match msg {
+                MeetingMessage::Tick => self.tick(),
+                MeetingMessage::Reply {
+                    business_op,
+                    business_args,
+                } => {
+                    if let Err(err) = self.handle_reply(&business_op, &business_args) {
+                        error!("special meeting failed: {}", err);
+                        self.meeting = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if self
+            .meeting
+            .as_ref()
+            .is_some_and(|meeting| meeting.started.elapsed() > Self::MEETING_TIMEOUT)
+        {
+            warn!("special meeting timed out");
+            self.meeting = None;
+        }
+        if self.meeting.is_some() {
+            return;
+        }
+
+        let pk = AMACONFIG.trainer_pk();
+        let height = Consensus::chain_height() + 1;
+        let trainers = Consensus::trainers_for_height(height);
+        if !trainers.contains(&pk) {
+            return;
+        }
+        let Some(malicious_pk) = SpecialMeetingAttestGen::delinquent_trainer() else {
+            return;
+        };
+        if malicious_pk == pk {
+            return;
+        }
+
+        let epoch = Consensus::chain_epoch();
+        info!(
+            "calling special meeting to slash {}",
+            bs58::encode(&malicious_pk).into_string()
+        );
+
+        let mut business_args = HashMap::new();
+        business_args.insert("epoch".to_string(), epoch.to_string().into_bytes());
+        business_args.insert("malicious_pk".to_string(), malicious_pk.clone());
+
+        self.meeting = Some(Meeting {
+            epoch,
+            malicious_pk,
+            trainers,
+            phase: MeetingPhase::Tx,
+            agg: None,
+            started: Instant::now(),
+        });
+        self.broadcast_business("slash_trainer_tx", &business_args);
+
+        // our own vote goes through the same path as everyone else's
+        if let Some(NodeMsg::SpecialBusinessReply {
+            business_op,
+            business_args,
+            ..
+        }) = SpecialMeetingAttestGen::maybe_attest("slash_trainer_tx", &business_args)
+        {
+            let _ = self.handle_reply(&business_op, &business_args);
+        }
+    }
+
+    fn handle_reply(
+        &mut self,
+        business_op: &str,
+        business_args: &HashMap<String, Vec<u8>>,
+    ) -> Result<(), ConsensusError> {
+        let Some(meeting) = self.meeting.as_mut() else {
+            return Ok(());
+        };
+        if !Self::collect(meeting, business_op, business_args)? {
+            return Ok(());
+        }
+
+        match meeting.phase {
+            MeetingPhase::Tx => self.propose_entry(),
+            MeetingPhase::Entry(_) => self.finish(),
+        }
+    }
+
+    /// Folds a trainer's signature into the meeting aggregate, true once a quorum has signed.
+    /// Both rounds sign what the chain later verifies: the motion message, then the entry
+    /// header hash.
+    fn collect(
+        meeting: &mut Meeting,
+        business_op: &str,
+        business_args: &HashMap<String, Vec<u8>>,
+    ) -> Result<bool, ConsensusError> {
+        let (Some(signer), Some(signature)) =
+            (business_args.get("pk"), business_args.get("signature"))
+        else {
+            return Ok(false);
+        };
+        if !meeting.trainers.contains(signer) {
+            return Ok(false);
+        }
+
+        let (msg, dst) = match (&meeting.phase, business_op) {
+            (MeetingPhase::Tx, "slash_trainer_tx") => {
+                if business_args.get("malicious_pk") != Some(&meeting.malicious_pk) {
+                    return Ok(false);
+                }
+                let msg = Epoch::slash_trainer_msg(meeting.epoch, &meeting.malicious_pk);
+                (msg, BLS12AggSig::DST_MOTION)
+            }
+            (MeetingPhase::Entry(entry), "slash_trainer_entry") => (
+                Entry::hash_header(&entry.header_unpacked),
+                BLS12AggSig::DST_ENTRY,
+            ),
+            _ => return Ok(false),
+        };
+        if !BlsRs::verify(signer, signature, &msg, dst) {
+            return Ok(false);
+        }
+        let Ok(signature) = Signature::from_bytes(signature) else {
+            return Ok(false);
+        };
+
+        let agg = match &meeting.agg {
+            Some(agg) => BLS12AggSig::add(agg, &meeting.trainers, signer.clone(), signature),
+            None => BLS12AggSig::new(&meeting.trainers, signer.clone(), signature),
+        }
+        .map_err(|_| ConsensusError::InvalidEntry)?;
+        let signed = agg.mask.count_ones() as f64;
+        let reached = signed >= meeting.trainers.len() as f64 * Entry::SPECIAL_MEETING_QUORUM;
+        meeting.agg = Some(agg);
+
+        Ok(reached)
+    }
+
+    /// Motion passed: wrap the slash tx in an entry and ask the trainers to sign it
+    fn propose_entry(&mut self) -> Result<(), ConsensusError> {
+        let meeting = self.meeting.as_mut().unwrap();
+        let agg = meeting.agg.take().unwrap();
+
+        let sk = AMACONFIG.trainer_sk();
+        let pk = AMACONFIG.trainer_pk();
+        let args = Self::slash_trainer_args(meeting, &agg);
+        let tx_packed = TX::build(&sk, "Epoch", "slash_trainer", args, None, None, None);
+
+        let tip = Consensus::chain_tip_entry();
+        let mut entry = Entry::build_next(&tip, tip.header_unpacked.slot + 1, &pk, &sk);
+        entry.txs = vec![tx_packed];
+        let entry = Entry::sign(entry);
+
+        let mut business_args = HashMap::new();
+        business_args.insert("entry_packed".to_string(), Entry::pack(entry.clone()));
+        meeting.phase = MeetingPhase::Entry(entry);
+
+        self.broadcast_business("slash_trainer_entry", &business_args);
+        if let Some(NodeMsg::SpecialBusinessReply {
+            business_op,
+            business_args,
+            ..
+        }) = SpecialMeetingAttestGen::maybe_attest("slash_trainer_entry", &business_args)
+        {
+            self.handle_reply(&business_op, &business_args)?;
+        }
+        Ok(())
+    }
+
+    /// Args of the Epoch.slash_trainer call carrying the passed motion
+    fn slash_trainer_args(meeting: &Meeting, agg: &AggSig) -> Vec<Vec<u8>> {
+        vec![
+            meeting.epoch.to_string().into_bytes(),
+            meeting.malicious_pk.clone(),
+            agg.aggsig.to_bytes().to_vec(),
+            meeting.trainers.len().to_string().into_bytes(),
+            BLS12AggSig::mask_to_bytes(&agg.mask),
+        ]
+    }
+
+    fn masked_entry(mut entry: Entry, agg: &AggSig) -> Entry {
+        entry.signature = agg.aggsig.to_bytes().to_vec();
+        entry.mask = Some(BLS12AggSig::mask_to_bytes(&agg.mask));
+        entry
+    }
+
+    /// Entry signed by a quorum: mask it, apply it and send it out
+    fn finish(&mut self) -> Result<(), ConsensusError> {
+        let meeting = self.meeting.take().unwrap();
+        let (MeetingPhase::Entry(entry), Some(agg)) = (meeting.phase, meeting.agg) else {
+            return Ok(());
+        };
+
+        let entry = Self::masked_entry(entry, &agg);
+        info!(
+            "slashing {} at height {}",
+            bs58::encode(&meeting.malicious_pk).into_string(),
+            entry.header_unpacked.height
+        );
+
+        Fabric::insert_entry(&entry, chrono::Utc::now().timestamp_millis())?;
+        Consensus::apply_entry(&entry)?;
+        NodeGen::broadcast(
+            BroadcastKind::Entry,
+            "trainers",
+            Entry::pack(entry),
+            self.socket_sender.clone(),
+        );
+        Ok(())
+    }
+
+    fn broadcast_business(&self, business_op: &str, business_args: &HashMap<String, Vec<u8>>) {
+        let payload = bincode::serialize(&(business_op, business_args)).unwrap();
+        NodeGen::broadcast(
+            BroadcastKind::SpecialBusiness,
+            "trainers",
+            payload,
+            self.socket_sender.clone(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRAINER_SKS: [[u8; 64]; 4] = [[51u8; 64], [52u8; 64], [53u8; 64], [54u8; 64]];
+
+    /// What a trainer running SpecialMeetingAttestGen sends back for the business
+    fn reply(
+        sk: &[u8],
+        business_args: &HashMap<String, Vec<u8>>,
+        signature: Vec<u8>,
+    ) -> HashMap<String, Vec<u8>> {
+        let mut reply_args = business_args.clone();
+        reply_args.insert("pk".to_string(), BlsRs::get_public_key(sk).unwrap());
+        reply_args.insert("signature".to_string(), signature);
+        reply_args
+    }
+
+    #[test]
+    fn test_meeting_round() {
+        let _guard = Fabric::init_for_test();
+        let trainers: Vec<Vec<u8>> = TRAINER_SKS
+            .iter()
+            .map(|sk| BlsRs::get_public_key(sk).unwrap())
+            .collect();
+        let malicious_pk = trainers[3].clone();
+        Consensus::invalidate_trainers_cache();
+        TRAINERS_CACHE.insert(0, vec![(0, trainers.clone())]);
+
+        let mut meeting = Meeting {
+            epoch: 0,
+            malicious_pk: malicious_pk.clone(),
+            trainers: trainers.clone(),
+            phase: MeetingPhase::Tx,
+            agg: None,
+            started: Instant::now(),
+        };
+
+        // motion: three of the four trainers are needed
+        let mut motion_args = HashMap::new();
+        motion_args.insert("epoch".to_string(), b"0".to_vec());
+        motion_args.insert("malicious_pk".to_string(), malicious_pk.clone());
+        let mut reached = vec![];
+        for sk in &TRAINER_SKS[..3] {
+            let signature = SpecialMeetingAttestGen::sign_motion(sk, 0, &malicious_pk).unwrap();
+            let args = reply(sk, &motion_args, signature);
+            reached
+                .push(SpecialMeetingGen::collect(&mut meeting, "slash_trainer_tx", &args).unwrap());
+        }
+        assert_eq!(reached, vec![false, false, true]);
+
+        // the passed motion is what Epoch.slash_trainer verifies on chain
+        let tip = Consensus::chain_tip_entry();
+        let motion = meeting.agg.take().unwrap();
+        let slash_args = SpecialMeetingGen::slash_trainer_args(&meeting, &motion);
+        let tx_packed = TX::build(
+            &TRAINER_SKS[0],
+            "Epoch",
+            "slash_trainer",
+            slash_args.clone(),
+            Some(1),
+            None,
+            None,
+        );
+        let mut entry = Entry::build_next(
+            &tip,
+            tip.header_unpacked.slot + 1,
+            &trainers[0],
+            &TRAINER_SKS[0],
+        );
+        entry.header_unpacked.txs_hash = blake3::hash(&tx_packed).as_bytes().to_vec();
+        entry.txs = vec![tx_packed];
+        entry.hash = Entry::hash_header(&entry.header_unpacked);
+        {
+            let rtx = Fabric::transaction();
+            let mut kv = ConsensusKV::new(&rtx);
+            let env = Consensus::make_mapenv(&entry);
+            Epoch::call(&mut kv, &env, "slash_trainer", &slash_args).unwrap();
+            let remaining = kv
+                .kv_get(&Consensus::trainers_height_key(2))
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                bincode::deserialize::<Vec<Vec<u8>>>(&remaining).unwrap(),
+                trainers[..3]
+            );
+        }
+
+        // entry: the same trainers sign the entry carrying the slash
+        meeting.phase = MeetingPhase::Entry(entry.clone());
+        let mut entry_args = HashMap::new();
+        entry_args.insert("entry_packed".to_string(), Entry::pack(entry.clone()));
+        for (i, sk) in TRAINER_SKS[..3].iter().enumerate() {
+            let signature = SpecialMeetingAttestGen::sign_entry(sk, &entry).unwrap();
+            let args = reply(sk, &entry_args, signature);
+            assert_eq!(
+                SpecialMeetingGen::collect(&mut meeting, "slash_trainer_entry", &args).unwrap(),
+                i == 2
+            );
+
+            // short of the quorum the masked entry is refused
+            let masked =
+                SpecialMeetingGen::masked_entry(entry.clone(), meeting.agg.as_ref().unwrap());
+            let expected = if i == 2 {
+                Ok(())
+            } else {
+                Err("invalid_amount_of_signatures")
+            };
+            assert_eq!(Entry::validate_signature(&masked), expected);
+        }
+
+        // a motion signature does not count in the entry round
+        let signature =
+            SpecialMeetingAttestGen::sign_motion(&TRAINER_SKS[3], 0, &malicious_pk).unwrap();
+        let args = reply(&TRAINER_SKS[3], &entry_args, signature);
+        assert!(!SpecialMeetingGen::collect(&mut meeting, "slash_trainer_entry", &args).unwrap());
+        assert_eq!(meeting.agg.as_ref().unwrap().mask.count_ones(), 3);
+
+        Consensus::invalidate_trainers_cache();
+    }
+}
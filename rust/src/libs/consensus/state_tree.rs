@@ -0,0 +1,598 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rocksdb::{Direction, IteratorMode, MultiThreaded, SnapshotWithThreadMode, TransactionDB};
+use serde::{Deserialize, Serialize};
+
+use crate::*;
+
+pub type Hash32 = [u8; 32];
+
+/// Hash of an empty subtree
+pub const EMPTY_HASH: Hash32 = [0u8; 32];
+
+/// Keys of `contractstate` rebuilt per transaction when the tree is first built
+const REBUILD_BATCH: usize = 10_000;
+
+/// A node of the tree. A subtree holding a single key is stored as that leaf,
+/// as high up as it can go, so paths are only as deep as keys share prefixes.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Leaf { path: Hash32, value_hash: Hash32 },
+    Internal { left: Hash32, right: Hash32 },
+}
+
+impl Node {
+    fn hash(&self) -> Hash32 {
+        match self {
+            Node::Leaf { path, value_hash } => StateTree::leaf_hash(path, value_hash),
+            Node::Internal { left, right } => StateTree::internal_hash(left, right),
+        }
+    }
+
+    fn pack(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf { path, value_hash } => [&[0u8][..], path, value_hash].concat(),
+            Node::Internal { left, right } => [&[1u8][..], left, right].concat(),
+        }
+    }
+
+    fn unpack(bin: &[u8]) -> Option<Self> {
+        if bin.len() != 65 {
+            return None;
+        }
+        let a: Hash32 = bin[1..33].try_into().ok()?;
+        let b: Hash32 = bin[33..65].try_into().ok()?;
+        match bin[0] {
+            0 => Some(Node::Leaf {
+                path: a,
+                value_hash: b,
+            }),
+            1 => Some(Node::Internal { left: a, right: b }),
+            _ => None,
+        }
+    }
+}
+
+/// Where tree nodes are read from, a transaction or a DB snapshot
+trait NodeRead {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error>;
+}
+
+trait NodeStore: NodeRead {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), rocksdb::Error>;
+    fn delete(&mut self, key: &[u8]) -> Result<(), rocksdb::Error>;
+}
+
+struct TxNodes<'a>(&'a RocksTx);
+
+impl NodeRead for TxNodes<'_> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        self.0.get_cf(&Fabric::cf_handle("state_tree"), key)
+    }
+}
+
+impl NodeStore for TxNodes<'_> {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), rocksdb::Error> {
+        self.0.put_cf(&Fabric::cf_handle("state_tree"), key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), rocksdb::Error> {
+        self.0.delete_cf(&Fabric::cf_handle("state_tree"), key)
+    }
+}
+
+struct SnapshotNodes<'a, 'b>(&'a SnapshotWithThreadMode<'b, TransactionDB<MultiThreaded>>);
+
+impl NodeRead for SnapshotNodes<'_, '_> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        self.0.get_cf(&Fabric::cf_handle("state_tree"), key)
+    }
+}
+
+impl NodeRead for HashMap<Vec<u8>, Vec<u8>> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        Ok(HashMap::get(self, key).cloned())
+    }
+}
+
+impl NodeStore for HashMap<Vec<u8>, Vec<u8>> {
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), rocksdb::Error> {
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), rocksdb::Error> {
+        self.remove(key);
+        Ok(())
+    }
+}
+
+/// Inclusion (`value` is Some) or exclusion proof of one `contractstate` key.
+/// `siblings` run from the root down to the slot the key's path ends in, and
+/// `leaf` is whatever sits in that slot (the key itself, another key or nothing).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateProof {
+    pub entry_hash: Vec<u8>,
+    pub root: Hash32,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub leaf: Option<(Hash32, Hash32)>,
+    pub siblings: Vec<Hash32>,
+}
+
+impl StateProof {
+    /// Checks the proof against a root the caller trusts, not the one it carries
+    pub fn verify(&self, root: &Hash32) -> bool {
+        let path = StateTree::path(&self.key);
+        let depth = self.siblings.len();
+        if depth > 256 {
+            return false;
+        }
+
+        let leaf_ok = match (&self.value, &self.leaf) {
+            (Some(value), Some((leaf_path, value_hash))) => {
+                *leaf_path == path && *value_hash == StateTree::value_hash(value)
+            }
+            // another key in our slot proves ours is absent, if the slot is really ours
+            (None, Some((leaf_path, _))) => {
+                *leaf_path != path
+                    && StateTree::prefix(leaf_path, depth) == StateTree::prefix(&path, depth)
+            }
+            (None, None) => true,
+            (Some(_), None) => false,
+        };
+        if !leaf_ok {
+            return false;
+        }
+
+        let mut hash = match &self.leaf {
+            Some((leaf_path, value_hash)) => StateTree::leaf_hash(leaf_path, value_hash),
+            None => EMPTY_HASH,
+        };
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            hash = match StateTree::bit(&path, depth) {
+                0 => StateTree::internal_hash(&hash, sibling),
+                _ => StateTree::internal_hash(sibling, &hash),
+            };
+        }
+        hash == *root
+    }
+}
+
+/// Sparse Merkle tree over `contractstate`, keyed by blake3(key).
+///
+/// - leaf: blake3(0x00 ++ blake3(key) ++ blake3(value))
+/// - internal: blake3(0x01 ++ left ++ right), bit 0 of the path goes left
+/// - empty subtree: 32 zero bytes
+///
+/// Nodes live in the `state_tree` CF under depth (u16 BE) ++ path prefix,
+/// and the root after each applied entry in `state_root` under the entry hash.
+pub struct StateTree;
+
+impl StateTree {
+    pub fn path(key: &[u8]) -> Hash32 {
+        *blake3::hash(key).as_bytes()
+    }
+
+    pub fn value_hash(value: &[u8]) -> Hash32 {
+        *blake3::hash(value).as_bytes()
+    }
+
+    pub fn leaf_hash(path: &Hash32, value_hash: &Hash32) -> Hash32 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[0]);
+        hasher.update(path);
+        hasher.update(value_hash);
+        *hasher.finalize().as_bytes()
+    }
+
+    pub fn internal_hash(left: &Hash32, right: &Hash32) -> Hash32 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[1]);
+        hasher.update(left);
+        hasher.update(right);
+        *hasher.finalize().as_bytes()
+    }
+
+    fn bit(path: &Hash32, depth: usize) -> u8 {
+        (path[depth / 8] >> (7 - depth % 8)) & 1
+    }
+
+    /// First `depth` bits of the path, the rest zeroed
+    fn prefix(path: &Hash32, depth: usize) -> Vec<u8> {
+        let mut prefix = path[..depth.div_ceil(8)].to_vec();
+        if depth % 8 != 0 {
+            *prefix.last_mut().unwrap() &= 0xFFu8 << (8 - depth % 8);
+        }
+        prefix
+    }
+
+    fn node_key(depth: usize, path: &Hash32) -> Vec<u8> {
+        [&(depth as u16).to_be_bytes()[..], &Self::prefix(path, depth)].concat()
+    }
+
+    fn flip(path: &Hash32, depth: usize) -> Hash32 {
+        let mut flipped = *path;
+        flipped[depth / 8] ^= 1 << (7 - depth % 8);
+        flipped
+    }
+
+    fn read(
+        store: &impl NodeRead,
+        depth: usize,
+        path: &Hash32,
+    ) -> Result<Option<Node>, rocksdb::Error> {
+        Ok(store
+            .get(&Self::node_key(depth, path))?
+            .and_then(|bin| Node::unpack(&bin)))
+    }
+
+    fn write(
+        store: &mut impl NodeStore,
+        depth: usize,
+        path: &Hash32,
+        node: &Node,
+    ) -> Result<(), rocksdb::Error> {
+        store.put(Self::node_key(depth, path), node.pack())
+    }
+
+    fn root_of(store: &impl NodeRead) -> Result<Hash32, rocksdb::Error> {
+        Ok(Self::read(store, 0, &EMPTY_HASH)?.map_or(EMPTY_HASH, |node| node.hash()))
+    }
+
+    fn set(
+        store: &mut impl NodeStore,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<(), rocksdb::Error> {
+        let path = Self::path(key);
+        match value {
+            Some(value) => Self::insert(store, 0, &path, &Self::value_hash(value)).map(|_| ()),
+            None => Self::remove(store, 0, &path).map(|_| ()),
+        }
+    }
+
+    fn insert(
+        store: &mut impl NodeStore,
+        depth: usize,
+        path: &Hash32,
+        value_hash: &Hash32,
+    ) -> Result<Hash32, rocksdb::Error> {
+        let leaf = Node::Leaf {
+            path: *path,
+            value_hash: *value_hash,
+        };
+        let node = match Self::read(store, depth, path)? {
+            None => leaf,
+            Some(Node::Leaf { path: other, .. }) if other == *path => leaf,
+            Some(Node::Leaf {
+                path: other,
+                value_hash: other_value_hash,
+            }) => {
+                let other = Node::Leaf {
+                    path: other,
+                    value_hash: other_value_hash,
+                };
+                return Self::split(store, depth, other, leaf);
+            }
+            Some(Node::Internal { left, right }) => {
+                let child = Self::insert(store, depth + 1, path, value_hash)?;
+                match Self::bit(path, depth) {
+                    0 => Node::Internal { left: child, right },
+                    _ => Node::Internal { left, right: child },
+                }
+            }
+        };
+        Self::write(store, depth, path, &node)?;
+        Ok(node.hash())
+    }
+
+    /// Pushes two leaves sharing the slot at `depth` down until their paths differ
+    fn split(
+        store: &mut impl NodeStore,
+        depth: usize,
+        a: Node,
+        b: Node,
+    ) -> Result<Hash32, rocksdb::Error> {
+        let (Node::Leaf { path: path_a, .. }, Node::Leaf { path: path_b, .. }) = (&a, &b) else {
+            unreachable!("split is only called with leaves");
+        };
+        let (path_a, path_b) = (*path_a, *path_b);
+
+        let (bit_a, bit_b) = (Self::bit(&path_a, depth), Self::bit(&path_b, depth));
+        let node = if bit_a == bit_b {
+            let child = Self::split(store, depth + 1, a, b)?;
+            match bit_a {
+                0 => Node::Internal {
+                    left: child,
+                    right: EMPTY_HASH,
+                },
+                _ => Node::Internal {
+                    left: EMPTY_HASH,
+                    right: child,
+                },
+            }
+        } else {
+            Self::write(store, depth + 1, &path_a, &a)?;
+            Self::write(store, depth + 1, &path_b, &b)?;
+            match bit_a {
+                0 => Node::Internal {
+                    left: a.hash(),
+                    right: b.hash(),
+                },
+                _ => Node::Internal {
+                    left: b.hash(),
+                    right: a.hash(),
+                },
+            }
+        };
+        Self::write(store, depth, &path_a, &node)?;
+        Ok(node.hash())
+    }
+
+    /// Removes `path` below `depth` and returns the node left in this slot.
+    /// A leaf left alone next to an empty sibling moves up into its parent's slot.
+    fn remove(
+        store: &mut impl NodeStore,
+        depth: usize,
+        path: &Hash32,
+    ) -> Result<Option<Node>, rocksdb::Error> {
+        let (left, right) = match Self::read(store, depth, path)? {
+            None => return Ok(None),
+            Some(Node::Leaf { path: other, .. }) if other == *path => {
+                store.delete(&Self::node_key(depth, path))?;
+                return Ok(None);
+            }
+            Some(leaf @ Node::Leaf { .. }) => return Ok(Some(leaf)),
+            Some(Node::Internal { left, right }) => (left, right),
+        };
+
+        let bit = Self::bit(path, depth);
+        let sibling_hash = if bit == 0 { right } else { left };
+        let sibling_path = Self::flip(path, depth);
+
+        let node = match Self::remove(store, depth + 1, path)? {
+            None => match Self::read(store, depth + 1, &sibling_path)? {
+                Some(leaf @ Node::Leaf { .. }) => {
+                    store.delete(&Self::node_key(depth + 1, &sibling_path))?;
+                    Some(leaf)
+                }
+                Some(Node::Internal { .. }) => Some(match bit {
+                    0 => Node::Internal {
+                        left: EMPTY_HASH,
+                        right,
+                    },
+                    _ => Node::Internal {
+                        left,
+                        right: EMPTY_HASH,
+                    },
+                }),
+                None => None,
+            },
+            Some(leaf @ Node::Leaf { .. }) if sibling_hash == EMPTY_HASH => {
+                store.delete(&Self::node_key(depth + 1, path))?;
+                Some(leaf)
+            }
+            Some(child) => Some(match bit {
+                0 => Node::Internal {
+                    left: child.hash(),
+                    right,
+                },
+                _ => Node::Internal {
+                    left,
+                    right: child.hash(),
+                },
+            }),
+        };
+
+        match &node {
+            Some(node) => Self::write(store, depth, path, node)?,
+            None => store.delete(&Self::node_key(depth, path))?,
+        }
+        Ok(node)
+    }
+
+    fn prove_in(
+        store: &impl NodeRead,
+        key: &[u8],
+    ) -> Result<(Option<(Hash32, Hash32)>, Vec<Hash32>), rocksdb::Error> {
+        let path = Self::path(key);
+        let mut siblings = vec![];
+        loop {
+            match Self::read(store, siblings.len(), &path)? {
+                None => return Ok((None, siblings)),
+                Some(Node::Leaf { path, value_hash }) => {
+                    return Ok((Some((path, value_hash)), siblings));
+                }
+                Some(Node::Internal { left, right }) => {
+                    let sibling = match Self::bit(&path, siblings.len()) {
+                        0 => right,
+                        _ => left,
+                    };
+                    siblings.push(sibling);
+                }
+            }
+        }
+    }
+
+    /// Brings the tree in line with the current `contractstate` value of each key,
+    /// inside the entry transaction. Returns the new root.
+    pub fn update<'k>(
+        rtx: &RocksTx,
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) -> Result<Hash32, rocksdb::Error> {
+        let cf = Fabric::cf_handle("contractstate");
+        let mut store = TxNodes(rtx);
+
+        let keys: BTreeSet<&[u8]> = keys.into_iter().collect();
+        for key in keys {
+            let value = rtx.get_cf(&cf, key)?;
+            Self::set(&mut store, key, value.as_deref())?;
+        }
+        Self::root_of(&store)
+    }
+
+    pub fn root(rtx: &RocksTx) -> Result<Hash32, rocksdb::Error> {
+        Self::root_of(&TxNodes(rtx))
+    }
+
+    /// Root recorded when the entry was applied
+    pub fn root_for_entry(entry_hash: &[u8]) -> Option<Hash32> {
+        let cf = Fabric::cf_handle("state_root");
+        Fabric::db()
+            .get_cf(&cf, entry_hash)
+            .ok()??
+            .try_into()
+            .ok()
+    }
+
+    /// Proof for `key` against the state at the temporal tip, read from one snapshot
+    pub fn prove(key: &[u8]) -> Result<StateProof, rocksdb::Error> {
+        let db = Fabric::db();
+        let snapshot = db.snapshot();
+        let store = SnapshotNodes(&snapshot);
+
+        let (leaf, siblings) = Self::prove_in(&store, key)?;
+        Ok(StateProof {
+            entry_hash: snapshot
+                .get_cf(&Fabric::cf_handle("sysconf"), b"temporal_tip")?
+                .unwrap_or_default(),
+            root: Self::root_of(&store)?,
+            key: key.to_vec(),
+            value: snapshot.get_cf(&Fabric::cf_handle("contractstate"), key)?,
+            leaf,
+            siblings,
+        })
+    }
+
+    pub fn prove_balance(pk: &[u8], symbol: &str) -> Result<StateProof, rocksdb::Error> {
+        Self::prove(&Coin::balance_key(pk, symbol))
+    }
+
+    /// Builds the tree from scratch over all of `contractstate`, for databases
+    /// that predate it (or came from a snapshot). A no-op once it was built.
+    pub fn build_if_missing() -> Result<(), rocksdb::Error> {
+        let db = Fabric::db();
+        let sysconf = Fabric::cf_handle("sysconf");
+        if db.get_cf(&sysconf, b"state_tree_built")?.is_some() {
+            return Ok(());
+        }
+
+        // a build interrupted halfway leaves nodes behind, start over
+        let cf_tree = Fabric::cf_handle("state_tree");
+        let stale: Vec<Box<[u8]>> = db
+            .iterator_cf(&cf_tree, IteratorMode::Start)
+            .map_while(|item| item.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for chunk in stale.chunks(REBUILD_BATCH) {
+            let rtx = Fabric::transaction();
+            for key in chunk {
+                rtx.delete_cf(&cf_tree, key)?;
+            }
+            rtx.commit()?;
+        }
+
+        let cf_state = Fabric::cf_handle("contractstate");
+        let mut from: Vec<u8> = vec![];
+        loop {
+            let batch: Vec<(Box<[u8]>, Box<[u8]>)> = db
+                .iterator_cf(&cf_state, IteratorMode::From(&from, Direction::Forward))
+                .map_while(|item| item.ok())
+                .skip_while(|(key, _)| !from.is_empty() && **key == *from)
+                .take(REBUILD_BATCH)
+                .collect();
+
+            let rtx = Fabric::transaction();
+            let mut store = TxNodes(&rtx);
+            for (key, value) in &batch {
+                Self::set(&mut store, key, Some(value))?;
+            }
+            if batch.len() < REBUILD_BATCH {
+                rtx.put_cf(&sysconf, b"state_tree_built", b"1")?;
+                rtx.commit()?;
+                return Ok(());
+            }
+            rtx.commit()?;
+            from = batch.last().unwrap().0.to_vec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prove(store: &HashMap<Vec<u8>, Vec<u8>>, key: &[u8], value: Option<&[u8]>) -> StateProof {
+        let (leaf, siblings) = StateTree::prove_in(store, key).unwrap();
+        StateProof {
+            entry_hash: vec![],
+            root: StateTree::root_of(store).unwrap(),
+            key: key.to_vec(),
+            value: value.map(|value| value.to_vec()),
+            leaf,
+            siblings,
+        }
+    }
+
+    #[test]
+    fn test_root_is_order_independent() {
+        let keys: Vec<Vec<u8>> = (0..64).map(|i| format!("key:{}", i).into_bytes()).collect();
+
+        let mut forward = HashMap::new();
+        for key in &keys {
+            StateTree::set(&mut forward, key, Some(b"v".as_slice())).unwrap();
+        }
+        let mut backward = HashMap::new();
+        for key in keys.iter().rev() {
+            StateTree::set(&mut backward, key, Some(b"v".as_slice())).unwrap();
+        }
+        assert_ne!(StateTree::root_of(&forward).unwrap(), EMPTY_HASH);
+        assert_eq!(forward, backward);
+
+        // removing every key again leaves no node behind
+        for key in &keys {
+            StateTree::set(&mut forward, key, None).unwrap();
+        }
+        assert!(forward.is_empty());
+        assert_eq!(StateTree::root_of(&forward).unwrap(), EMPTY_HASH);
+    }
+
+    #[test]
+    fn test_remove_restores_previous_tree() {
+        let mut store = HashMap::new();
+        for i in 0..16 {
+            let key = format!("a:{}", i);
+            StateTree::set(&mut store, key.as_bytes(), Some(b"1".as_slice())).unwrap();
+        }
+        let before = store.clone();
+
+        StateTree::set(&mut store, b"extra", Some(b"2".as_slice())).unwrap();
+        assert_ne!(store, before);
+        StateTree::set(&mut store, b"extra", None).unwrap();
+        assert_eq!(store, before);
+    }
+
+    #[test]
+    fn test_proofs() {
+        let balance = Coin::balance_key(&[9u8; 48], "AMA");
+        let mut store = HashMap::new();
+        for i in 0..32 {
+            let key = Coin::balance_key(&[i as u8; 48], "AMA");
+            StateTree::set(&mut store, &key, Some(i.to_string().as_bytes())).unwrap();
+        }
+        let root = StateTree::root_of(&store).unwrap();
+
+        let inclusion = prove(&store, &balance, Some(b"9".as_slice()));
+        assert!(inclusion.verify(&root));
+
+        let forged = prove(&store, &balance, Some(b"1000".as_slice()));
+        assert!(!forged.verify(&root));
+
+        let missing = Coin::balance_key(&[200u8; 48], "AMA");
+        let exclusion = prove(&store, &missing, None);
+        assert!(exclusion.verify(&root));
+        assert!(!prove(&store, &balance, None).verify(&root));
+        assert!(!inclusion.verify(&EMPTY_HASH));
+    }
+}
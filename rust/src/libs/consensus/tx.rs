@@ -109,6 +109,11 @@ impl TX {
 
         Ok(txu)
     }
+
+    pub fn pack(txu: &Txu) -> Vec<u8> {
+        to_vec(txu).unwrap()
+    }
+
     pub fn validate(tx_packed: &[u8], is_special_meeting_block: bool) -> TxResult<Txu> {
         let tx_size = CONFIG.ama.tx_size as usize;
         // size check
@@ -295,61 +300,33 @@ impl TX {
     //     epoch_sol_valid && nonce_valid && has_balance
     // }
 
-    // // valid_pk(pk)
-    // pub fn valid_pk(env: &impl Env, pk: &str) -> bool {
-    //     pk == env.burn_address() || env.bls_validate_public_key(pk)
-    // }
+    pub fn valid_pk(pk: &[u8]) -> bool {
+        pk == Coin::BURN_ADDRESS.as_bytes() || BlsRs::validate_public_key(pk)
+    }
 
-    // // known_receivers(txu)
-    // pub fn known_receivers(env: &impl Env, txu: &Txu) -> Vec<String> {
-    //     let Some(tx) = &txu.tx else { return vec![]; };
-    //     let a = &tx.actions[0];
-    //     let c = a.contract.as_str();
-    //     let f = a.function.as_str();
-    //     let args = &a.args;
-
-    //     match (c, f) {
-    //         ("Coin", "transfer") => {
-    //             // Variants:
-    //             // [receiver, amount]
-    //             // ["AMA", receiver, amount]
-    //             // [receiver, amount, symbol]
-    //             if args.len() >= 2 {
-    //                 if args.len() == 2 {
-    //                     // [receiver, _amount]
-    //                     let receiver = String::from_utf8_lossy(&args[0]).to_string();
-    //                     if TX::valid_pk(env, &receiver) {
-    //                         return vec![receiver];
-    //                     }
-    //                 } else if args.len() == 3 {
-    //                     // either ["AMA", receiver, _amount] OR [receiver, _amount, _symbol]
-    //                     let first = String::from_utf8_lossy(&args[0]).to_string();
-    //                     if first == "AMA" {
-    //                         let receiver = String::from_utf8_lossy(&args[1]).to_string();
-    //                         if TX::valid_pk(env, &receiver) {
-    //                             return vec![receiver];
-    //                         }
-    //                     } else {
-    //                         let receiver = first;
-    //                         if TX::valid_pk(env, &receiver) {
-    //                             return vec![receiver];
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //             vec![]
-    //         }
-    //         ("Epoch", "slash_trainer") => {
-    //             // [_epoch, malicious_pk, _signature, _mask_size, _mask]
-    //             if args.len() >= 2 {
-    //                 let malicious_pk = String::from_utf8_lossy(&args[1]).to_string();
-    //                 if TX::valid_pk(env, &malicious_pk) {
-    //                     return vec![malicious_pk];
-    //                 }
-    //             }
-    //             vec![]
-    //         }
-    //         _ => vec![],
-    //     }
-    // }
+    /// Accounts other than the signer that a tx touches, used for the receiver index
+    pub fn known_receivers(txu: &Txu) -> Vec<Vec<u8>> {
+        let Some(action) = txu.tx.actions.first() else {
+            return vec![];
+        };
+        let args = &action.args;
+
+        let receiver = match (action.contract.as_str(), action.function.as_str()) {
+            // [receiver, amount] | ["AMA", receiver, amount] | [receiver, amount, symbol]
+            ("Coin", "transfer") => match args.len() {
+                2 => Some(&args[0]),
+                3 if args[0] == b"AMA" => Some(&args[1]),
+                3 => Some(&args[0]),
+                _ => None,
+            },
+            // [epoch, malicious_pk, signature, mask_size, mask]
+            ("Epoch", "slash_trainer") if args.len() >= 2 => Some(&args[1]),
+            _ => None,
+        };
+
+        match receiver {
+            Some(receiver) if Self::valid_pk(receiver) => vec![receiver.clone()],
+            _ => vec![],
+        }
+    }
 }
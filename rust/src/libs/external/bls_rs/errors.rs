@@ -6,4 +6,6 @@ pub enum CryptoError {
     ZeroSizedInput,
     InvalidSeed,
     VerificationFailed,
+    /// Signer is not part of the trainer set being aggregated
+    SignerNotTrainer,
 }
\ No newline at end of file
@@ -1,3 +1,8 @@
+pub mod mod1;
+pub mod module_cache;
+pub use mod1::*;
+pub use module_cache::*;
+
 // use std::sync::Arc;
 
 // use crate::MapEnv;
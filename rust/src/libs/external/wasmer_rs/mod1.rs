@@ -1,12 +1,13 @@
 use wasmer::{
-    AsStoreMut, Engine, Function, FunctionEnv, FunctionEnvMut, FunctionType, Global, Instance,
-    Memory, MemoryType, MemoryView, Module, Pages, RuntimeError, Store, Type, Value, imports,
+    AsStoreMut, Engine, ExternType, Function, FunctionEnv, FunctionEnvMut, FunctionType, Global,
+    Instance, Memory, MemoryType, MemoryView, Module, Pages, RuntimeError, Store, Type, Value,
+    imports,
     sys::{EngineBuilder, Features},
-    wasmparser::Operator,
+    wasmparser::{Parser, Payload},
 };
 use wasmer_compiler_singlepass::Singlepass;
 
-use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::{Arc, Mutex};
 use wasmer_middlewares::{
     Metering,
     metering::{MeteringPoints, get_remaining_points, set_remaining_points},
@@ -14,29 +15,85 @@ use wasmer_middlewares::{
 
 use std::collections::HashMap;
 
-use sha2::{Digest, Sha256};
-static MODULE_CACHE: OnceLock<Mutex<HashMap<[u8; 32], (Arc<Engine>, Arc<Module>)>>> =
-    OnceLock::new();
-
 use rand::random;
+
+use crate::{GasSchedule, MapEnv, ModuleCache, WasmMessage};
+
+/// Host imports a contract may link against, anything else fails deploy
+pub const ALLOWED_IMPORTS: &[&str] = &[
+    "memory",
+    "seed_ptr",
+    "entry_signer_ptr",
+    "entry_prev_hash_ptr",
+    "entry_slot",
+    "entry_prev_slot",
+    "entry_height",
+    "entry_epoch",
+    "entry_vr_ptr",
+    "entry_dr_ptr",
+    "tx_signer_ptr",
+    "tx_nonce",
+    "account_current_ptr",
+    "account_caller_ptr",
+    "account_origin_ptr",
+    "attached_symbol_ptr",
+    "attached_amount_ptr",
+    "abort",
+    "import_log",
+    "import_attach",
+    "import_return_value",
+    "import_storage_kv_get",
+    "import_storage_kv_exists",
+    "import_storage_kv_get_prev",
+    "import_storage_kv_get_next",
+    "import_storage_kv_put",
+    "import_storage_kv_increment",
+    "import_storage_kv_delete",
+    "import_storage_kv_clear",
+    "import_call_0",
+    "import_call_1",
+    "import_call_2",
+    "import_call_3",
+    "import_call_4",
+];
+
+/// Globals holding a pointer to a `len ++ bytes` env record, in the order they are written
+pub const ENV_PTR_GLOBALS: &[&str] = &[
+    "seed_ptr",
+    "entry_signer_ptr",
+    "entry_prev_hash_ptr",
+    "entry_vr_ptr",
+    "entry_dr_ptr",
+    "tx_signer_ptr",
+    "account_current_ptr",
+    "account_caller_ptr",
+    "account_origin_ptr",
+    "attached_symbol_ptr",
+    "attached_amount_ptr",
+];
+
+/// 64 KiB pages, 2 MiB in total
+pub const MAX_MEMORY_PAGES: u32 = 32;
+/// Size the host memory starts at, contracts grow it up to MAX_MEMORY_PAGES
+pub const INITIAL_MEMORY_PAGES: u32 = 8;
 use std::sync::{LazyLock, mpsc};
-static REQ_REGISTRY_STORAGE_KV_GET: LazyLock<Mutex<HashMap<u64, mpsc::Sender<Option<Vec<u8>>>>>> =
+type Registry<T> = LazyLock<Mutex<HashMap<u64, mpsc::Sender<Result<T, String>>>>>;
+
+static REQ_REGISTRY_STORAGE_KV_GET: Registry<Option<Vec<u8>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
-static REQ_REGISTRY_STORAGE_KV_EXISTS: LazyLock<Mutex<HashMap<u64, mpsc::Sender<bool>>>> =
+static REQ_REGISTRY_STORAGE_KV_EXISTS: Registry<bool> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
-static REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT: LazyLock<
-    Mutex<HashMap<u64, mpsc::Sender<(Option<Vec<u8>>, Option<Vec<u8>>)>>>,
-> = LazyLock::new(|| Mutex::new(HashMap::new()));
-
-static REQ_REGISTRY_STORAGE: LazyLock<Mutex<HashMap<u64, mpsc::Sender<Vec<u8>>>>> =
+static REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT: Registry<(Option<Vec<u8>>, Option<Vec<u8>>)> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-static REQ_REGISTRY_CALL: LazyLock<
-    Mutex<HashMap<u64, mpsc::Sender<(Vec<u8>, Vec<Vec<u8>>, u64, Option<Vec<u8>>)>>>,
-> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static REQ_REGISTRY_STORAGE: Registry<Vec<u8>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// (error, logs, exec remaining, return value) of a nested call
+static REQ_REGISTRY_CALL: Registry<(Vec<u8>, Vec<Vec<u8>>, u64, Option<Vec<u8>>)> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 // Host environment for WASM execution
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct HostEnv {
     memory: Option<Memory>,
     readonly: bool,
@@ -47,80 +104,82 @@ struct HostEnv {
     attached_symbol: Vec<u8>,
     attached_amount: Vec<u8>,
     instance: Option<Arc<Instance>>,
+    rpc: Option<mpsc::Sender<WasmMessage>>,
+    gas: GasSchedule,
 }
 
 pub struct WasmerRs;
 
 impl WasmerRs {
-    // Example function: respond to KV get without Rustler
-    fn respond_to_storage_kv_get(request_id: u64, response: Option<Vec<u8>>) -> Result<(), String> {
-        let mut map = REQ_REGISTRY_STORAGE_KV_GET.lock().unwrap();
-
-        if let Some(tx) = map.remove(&request_id) {
-            tx.send(response).map_err(|_| "send failed".to_string())
-        } else {
-            Err("no request found".to_string())
-        }
+    pub fn respond_to_storage_kv_get(
+        request_id: u64,
+        response: Result<Option<Vec<u8>>, String>,
+    ) -> Result<(), String> {
+        Self::respond(&REQ_REGISTRY_STORAGE_KV_GET, request_id, response)
     }
 
-    fn respond_to_rust_storage_kv_exists(request_id: u64, response: bool) -> Result<(), String> {
-        let mut map = REQ_REGISTRY_STORAGE_KV_EXISTS.lock().unwrap();
-
-        if let Some(tx) = map.remove(&request_id) {
-            tx.send(response).map_err(|_| "send failed".to_string())
-        } else {
-            Err("no request found".to_string())
-        }
+    pub fn respond_to_rust_storage_kv_exists(
+        request_id: u64,
+        response: Result<bool, String>,
+    ) -> Result<(), String> {
+        Self::respond(&REQ_REGISTRY_STORAGE_KV_EXISTS, request_id, response)
     }
 
-    fn respond_to_rust_storage_kv_get_prev_next(
+    pub fn respond_to_rust_storage_kv_get_prev_next(
         request_id: u64,
-        response: (Option<Vec<u8>>, Option<Vec<u8>>),
+        response: Result<(Option<Vec<u8>>, Option<Vec<u8>>), String>,
     ) -> Result<(), String> {
-        let mut map = REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT.lock().unwrap();
-
-        if let Some(tx) = map.remove(&request_id) {
-            tx.send(response).map_err(|_| "send failed".to_string())
-        } else {
-            Err("no request found".to_string())
-        }
+        Self::respond(&REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT, request_id, response)
     }
 
-    fn respond_to_rust_storage(request_id: u64, response: Vec<u8>) -> Result<(), String> {
-        let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-
-        if let Some(tx) = map.remove(&request_id) {
-            tx.send(response).map_err(|_| "send failed".to_string())
-        } else {
-            Err("no request found".to_string())
-        }
+    pub fn respond_to_rust_storage(
+        request_id: u64,
+        response: Result<Vec<u8>, String>,
+    ) -> Result<(), String> {
+        Self::respond(&REQ_REGISTRY_STORAGE, request_id, response)
     }
 
-    fn respond_to_rust_call(
+    pub fn respond_to_rust_call(
         request_id: u64,
         main_error: Vec<u8>,
         logs: Vec<Vec<u8>>,
-        exec_cost: u64,
+        exec_remaining: u64,
         result: Option<Vec<u8>>,
     ) -> Result<(), String> {
-        let mut map = REQ_REGISTRY_CALL.lock().unwrap();
+        let response = Ok((main_error, logs, exec_remaining, result));
+        Self::respond(&REQ_REGISTRY_CALL, request_id, response)
+    }
+
+    fn respond<T>(
+        registry: &Mutex<HashMap<u64, mpsc::Sender<Result<T, String>>>>,
+        request_id: u64,
+        response: Result<T, String>,
+    ) -> Result<(), String> {
+        let mut map = registry.lock().unwrap();
 
         if let Some(tx) = map.remove(&request_id) {
-            tx.send((main_error, logs, exec_cost, result))
-                .map_err(|_| "send failed".to_string())
+            tx.send(response).map_err(|_| "send failed".to_string())
         } else {
             Err("no request found".to_string())
         }
     }
 
-    #[inline]
-    fn charge_points(remaining: u64, cost: u64) -> Result<u64, String> {
-        if cost > remaining {
-            return Err("Insufficient points".to_string());
+    /// Host calls are metered on top of the instructions, running out zeroes the meter
+    fn charge_points(
+        store: &mut impl AsStoreMut,
+        instance: &Instance,
+        cost: u64,
+    ) -> Result<u64, RuntimeError> {
+        match get_remaining_points(store, instance) {
+            MeteringPoints::Remaining(remaining) if remaining >= cost => {
+                set_remaining_points(store, instance, remaining - cost);
+                Ok(remaining - cost)
+            }
+            _ => {
+                set_remaining_points(store, instance, 0);
+                Err(RuntimeError::new("insufficient_exec_points"))
+            }
         }
-
-        let new_remaining = remaining - cost;
-        Ok(new_remaining)
     }
 
     fn build_prefixed_key(
@@ -224,9 +283,8 @@ impl WasmerRs {
         ptr: i32,
         len: i32,
     ) -> Result<(), RuntimeError> {
-        let cost = (len as u64) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.log(Self::byte_len(&[len])?);
 
         let instance_arc = data
             .instance
@@ -257,9 +315,8 @@ impl WasmerRs {
         amount_ptr: i32,
         amount_len: i32,
     ) -> Result<(), RuntimeError> {
-        let cost = 1000 + (symbol_len as u64) * 100 + (amount_len as u64) * 100;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.attach(Self::byte_len(&[symbol_len, amount_len])?);
 
         let instance_arc = data
             .instance
@@ -291,9 +348,8 @@ impl WasmerRs {
         ptr: i32,
         len: i32,
     ) -> Result<(), RuntimeError> {
-        let cost = (len as u64) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.return_value(Self::byte_len(&[len])?);
 
         let instance_arc = data
             .instance
@@ -317,387 +373,216 @@ impl WasmerRs {
         }
     }
 
-    //MOVE THESE OUT TO SEPERATA FILES
-    //KVGET
-    fn request_from_rust_storage_kv_get(
-        reply_to_pid: LocalPid,
-        key: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<Option<Vec<u8>>>, u64) {
-        let (tx, rx) = mpsc::channel::<Option<Vec<u8>>>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE_KV_GET.lock().unwrap();
-            map.insert(request_id, tx);
+    /// Sends a host request to the node thread that owns the entry transaction
+    /// and blocks until it answers on the registry channel. There is no timeout,
+    /// a nested call may run as long as its exec budget allows.
+    fn request<T>(
+        registry: &Mutex<HashMap<u64, mpsc::Sender<Result<T, String>>>>,
+        rpc: &Option<mpsc::Sender<WasmMessage>>,
+        build: impl FnOnce(u64) -> WasmMessage,
+    ) -> Result<T, RuntimeError> {
+        let rpc = rpc.as_ref().ok_or_else(|| RuntimeError::new("no_rpc"))?;
+        let (tx, rx) = mpsc::channel::<Result<T, String>>();
+        let request_id = random::<u64>();
+        registry.lock().unwrap().insert(request_id, tx);
+
+        if rpc.send(build(request_id)).is_ok() {
+            if let Ok(response) = rx.recv() {
+                return response.map_err(RuntimeError::new);
+            }
         }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let payload = (
-                    atoms::rust_request_storage_kv_get(),
-                    request_id,
-                    Binary::from_owned(owned_key, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
+        registry.lock().unwrap().remove(&request_id);
+        Err(RuntimeError::new("no_rpc_callback"))
     }
-    fn import_storage_kv_get_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
-        key_ptr: i32,
-        key_len: i32,
-    ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64)) * 100;
-
-        let (data, mut store) = env.data_and_store_mut();
-
-        let instance_arc = data
-            .instance
-            .as_ref()
-            .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
-
-        let Some(memory) = &data.memory else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let view: MemoryView = memory.view(&store);
 
-        let key_buffer = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
-
-        //let mut key_buffer_suffix = vec![0u8; key_len as usize];
-        //let Ok(_) = view.read(key_ptr as u64, &mut key_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-        //let mut key_buffer = data.current_account.clone();
-        //key_buffer.extend_from_slice(b":");
-        //key_buffer.extend_from_slice(&key_buffer_suffix);
+    /// Total of the guest supplied lengths an import is priced on, checked before
+    /// anything is charged as a negative i32 would sign extend to a huge u64
+    fn byte_len(lens: &[i32]) -> Result<u64, RuntimeError> {
+        lens.iter().try_fold(0u64, |total, &len| {
+            let len = u64::try_from(len).map_err(|_| RuntimeError::new("invalid_memory"))?;
+            total
+                .checked_add(len)
+                .ok_or_else(|| RuntimeError::new("invalid_memory"))
+        })
+    }
 
-        let (rx, request_id) = Self::request_from_rust_storage_kv_get(data.rpc_pid, key_buffer);
+    fn read_bin(view: &MemoryView, ptr: i32, len: i32) -> Result<Vec<u8>, RuntimeError> {
+        let mut buffer = vec![0u8; len.max(0) as usize];
+        view.read(ptr as u64, &mut buffer)
+            .map_err(|_| RuntimeError::new("invalid_memory"))?;
+        Ok(buffer)
+    }
 
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(option_response) => match option_response {
-                Some(response) => {
-                    Self::write_i32(&view, 30_000, response.len() as i32)?;
-                    Self::write_bin(&view, 30_004, &response)?;
-                    Ok(30_000)
-                }
-                None => {
-                    Self::write_i32(&view, 30_000, -1)?;
-                    Ok(30_000)
-                }
-            },
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE_KV_GET.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
+    /// Writes `len ++ bytes` at the shared response offset, -1 for a missing value
+    fn write_response(view: &MemoryView, response: Option<&[u8]>) -> Result<i32, RuntimeError> {
+        match response {
+            Some(bytes) => {
+                Self::write_i32(view, 30_000, bytes.len() as i32)?;
+                Self::write_bin(view, 30_004, bytes)?;
             }
+            None => Self::write_i32(view, 30_000, -1)?,
         }
+        Ok(30_000)
     }
 
-    ///EXISTS
-    fn request_from_rust_storage_kv_exists(
-        reply_to_pid: LocalPid,
-        key: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<bool>, u64) {
-        let (tx, rx) = mpsc::channel::<bool>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE_KV_EXISTS.lock().unwrap();
-            map.insert(request_id, tx);
+    /// Writes `key_len ++ key ++ value_len ++ value`, -1 when nothing was found
+    fn write_key_value(
+        view: &MemoryView,
+        response: (Option<Vec<u8>>, Option<Vec<u8>>),
+    ) -> Result<i32, RuntimeError> {
+        match response {
+            (Some(key), Some(value)) => {
+                let value_offset = 30_004 + key.len() as u64;
+                Self::write_i32(view, 30_000, key.len() as i32)?;
+                Self::write_bin(view, 30_004, &key)?;
+                Self::write_i32(view, value_offset, value.len() as i32)?;
+                Self::write_bin(view, value_offset + 4, &value)?;
+            }
+            _ => Self::write_i32(view, 30_000, -1)?,
         }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let payload = (
-                    atoms::rust_request_storage_kv_exists(),
-                    request_id,
-                    Binary::from_owned(owned_key, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
+        Ok(30_000)
     }
-    fn import_storage_kv_exists_implementation(
+
+    //KVGET
+    fn import_storage_kv_get_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         key_ptr: i32,
         key_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64)) * 100;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_read(Self::byte_len(&[key_len])?);
 
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
-        let view: MemoryView = memory.view(&store);
-
-        let key_buffer = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
-        /*
-            let mut key_buffer_suffix = vec![0u8; key_len as usize];
-            let Ok(_) = view.read(key_ptr as u64, &mut key_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut key_buffer = data.current_account.clone();
-            key_buffer.extend_from_slice(b":");
-            key_buffer.extend_from_slice(&key_buffer_suffix);
-        */
-        let (rx, request_id) = Self::request_from_rust_storage_kv_exists(data.rpc_pid, key_buffer);
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(true) => {
-                Self::write_i32(&view, 30_000, 1)?;
-                Ok(30_000)
-            }
-            Ok(false) => {
-                Self::write_i32(&view, 30_000, 0)?;
-                Ok(30_000)
-            }
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE_KV_EXISTS.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let key = Self::build_prefixed_key(
+            &memory.view(&store),
+            &data.current_account,
+            key_ptr,
+            key_len,
+        )?;
+        let response = Self::request(&REQ_REGISTRY_STORAGE_KV_GET, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvGet { rpc_id, key }
+        })?;
+
+        let returned = response.as_ref().map_or(0, |value| value.len() as u64);
+        let cost = data.gas.storage_read_returned(returned);
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::write_response(&memory.view(&store), response.as_deref())
     }
 
-    ///PREV
-    fn request_from_rust_storage_kv_get_prev(
-        reply_to_pid: LocalPid,
-        suffix: Vec<u8>,
-        key: Vec<u8>,
-    ) -> (
-        std::sync::mpsc::Receiver<(Option<Vec<u8>>, Option<Vec<u8>>)>,
-        u64,
-    ) {
-        let (tx, rx) = mpsc::channel::<(Option<Vec<u8>>, Option<Vec<u8>>)>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_suffix = OwnedBinary::new(suffix.len()).unwrap();
-                owned_suffix.as_mut_slice().copy_from_slice(&suffix);
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let payload = (
-                    atoms::rust_request_storage_kv_get_prev(),
-                    request_id,
-                    Binary::from_owned(owned_suffix, cenv),
-                    Binary::from_owned(owned_key, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
-    fn import_storage_kv_get_prev_implementation(
+    //EXISTS
+    fn import_storage_kv_exists_implementation(
         mut env: FunctionEnvMut<HostEnv>,
-        suffix_ptr: i32,
-        suffix_len: i32,
         key_ptr: i32,
         key_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64)) * 100;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_read(Self::byte_len(&[key_len])?);
 
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let suffix_buffer =
-            Self::build_prefixed_key(&view, &data.current_account, suffix_ptr, suffix_len)?;
-
-        /*
-            let mut suffix_buffer_suffix = vec![0u8; suffix_len as usize];
-            let Ok(_) = view.read(suffix_ptr as u64, &mut suffix_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut suffix_buffer = data.current_account.clone();
-            suffix_buffer.extend_from_slice(b":");
-            suffix_buffer.extend_from_slice(&suffix_buffer_suffix);
-        */
-
-        let mut key_buffer = vec![0u8; key_len as usize];
-        let Ok(_) = view.read(key_ptr as u64, &mut key_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let (rx, request_id) =
-            Self::request_from_rust_storage_kv_get_prev(data.rpc_pid, suffix_buffer, key_buffer);
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((maybe_prev_key, maybe_value)) => match (maybe_prev_key, maybe_value) {
-                (Some(prev_key), Some(value)) => {
-                    Self::write_i32(&view, 30_000, prev_key.len() as i32)?;
-                    Self::write_bin(&view, 30_004, &prev_key)?;
-
-                    Self::write_i32(&view, 30_004 + (prev_key.len() as u64), value.len() as i32)?;
-                    Self::write_bin(&view, 30_004 + (prev_key.len() as u64) + 4, &value)?;
-                    Ok(30_000)
-                }
-                _ => {
-                    Self::write_i32(&view, 30_000, -1)?;
-                    Ok(30_000)
-                }
-            },
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let key = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
+        let exists = Self::request(&REQ_REGISTRY_STORAGE_KV_EXISTS, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvExists { rpc_id, key }
+        })?;
+        Self::write_i32(&view, 30_000, exists as i32)?;
+        Ok(30_000)
     }
 
-    ///NEXT
-    fn request_from_rust_storage_kv_get_next(
-        reply_to_pid: LocalPid,
-        suffix: Vec<u8>,
-        key: Vec<u8>,
-    ) -> (
-        std::sync::mpsc::Receiver<(Option<Vec<u8>>, Option<Vec<u8>>)>,
-        u64,
-    ) {
-        let (tx, rx) = mpsc::channel::<(Option<Vec<u8>>, Option<Vec<u8>>)>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_suffix = OwnedBinary::new(suffix.len()).unwrap();
-                owned_suffix.as_mut_slice().copy_from_slice(&suffix);
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let payload = (
-                    atoms::rust_request_storage_kv_get_next(),
-                    request_id,
-                    Binary::from_owned(owned_suffix, cenv),
-                    Binary::from_owned(owned_key, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
+    //PREV and NEXT
+    fn import_storage_kv_get_prev_implementation(
+        env: FunctionEnvMut<HostEnv>,
+        prefix_ptr: i32,
+        prefix_len: i32,
+        key_ptr: i32,
+        key_len: i32,
+    ) -> Result<i32, RuntimeError> {
+        Self::storage_kv_get_adjacent(env, true, prefix_ptr, prefix_len, key_ptr, key_len)
     }
+
     fn import_storage_kv_get_next_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
-        suffix_ptr: i32,
-        suffix_len: i32,
+        env: FunctionEnvMut<HostEnv>,
+        prefix_ptr: i32,
+        prefix_len: i32,
         key_ptr: i32,
         key_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64)) * 100;
+        Self::storage_kv_get_adjacent(env, false, prefix_ptr, prefix_len, key_ptr, key_len)
+    }
 
+    fn storage_kv_get_adjacent(
+        mut env: FunctionEnvMut<HostEnv>,
+        prev: bool,
+        prefix_ptr: i32,
+        prefix_len: i32,
+        key_ptr: i32,
+        key_len: i32,
+    ) -> Result<i32, RuntimeError> {
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data
+            .gas
+            .storage_read(Self::byte_len(&[prefix_len, key_len])?);
 
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let suffix_buffer =
-            Self::build_prefixed_key(&view, &data.current_account, suffix_ptr, suffix_len)?;
-        /*
-            let mut suffix_buffer_suffix = vec![0u8; suffix_len as usize];
-            let Ok(_) = view.read(suffix_ptr as u64, &mut suffix_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut suffix_buffer = data.current_account.clone();
-            suffix_buffer.extend_from_slice(b":");
-            suffix_buffer.extend_from_slice(&suffix_buffer_suffix);
-        */
-        let mut key_buffer = vec![0u8; key_len as usize];
-        let Ok(_) = view.read(key_ptr as u64, &mut key_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let (rx, request_id) =
-            Self::request_from_rust_storage_kv_get_next(data.rpc_pid, suffix_buffer, key_buffer);
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((maybe_next_key, maybe_value)) => match (maybe_next_key, maybe_value) {
-                (Some(next_key), Some(value)) => {
-                    Self::write_i32(&view, 30_000, next_key.len() as i32)?;
-                    Self::write_bin(&view, 30_004, &next_key)?;
-                    Self::write_i32(&view, 30_004 + (next_key.len() as u64), value.len() as i32)?;
-                    Self::write_bin(&view, 30_004 + (next_key.len() as u64) + 4, &value)?;
-                    Ok(30_000)
-                }
-                _ => {
-                    Self::write_i32(&view, 30_000, -1)?;
-                    Ok(30_000)
+        let prefix =
+            Self::build_prefixed_key(&view, &data.current_account, prefix_ptr, prefix_len)?;
+        let key = Self::read_bin(&view, key_ptr, key_len)?;
+        let response = Self::request(
+            &REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT,
+            &data.rpc,
+            |rpc_id| {
+                if prev {
+                    WasmMessage::RustRequestStorageKvGetPrev {
+                        rpc_id,
+                        prefix,
+                        key,
+                    }
+                } else {
+                    WasmMessage::RustRequestStorageKvGetNext {
+                        rpc_id,
+                        prefix,
+                        key,
+                    }
                 }
             },
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE_KV_GET_PREV_NEXT.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        )?;
+
+        let returned = match &response {
+            (Some(key), Some(value)) => (key.len() + value.len()) as u64,
+            _ => 0,
+        };
+        let cost = data.gas.storage_read_returned(returned);
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::write_key_value(&memory.view(&store), response)
     }
 
     //PUT
-    fn request_from_rust_storage_kv_put<'a>(
-        reply_to_pid: LocalPid,
-        key: Vec<u8>,
-        val: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<Vec<u8>>, u64) {
-        let (tx, rx) = mpsc::channel::<Vec<u8>>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let mut owned_val = OwnedBinary::new(val.len()).unwrap();
-                owned_val.as_mut_slice().copy_from_slice(&val);
-                let payload = (
-                    atoms::rust_request_storage_kv_put(),
-                    request_id,
-                    Binary::from_owned(owned_key, cenv),
-                    Binary::from_owned(owned_val, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
     fn import_storage_kv_put_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         key_ptr: i32,
@@ -705,86 +590,32 @@ impl WasmerRs {
         val_ptr: i32,
         val_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64) + (val_len as u64)) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_write(Self::byte_len(&[key_len, val_len])?);
+
         if data.readonly {
             return Err(RuntimeError::new("read_only"));
         }
-
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let key_buffer = build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
-        /*
-           let mut key_buffer_suffix = vec![0u8; key_len as usize];
-           let Ok(_) = view.read(key_ptr as u64, &mut key_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-           let mut key_buffer = data.current_account.clone();
-           key_buffer.extend_from_slice(b":");
-           key_buffer.extend_from_slice(&key_buffer_suffix);
-        */
-        let mut val_buffer = vec![0u8; val_len as usize];
-        let Ok(_) = view.read(val_ptr as u64, &mut val_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let (rx, request_id) =
-            request_from_rust_storage_kv_put(data.rpc_pid, key_buffer, val_buffer);
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(response) => {
-                Self::write_i32(&view, 30_000, response.len() as i32)?;
-                write_bin(&view, 30_004, &response)?;
-                Ok(30_000)
-            }
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let key = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
+        let value = Self::read_bin(&view, val_ptr, val_len)?;
+        let response = Self::request(&REQ_REGISTRY_STORAGE, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvPut { rpc_id, key, value }
+        })?;
+        Self::write_response(&view, Some(&response))
     }
 
     //INCREMENT
-    fn request_from_rust_storage_kv_increment<'a>(
-        reply_to_pid: LocalPid,
-        key: Vec<u8>,
-        val: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<Vec<u8>>, u64) {
-        let (tx, rx) = mpsc::channel::<Vec<u8>>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let mut owned_val = OwnedBinary::new(val.len()).unwrap();
-                owned_val.as_mut_slice().copy_from_slice(&val);
-                let payload = (
-                    atoms::rust_request_storage_kv_increment(),
-                    request_id,
-                    Binary::from_owned(owned_key, cenv),
-                    Binary::from_owned(owned_val, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
     fn import_storage_kv_increment_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         key_ptr: i32,
@@ -792,275 +623,109 @@ impl WasmerRs {
         val_ptr: i32,
         val_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64) + (val_len as u64)) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_write(Self::byte_len(&[key_len, val_len])?);
+
         if data.readonly {
             return Err(RuntimeError::new("read_only"));
         }
-
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let key_buffer = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
-        /*
-            let mut key_buffer_suffix = vec![0u8; key_len as usize];
-            let Ok(_) = view.read(key_ptr as u64, &mut key_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut key_buffer = data.current_account.clone();
-            key_buffer.extend_from_slice(b":");
-            key_buffer.extend_from_slice(&key_buffer_suffix);
-        */
-        let mut val_buffer = vec![0u8; val_len as usize];
-        let Ok(_) = view.read(val_ptr as u64, &mut val_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let (rx, request_id) =
-            Self::request_from_rust_storage_kv_increment(data.rpc_pid, key_buffer, val_buffer);
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(response) => {
-                Self::write_i32(&view, 30_000, response.len() as i32)?;
-                Self::write_bin(&view, 30_004, &response)?;
-                Ok(30_000)
-            }
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let key = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
+        let value = Self::read_bin(&view, val_ptr, val_len)?;
+        let response = Self::request(&REQ_REGISTRY_STORAGE, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvIncrement { rpc_id, key, value }
+        })?;
+        Self::write_response(&view, Some(&response))
     }
 
     //DELETE
-    fn request_from_rust_storage_kv_delete<'a>(
-        reply_to_pid: LocalPid,
-        key: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<Vec<u8>>, u64) {
-        let (tx, rx) = mpsc::channel::<Vec<u8>>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_key = OwnedBinary::new(key.len()).unwrap();
-                owned_key.as_mut_slice().copy_from_slice(&key);
-                let payload = (
-                    atoms::rust_request_storage_kv_delete(),
-                    request_id,
-                    Binary::from_owned(owned_key, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
     fn import_storage_kv_delete_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         key_ptr: i32,
         key_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (key_len as u64)) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_write(Self::byte_len(&[key_len])?);
+
         if data.readonly {
             return Err(RuntimeError::new("read_only"));
         }
-
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let key_buffer = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
-        /*
-            let mut key_buffer_suffix = vec![0u8; key_len as usize];
-            let Ok(_) = view.read(key_ptr as u64, &mut key_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut key_buffer = data.current_account.clone();
-            key_buffer.extend_from_slice(b":");
-            key_buffer.extend_from_slice(&key_buffer_suffix);
-        */
-        let (rx, request_id) = Self::request_from_rust_storage_kv_delete(data.rpc_pid, key_buffer);
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(response) => {
-                Self::write_i32(&view, 30_000, response.len() as i32)?;
-                Self::write_bin(&view, 30_004, &response)?;
-                Ok(30_000)
-            }
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let key = Self::build_prefixed_key(&view, &data.current_account, key_ptr, key_len)?;
+        let response = Self::request(&REQ_REGISTRY_STORAGE, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvDelete { rpc_id, key }
+        })?;
+        Self::write_response(&view, Some(&response))
     }
 
     //CLEAR
-    fn request_from_rust_storage_kv_clear<'a>(
-        reply_to_pid: LocalPid,
-        prefix: Vec<u8>,
-    ) -> (std::sync::mpsc::Receiver<Vec<u8>>, u64) {
-        let (tx, rx) = mpsc::channel::<Vec<u8>>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_prefix = OwnedBinary::new(prefix.len()).unwrap();
-                owned_prefix.as_mut_slice().copy_from_slice(&prefix);
-                let payload = (
-                    atoms::rust_request_storage_kv_delete(),
-                    request_id,
-                    Binary::from_owned(owned_prefix, cenv),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
     fn import_storage_kv_clear_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         prefix_ptr: i32,
         prefix_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (prefix_len as u64)) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        let cost = data.gas.storage_write(Self::byte_len(&[prefix_len])?);
+
         if data.readonly {
             return Err(RuntimeError::new("read_only"));
         }
-
         let instance_arc = data
             .instance
             .as_ref()
             .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
+        Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
 
         let Some(memory) = &data.memory else {
             return Err(RuntimeError::new("invalid_memory"));
         };
         let view: MemoryView = memory.view(&store);
 
-        let prefix_buffer =
+        let prefix =
             Self::build_prefixed_key(&view, &data.current_account, prefix_ptr, prefix_len)?;
-        /*
-            let mut prefix_buffer_suffix = vec![0u8; prefix_len as usize];
-            let Ok(_) = view.read(prefix_ptr as u64, &mut prefix_buffer_suffix) else { return Err(RuntimeError::new("invalid_memory")) };
-            let mut prefix_buffer = data.current_account.clone();
-            prefix_buffer.extend_from_slice(b":");
-            prefix_buffer.extend_from_slice(&prefix_buffer_suffix);
-        */
-        let (rx, request_id) = Self::request_from_rust_storage_kv_clear(data.rpc_pid, prefix_buffer);
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok(response) => {
-                Self::write_i32(&view, 30_000, response.len() as i32)?;
-                Self::write_bin(&view, 30_004, &response)?;
-                Ok(30_000)
-            }
-            Err(_) => {
-                let mut map = REQ_REGISTRY_STORAGE.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        let response = Self::request(&REQ_REGISTRY_STORAGE, &data.rpc, |rpc_id| {
+            WasmMessage::RustRequestStorageKvClear { rpc_id, prefix }
+        })?;
+        Self::write_response(&view, Some(&response))
     }
 
     //CALL
-    fn request_from_rust_call<'a>(
-        reply_to_pid: LocalPid,
-        remaining_u64: u64,
-        module: Vec<u8>,
-        function: Vec<u8>,
-        args: Vec<Vec<u8>>,
-        attached_symbol: Vec<u8>,
-        attached_amount: Vec<u8>,
-    ) -> (
-        std::sync::mpsc::Receiver<(Vec<u8>, Vec<Vec<u8>>, u64, Option<Vec<u8>>)>,
-        u64,
-    ) {
-        let (tx, rx) = mpsc::channel::<(Vec<u8>, Vec<Vec<u8>>, u64, Option<Vec<u8>>)>();
-        let request_id = rand::random::<u64>();
-        {
-            let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-            map.insert(request_id, tx);
-        }
-
-        std::thread::spawn(move || {
-            let mut env = OwnedEnv::new();
-            let _ = env.send_and_clear(&reply_to_pid, |cenv| {
-                let mut owned_module = OwnedBinary::new(module.len()).unwrap();
-                owned_module.as_mut_slice().copy_from_slice(&module);
-                let mut owned_function = OwnedBinary::new(function.len()).unwrap();
-                owned_function.as_mut_slice().copy_from_slice(&function);
-                let encoded_args: Vec<Binary> = args
-                    .iter()
-                    .map(|bytes| {
-                        let mut bin = OwnedBinary::new(bytes.len()).unwrap();
-                        bin.as_mut_slice().copy_from_slice(bytes);
-                        Binary::from_owned(bin, cenv)
-                    })
-                    .collect();
-                let mut owned_attached_symbol = OwnedBinary::new(attached_symbol.len()).unwrap();
-                owned_attached_symbol
-                    .as_mut_slice()
-                    .copy_from_slice(&attached_symbol);
-                let mut owned_attached_amount = OwnedBinary::new(attached_amount.len()).unwrap();
-                owned_attached_amount
-                    .as_mut_slice()
-                    .copy_from_slice(&attached_amount);
-
-                let payload = (
-                    atoms::rust_request_call(),
-                    request_id,
-                    remaining_u64,
-                    Binary::from_owned(owned_module, cenv),
-                    Binary::from_owned(owned_function, cenv),
-                    encoded_args,
-                    (
-                        Binary::from_owned(owned_attached_symbol, cenv),
-                        Binary::from_owned(owned_attached_amount, cenv),
-                    ),
-                );
-                payload.encode(cenv)
-            });
-        });
-
-        (rx, request_id)
-    }
-    fn import_call_0_implementation(
+    /// Shared body of `import_call_0..4`. Any attachment set by `import_attach` is
+    /// consumed by this call. The callee runs on the node thread against the remaining
+    /// exec points, and its return value is written back at 30_000 as
+    /// `error_len ++ error ++ result_len ++ result`.
+    fn import_call_implementation(
         mut env: FunctionEnvMut<HostEnv>,
         module_ptr: i32,
         module_len: i32,
         function_ptr: i32,
         function_len: i32,
+        arg_ptrs: &[(i32, i32)],
     ) -> Result<i32, RuntimeError> {
-        let cost = (48) * 1000;
-
         let (data, mut store) = env.data_and_store_mut();
+        Self::byte_len(&[module_len, function_len])?;
+        let arg_lens: Vec<i32> = arg_ptrs.iter().map(|(_, len)| *len).collect();
+        let cost = data.gas.call(Self::byte_len(&arg_lens)?);
 
         let instance_arc = data
             .instance
@@ -1073,66 +738,79 @@ impl WasmerRs {
         };
         let view: MemoryView = memory.view(&store);
 
-        let mut module_buffer = vec![0u8; module_len as usize];
-        let Ok(_) = view.read(module_ptr as u64, &mut module_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut function_buffer = vec![0u8; function_len as usize];
-        let Ok(_) = view.read(function_ptr as u64, &mut function_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
+        let contract = Self::read_bin(&view, module_ptr, module_len)?;
+        let function = String::from_utf8(Self::read_bin(&view, function_ptr, function_len)?)
+            .map_err(|_| RuntimeError::new("invalid_function"))?;
+        let args = arg_ptrs
+            .iter()
+            .map(|(ptr, len)| Self::read_bin(&view, *ptr, *len))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let attached_symbol = std::mem::take(&mut data.attached_symbol);
+        let attached_amount = std::mem::take(&mut data.attached_amount);
+        let (attached_symbol, attached_amount) = if attached_symbol.is_empty() {
+            (None, None)
+        } else {
+            let symbol = String::from_utf8(attached_symbol)
+                .map_err(|_| RuntimeError::new("invalid_attached_symbol"))?;
+            let amount = std::str::from_utf8(&attached_amount)
+                .ok()
+                .and_then(|amount| amount.parse::<u64>().ok())
+                .ok_or_else(|| RuntimeError::new("invalid_attached_amount"))?;
+            (Some(symbol), Some(amount))
         };
+        if data.readonly && attached_symbol.is_some() {
+            return Err(RuntimeError::new("read_only"));
+        }
 
-        let mut args = Vec::with_capacity(0);
-
-        let (rx, request_id) = Self::request_from_rust_call(
-            data.rpc_pid,
-            remaining_u64,
-            module_buffer,
-            function_buffer,
-            args,
-            data.attached_symbol.clone(),
-            data.attached_amount.clone(),
-        );
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((error, logs, remaining_exec, result)) => {
-                if error != b"ok" {
-                    return Err(RuntimeError::new("xcc_failed"));
-                }
-
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                Self::write_i32(&view, 30_000, error.len() as i32)?;
-                Self::write_bin(&view, 30_004, &error)?;
-
-                match result {
-                    Some(bytes) => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), bytes.len() as i32)?;
-                        Self::write_bin(&view, 30_004 + (error.len() as u64) + 4, &bytes)?;
-                    }
-                    None => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), 0)?;
-                    }
+        let (error, logs, remaining_exec, result) =
+            Self::request(&REQ_REGISTRY_CALL, &data.rpc, |rpc_id| {
+                WasmMessage::RustRequestCall {
+                    rpc_id,
+                    exec_remaining: remaining_u64,
+                    contract,
+                    function,
+                    args,
+                    attached_symbol,
+                    attached_amount,
                 }
+            })?;
 
-                data.logs.extend(logs);
-                Self::set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
+        // the callee spent from the same budget
+        set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
+        data.logs.extend(logs);
+        if error != b"ok" {
+            return Err(RuntimeError::new("xcc_failed"));
+        }
 
-                Ok(30_000)
-            }
-            Err(_) => {
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
+        let result = result.unwrap_or_default();
+        let result_offset = 30_004 + error.len() as u64;
+        Self::write_i32(&view, 30_000, error.len() as i32)?;
+        Self::write_bin(&view, 30_004, &error)?;
+        Self::write_i32(&view, result_offset, result.len() as i32)?;
+        Self::write_bin(&view, result_offset + 4, &result)?;
+        Ok(30_000)
+    }
 
-                let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+    fn import_call_0_implementation(
+        env: FunctionEnvMut<HostEnv>,
+        module_ptr: i32,
+        module_len: i32,
+        function_ptr: i32,
+        function_len: i32,
+    ) -> Result<i32, RuntimeError> {
+        Self::import_call_implementation(
+            env,
+            module_ptr,
+            module_len,
+            function_ptr,
+            function_len,
+            &[],
+        )
     }
+
     fn import_call_1_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
+        env: FunctionEnvMut<HostEnv>,
         module_ptr: i32,
         module_len: i32,
         function_ptr: i32,
@@ -1140,87 +818,19 @@ impl WasmerRs {
         arg_1_ptr: i32,
         arg_1_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (arg_1_len as u64)) * 1000;
-
-        let (data, mut store) = env.data_and_store_mut();
-
-        let instance_arc = data
-            .instance
-            .as_ref()
-            .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
-
-        let Some(memory) = &data.memory else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let view: MemoryView = memory.view(&store);
-
-        let mut module_buffer = vec![0u8; module_len as usize];
-        let Ok(_) = view.read(module_ptr as u64, &mut module_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut function_buffer = vec![0u8; function_len as usize];
-        let Ok(_) = view.read(function_ptr as u64, &mut function_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut arg_1_buffer = vec![0u8; arg_1_len as usize];
-        let Ok(_) = view.read(arg_1_ptr as u64, &mut arg_1_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut args = Vec::with_capacity(1);
-        args.push(arg_1_buffer);
-
-        let (rx, request_id) = request_from_rust_call(
-            data.rpc_pid,
-            remaining_u64,
-            module_buffer,
-            function_buffer,
-            args,
-            data.attached_symbol.clone(),
-            data.attached_amount.clone(),
-        );
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((error, logs, remaining_exec, result)) => {
-                if error != b"ok" {
-                    return Err(RuntimeError::new("xcc_failed"));
-                }
-
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                Self::write_i32(&view, 30_000, error.len() as i32)?;
-                write_bin(&view, 30_004, &error)?;
-
-                match result {
-                    Some(bytes) => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), bytes.len() as i32)?;
-                        write_bin(&view, 30_004 + (error.len() as u64) + 4, &bytes)?;
-                    }
-                    None => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), 0)?;
-                    }
-                }
-
-                data.logs.extend(logs);
-                set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
-
-                Ok(30_000)
-            }
-            Err(_) => {
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        Self::import_call_implementation(
+            env,
+            module_ptr,
+            module_len,
+            function_ptr,
+            function_len,
+            &[(arg_1_ptr, arg_1_len)],
+        )
     }
+
+    #[allow(clippy::too_many_arguments)]
     fn import_call_2_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
+        env: FunctionEnvMut<HostEnv>,
         module_ptr: i32,
         module_len: i32,
         function_ptr: i32,
@@ -1230,92 +840,19 @@ impl WasmerRs {
         arg_2_ptr: i32,
         arg_2_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (arg_1_len as u64) + (arg_2_len as u64)) * 1000;
-
-        let (data, mut store) = env.data_and_store_mut();
-
-        let instance_arc = data
-            .instance
-            .as_ref()
-            .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
-
-        let Some(memory) = &data.memory else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let view: MemoryView = memory.view(&store);
-
-        let mut module_buffer = vec![0u8; module_len as usize];
-        let Ok(_) = view.read(module_ptr as u64, &mut module_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut function_buffer = vec![0u8; function_len as usize];
-        let Ok(_) = view.read(function_ptr as u64, &mut function_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut arg_1_buffer = vec![0u8; arg_1_len as usize];
-        let Ok(_) = view.read(arg_1_ptr as u64, &mut arg_1_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_2_buffer = vec![0u8; arg_2_len as usize];
-        let Ok(_) = view.read(arg_2_ptr as u64, &mut arg_2_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut args = Vec::with_capacity(2);
-        args.push(arg_1_buffer);
-        args.push(arg_2_buffer);
-
-        let (rx, request_id) = Self::request_from_rust_call(
-            data.rpc_pid,
-            remaining_u64,
-            module_buffer,
-            function_buffer,
-            args,
-            data.attached_symbol.clone(),
-            data.attached_amount.clone(),
-        );
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((error, logs, remaining_exec, result)) => {
-                if error != b"ok" {
-                    return Err(RuntimeError::new("xcc_failed"));
-                }
-
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                Self::write_i32(&view, 30_000, error.len() as i32)?;
-                write_bin(&view, 30_004, &error)?;
-
-                match result {
-                    Some(bytes) => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), bytes.len() as i32)?;
-                        write_bin(&view, 30_004 + (error.len() as u64) + 4, &bytes)?;
-                    }
-                    None => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), 0)?;
-                    }
-                }
-
-                data.logs.extend(logs);
-                set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
-
-                Ok(30_000)
-            }
-            Err(_) => {
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        Self::import_call_implementation(
+            env,
+            module_ptr,
+            module_len,
+            function_ptr,
+            function_len,
+            &[(arg_1_ptr, arg_1_len), (arg_2_ptr, arg_2_len)],
+        )
     }
+
+    #[allow(clippy::too_many_arguments)]
     fn import_call_3_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
+        env: FunctionEnvMut<HostEnv>,
         module_ptr: i32,
         module_len: i32,
         function_ptr: i32,
@@ -1327,97 +864,23 @@ impl WasmerRs {
         arg_3_ptr: i32,
         arg_3_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48 + (arg_1_len as u64) + (arg_2_len as u64) + (arg_3_len as u64)) * 1000;
-
-        let (data, mut store) = env.data_and_store_mut();
-
-        let instance_arc = data
-            .instance
-            .as_ref()
-            .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
-
-        let Some(memory) = &data.memory else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let view: MemoryView = memory.view(&store);
-
-        let mut module_buffer = vec![0u8; module_len as usize];
-        let Ok(_) = view.read(module_ptr as u64, &mut module_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut function_buffer = vec![0u8; function_len as usize];
-        let Ok(_) = view.read(function_ptr as u64, &mut function_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut arg_1_buffer = vec![0u8; arg_1_len as usize];
-        let Ok(_) = view.read(arg_1_ptr as u64, &mut arg_1_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_2_buffer = vec![0u8; arg_2_len as usize];
-        let Ok(_) = view.read(arg_2_ptr as u64, &mut arg_2_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_3_buffer = vec![0u8; arg_3_len as usize];
-        let Ok(_) = view.read(arg_3_ptr as u64, &mut arg_3_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut args = Vec::with_capacity(3);
-        args.push(arg_1_buffer);
-        args.push(arg_2_buffer);
-        args.push(arg_3_buffer);
-
-        let (rx, request_id) = Self::request_from_rust_call(
-            data.rpc_pid,
-            remaining_u64,
-            module_buffer,
-            function_buffer,
-            args,
-            data.attached_symbol.clone(),
-            data.attached_amount.clone(),
-        );
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((error, logs, remaining_exec, result)) => {
-                if error != b"ok" {
-                    return Err(RuntimeError::new("xcc_failed"));
-                }
-
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                Self::write_i32(&view, 30_000, error.len() as i32)?;
-                Self::write_bin(&view, 30_004, &error)?;
-
-                match result {
-                    Some(bytes) => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), bytes.len() as i32)?;
-                        Self::write_bin(&view, 30_004 + (error.len() as u64) + 4, &bytes)?;
-                    }
-                    None => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), 0)?;
-                    }
-                }
-
-                data.logs.extend(logs);
-                set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
-
-                Ok(30_000)
-            }
-            Err(_) => {
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+        Self::import_call_implementation(
+            env,
+            module_ptr,
+            module_len,
+            function_ptr,
+            function_len,
+            &[
+                (arg_1_ptr, arg_1_len),
+                (arg_2_ptr, arg_2_len),
+                (arg_3_ptr, arg_3_len),
+            ],
+        )
     }
+
+    #[allow(clippy::too_many_arguments)]
     fn import_call_4_implementation(
-        mut env: FunctionEnvMut<HostEnv>,
+        env: FunctionEnvMut<HostEnv>,
         module_ptr: i32,
         module_len: i32,
         function_ptr: i32,
@@ -1431,209 +894,202 @@ impl WasmerRs {
         arg_4_ptr: i32,
         arg_4_len: i32,
     ) -> Result<i32, RuntimeError> {
-        let cost = (48
-            + (arg_1_len as u64)
-            + (arg_2_len as u64)
-            + (arg_3_len as u64)
-            + (arg_4_len as u64))
-            * 1000;
-
-        let (data, mut store) = env.data_and_store_mut();
-
-        let instance_arc = data
-            .instance
-            .as_ref()
-            .ok_or_else(|| RuntimeError::new("invalid_instance"))?;
-        let remaining_u64 = Self::charge_points(&mut store, instance_arc.as_ref(), cost)?;
-
-        let Some(memory) = &data.memory else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let view: MemoryView = memory.view(&store);
-
-        let mut module_buffer = vec![0u8; module_len as usize];
-        let Ok(_) = view.read(module_ptr as u64, &mut module_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut function_buffer = vec![0u8; function_len as usize];
-        let Ok(_) = view.read(function_ptr as u64, &mut function_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut arg_1_buffer = vec![0u8; arg_1_len as usize];
-        let Ok(_) = view.read(arg_1_ptr as u64, &mut arg_1_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_2_buffer = vec![0u8; arg_2_len as usize];
-        let Ok(_) = view.read(arg_2_ptr as u64, &mut arg_2_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_3_buffer = vec![0u8; arg_3_len as usize];
-        let Ok(_) = view.read(arg_3_ptr as u64, &mut arg_3_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-        let mut arg_4_buffer = vec![0u8; arg_4_len as usize];
-        let Ok(_) = view.read(arg_4_ptr as u64, &mut arg_4_buffer) else {
-            return Err(RuntimeError::new("invalid_memory"));
-        };
-
-        let mut args = Vec::with_capacity(4);
-        args.push(arg_1_buffer);
-        args.push(arg_2_buffer);
-        args.push(arg_3_buffer);
-        args.push(arg_4_buffer);
-
-        let (rx, request_id) = request_from_rust_call(
-            data.rpc_pid,
-            remaining_u64,
-            module_buffer,
-            function_buffer,
-            args,
-            data.attached_symbol.clone(),
-            data.attached_amount.clone(),
-        );
-
-        match rx.recv_timeout(std::time::Duration::from_secs(6)) {
-            Ok((error, logs, remaining_exec, result)) => {
-                if error != b"ok" {
-                    return Err(RuntimeError::new("xcc_failed"));
-                }
-
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
-
-                Self::write_i32(&view, 30_000, error.len() as i32)?;
-                write_bin(&view, 30_004, &error)?;
-
-                match result {
-                    Some(bytes) => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), bytes.len() as i32)?;
-                        write_bin(&view, 30_004 + (error.len() as u64) + 4, &bytes)?;
-                    }
-                    None => {
-                        Self::write_i32(&view, 30_004 + (error.len() as u64), 0)?;
-                    }
-                }
-
-                data.logs.extend(logs);
-                set_remaining_points(&mut store, instance_arc.as_ref(), remaining_exec);
+        Self::import_call_implementation(
+            env,
+            module_ptr,
+            module_len,
+            function_ptr,
+            function_len,
+            &[
+                (arg_1_ptr, arg_1_len),
+                (arg_2_ptr, arg_2_len),
+                (arg_3_ptr, arg_3_len),
+                (arg_4_ptr, arg_4_len),
+            ],
+        )
+    }
 
-                Ok(30_000)
-            }
-            Err(_) => {
-                data.attached_symbol = Vec::new();
-                data.attached_amount = Vec::new();
+    /// Env values a contract reads through the `*_ptr` globals, in `ENV_PTR_GLOBALS` order
+    fn env_records(env: &MapEnv) -> Vec<Vec<u8>> {
+        vec![
+            env.seed.clone().unwrap_or_default(),
+            env.entry_signer.clone(),
+            env.entry_prev_hash.clone(),
+            env.entry_vr.clone(),
+            env.entry_dr.clone(),
+            env.tx_signer.clone().unwrap_or_default(),
+            env.account_current.clone().unwrap_or_default(),
+            env.account_caller.clone().unwrap_or_default(),
+            env.account_origin.clone().unwrap_or_default(),
+            env.attached_symbol.clone().into_bytes(),
+            env.attached_amount.to_string().into_bytes(),
+        ]
+    }
 
-                let mut map = REQ_REGISTRY_CALL.lock().unwrap();
-                map.remove(&request_id);
-                Err(RuntimeError::new("no_elixir_callback"))
-            }
-        }
+    /// Pointers of `records` laid out back to back as `len ++ bytes` from `offset`
+    fn record_ptrs(offset: u64, records: &[Vec<u8>]) -> Vec<i32> {
+        records
+            .iter()
+            .scan(offset, |offset, record| {
+                let ptr = *offset as i32;
+                *offset += 4 + record.len() as u64;
+                Some(ptr)
+            })
+            .collect()
     }
 
-    fn cost_function(operator: &Operator) -> u64 {
-        10
-        /*match operator {
-            Operator::Loop { .. }
-            | Operator::Block { .. }
-            | Operator::If { .. }
-            | Operator::Else { .. }
-            | Operator::End { .. }
-            | Operator::Br { .. }
-            | Operator::BrIf { .. }
-            | Operator::Return { .. }
-            | Operator::Unreachable { .. } => 1,
-
-            Operator::Call { .. } | Operator::CallIndirect { .. } => 5,
-
-            Operator::I32Load { .. }
-            | Operator::I64Load { .. }
-            | Operator::F32Load { .. }
-            | Operator::F64Load { .. }
-            | Operator::I32Store { .. }
-            | Operator::I64Store { .. }
-            | Operator::F32Store { .. }
-            | Operator::F64Store { .. } => 3,
-
-            _ => 2,
-        }*/
+    fn write_records(
+        view: &MemoryView,
+        offset: u64,
+        records: &[Vec<u8>],
+    ) -> Result<(), RuntimeError> {
+        for (ptr, record) in Self::record_ptrs(offset, records).into_iter().zip(records) {
+            Self::write_i32(view, ptr as u64, record.len() as i32)?;
+            Self::write_bin(view, ptr as u64 + 4, record)?;
+        }
+        Ok(())
     }
 
-    pub fn call(
-        wasm_bytes: &[u8],
-        function_name: &str,
-        wasm_args: Vec<Value>,
-        exec_points: u64,
-    ) -> Result<(u64, Vec<Vec<u8>>, Option<Vec<u8>>), String> {
-        // metering
-        let metering = Arc::new(Metering::new(exec_points, |_operator| -> u64 { 1 }));
+    /// Compiles with the metering of `gas` built in. The meter starts empty, callers
+    /// fill it with `set_remaining_points` after instantiating.
+    pub fn compile(wasm_bytes: &[u8], gas: &GasSchedule) -> Result<(Engine, Module), String> {
+        let gas = *gas;
+        let metering = Arc::new(Metering::new(0, move |operator| {
+            gas.operator_cost(operator)
+        }));
 
         let mut compiler = Singlepass::default();
         compiler.canonicalize_nans(true);
         compiler.push_middleware(metering);
 
-        let mut features = Features::new();
-        features.threads(false);
-        features.reference_types(false);
-        features.simd(false);
-        features.multi_value(false);
-        features.tail_call(false);
-        features.module_linking(false);
-        features.multi_memory(false);
-        features.memory64(false);
+        let engine: Engine = EngineBuilder::new(compiler)
+            .set_features(Some(Self::features()))
+            .into();
+        let module = Module::new(&engine, wasm_bytes).map_err(|_| "invalid_wasm".to_string())?;
+        Ok((engine, module))
+    }
 
-        let engine = EngineBuilder::new(compiler).set_features(Some(features));
+    /// Runs `function_name` metered with the env's remaining exec points.
+    /// Storage host calls are forwarded over `rpc`. Returns (remaining, logs, return value),
+    /// or (error, remaining, logs) so a failed call is still charged.
+    pub fn call(
+        env: &MapEnv,
+        wasm_bytes: &[u8],
+        function_name: &str,
+        args: &[Vec<u8>],
+        rpc: mpsc::Sender<WasmMessage>,
+    ) -> Result<(u64, Vec<Vec<u8>>, Option<Vec<u8>>), (String, u64, Vec<Vec<u8>>)> {
+        let exec_points = env.call_exec_points_remaining;
+        let setup_err = |e: &str| (e.to_string(), exec_points, vec![]);
+
+        let gas = *GasSchedule::for_epoch(env.entry_epoch);
+        let (engine, module) =
+            ModuleCache::get(wasm_bytes, &gas).map_err(|_| setup_err("invalid_wasm"))?;
         let mut store = Store::new(engine);
 
-        // compile module
-        let module =
-            Module::new(&store, wasm_bytes).map_err(|e| format!("Failed to compile wasm: {e}"))?;
-
         // create memory
-        let memory = Memory::new(&mut store, MemoryType::new(Pages(8), None, false))
-            .map_err(|e| format!("Failed to create memory: {e}"))?;
+        let memory_type = MemoryType::new(
+            Pages(INITIAL_MEMORY_PAGES),
+            Some(Pages(MAX_MEMORY_PAGES)),
+            false,
+        );
+        let memory =
+            Memory::new(&mut store, memory_type).map_err(|_| setup_err("invalid_memory"))?;
+        let env_records = Self::env_records(env);
 
         // host environment
         let host_env = FunctionEnv::new(
             &mut store,
             HostEnv {
                 memory: Some(memory.clone()),
+                readonly: env.readonly,
+                current_account: env.account_current.clone().unwrap_or_default(),
+                rpc: Some(rpc),
+                gas,
                 ..Default::default()
             },
         );
 
         // imports
-        let import_object = imports! {
+        let mut import_object = imports! {
             "env" => {
-                "memory" => memory,
-                // Example host function:
-                "log" => Function::new_typed_with_env(&mut store, &host_env, |env: FunctionEnvMut<HostEnv>, ptr: i32, len: i32| {
-                    if let Some(mem) = &env.data().memory {
-                        let view = mem.view(&env.as_store_ref());
-                        let mut buf = vec![0u8; len as usize];
-                        for i in 0..len {
-                            buf[i as usize] = view[(ptr + i) as u64].get();
-                        }
-                        env.data_mut().logs.push(buf);
-                    }
-                }),
+                "memory" => memory.clone(),
+                "entry_slot" => Global::new(&mut store, Value::I64(env.entry_slot as i64)),
+                "entry_prev_slot" => Global::new(&mut store, Value::I64(env.entry_prev_slot)),
+                "entry_height" => Global::new(&mut store, Value::I64(env.entry_height as i64)),
+                "entry_epoch" => Global::new(&mut store, Value::I64(env.entry_epoch as i64)),
+                "tx_nonce" => Global::new(
+                    &mut store,
+                    Value::I64(env.tx_nonce.unwrap_or_default() as i64),
+                ),
+
+                "abort" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::abort_implementation),
+                "import_log" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_log_implementation),
+                "import_attach" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_attach_implementation),
+                "import_return_value" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_return_value_implementation),
+
+                "import_storage_kv_get" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_get_implementation),
+                "import_storage_kv_exists" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_exists_implementation),
+                "import_storage_kv_get_prev" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_get_prev_implementation),
+                "import_storage_kv_get_next" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_get_next_implementation),
+                "import_storage_kv_put" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_put_implementation),
+                "import_storage_kv_increment" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_increment_implementation),
+                "import_storage_kv_delete" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_delete_implementation),
+                "import_storage_kv_clear" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_storage_kv_clear_implementation),
+
+                "import_call_0" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_call_0_implementation),
+                "import_call_1" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_call_1_implementation),
+                "import_call_2" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_call_2_implementation),
+                "import_call_3" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_call_3_implementation),
+                "import_call_4" => Function::new_typed_with_env(
+                    &mut store, &host_env, Self::import_call_4_implementation),
             }
         };
+        let env_ptrs = Self::record_ptrs(10_000, &env_records);
+        for (name, ptr) in ENV_PTR_GLOBALS.iter().zip(env_ptrs) {
+            import_object.define("env", name, Global::new(&mut store, Value::I32(ptr)));
+        }
 
         // instantiate
         let instance = Instance::new(&mut store, &module, &import_object)
-            .map_err(|e| format!("Failed to instantiate: {e}"))?;
+            .map_err(|_| setup_err("invalid_imports"))?;
+        host_env.as_mut(&mut store).instance = Some(Arc::new(instance.clone()));
+        // cached modules are compiled with an empty meter
+        set_remaining_points(&mut store, &instance, exec_points);
 
         // get function
         let entry_to_call = instance
             .exports
             .get_function(function_name)
-            .map_err(|e| format!("Function not found: {e}"))?;
-
-        // call function
-        let result = entry_to_call
-            .call(&mut store, &wasm_args)
-            .map_err(|e| format!("Call failed: {e}"))?;
+            .map_err(|_| setup_err("function_not_found"))?;
+        // written after instantiation so data segments cannot clobber them
+        let view = memory.view(&store);
+        Self::write_records(&view, 10_000, &env_records)
+            .and_then(|_| Self::write_records(&view, 40_000, args))
+            .map_err(|_| setup_err("invalid_memory"))?;
+        let wasm_args: Vec<Value> = Self::record_ptrs(40_000, args)
+            .into_iter()
+            .map(Value::I32)
+            .collect();
+
+        // call function, import_return_value ends the call early by trapping
+        let result = entry_to_call.call(&mut store, &wasm_args);
 
         let remaining = match get_remaining_points(&mut store, &instance) {
             MeteringPoints::Remaining(v) => v,
@@ -1641,20 +1097,98 @@ impl WasmerRs {
         };
 
         let host_data = host_env.as_ref(&store);
+        let error = match result {
+            Ok(_) => None,
+            Err(_) if remaining == 0 => Some("insufficient_exec_points".to_string()),
+            Err(e) if e.message() == "return_value" => None,
+            Err(e) => Some(e.message()),
+        };
 
-        Ok((
-            remaining,
-            host_data.logs.clone(),
-            host_data.return_value.clone(),
-        ))
+        match error {
+            None => Ok((
+                remaining,
+                host_data.logs.clone(),
+                host_data.return_value.clone(),
+            )),
+            Some(error) => Err((error, remaining, host_data.logs.clone())),
+        }
     }
 
-    pub fn validate_contract(mapenv: &ContractEnv, wasm_bytes: &[u8]) -> Result<(), String> {
-        let metering = Arc::new(Metering::new(10_000_000, |_op| 1));
-        let mut compiler = Singlepass::default();
-        compiler.canonicalize_nans(true);
-        compiler.push_middleware(metering);
+    /// Static checks run on deploy, before the bytecode is stored.
+    /// Returns the names of the exported functions.
+    /// Compiles through the module cache under `gas`, so the first call finds it warm.
+    pub fn validate_contract(wasm_bytes: &[u8], gas: &GasSchedule) -> Result<Vec<String>, String> {
+        // compiling with the restricted feature set rejects simd, threads, memory64 etc
+        let (_, module) = ModuleCache::get(wasm_bytes, gas)?;
+
+        // a start function runs on instantiation, before the call budget is set on the
+        // metering, so it would always trap
+        for payload in Parser::new(0).parse_all(wasm_bytes) {
+            if let Ok(Payload::StartSection { .. }) = payload {
+                return Err("start_function_not_allowed".to_string());
+            }
+        }
+
+        let mut memories = 0;
+        for import in module.imports() {
+            if import.module() != "env" || !ALLOWED_IMPORTS.contains(&import.name()) {
+                return Err(format!(
+                    "import_not_allowed:{}.{}",
+                    import.module(),
+                    import.name()
+                ));
+            }
+            match (import.name(), import.ty()) {
+                ("memory", ExternType::Memory(ty)) => {
+                    Self::validate_memory(ty)?;
+                    memories += 1;
+                }
+                ("memory", _) | (_, ExternType::Memory(_)) => {
+                    return Err("invalid_memory_import".to_string());
+                }
+                _ => {}
+            }
+        }
+        // calls only ever see the host memory: it has to be imported as env.memory, and with
+        // multi memory disabled a module importing it cannot define a second one
+        if memories != 1 {
+            return Err("exactly_one_memory_required".to_string());
+        }
+
+        let mut functions = vec![];
+        for export in module.exports() {
+            match export.ty() {
+                ExternType::Function(_) => functions.push(export.name().to_string()),
+                ExternType::Memory(_) => return Err("memory_export_not_allowed".to_string()),
+                _ if export.name() == "init" => return Err("init_not_a_function".to_string()),
+                _ => {}
+            }
+        }
+        if functions.iter().all(|name| name == "init") {
+            return Err("no_exported_functions".to_string());
+        }
 
+        Ok(functions)
+    }
+
+    /// The import has to accept the memory `call` links in
+    fn validate_memory(ty: &MemoryType) -> Result<(), String> {
+        if ty.shared {
+            return Err("shared_memory_not_allowed".to_string());
+        }
+        if ty.minimum.0 > INITIAL_MEMORY_PAGES {
+            return Err("memory_too_large".to_string());
+        }
+        if ty
+            .maximum
+            .is_some_and(|maximum| maximum.0 < MAX_MEMORY_PAGES)
+        {
+            return Err("memory_maximum_too_small".to_string());
+        }
+        Ok(())
+    }
+
+    fn features() -> Features {
         let mut features = Features::new();
         features.threads(false);
         features.reference_types(false);
@@ -1664,79 +1198,6 @@ impl WasmerRs {
         features.module_linking(false);
         features.multi_memory(false);
         features.memory64(false);
-
-        let engine = EngineBuilder::new(compiler).set_features(Some(features));
-        let mut store = Store::new(engine);
-
-        // Compile module
-        let module =
-            Module::new(&store, wasm_bytes).map_err(|e| format!("Failed to compile wasm: {e}"))?;
-
-        // Allocate memory
-        let memory = Memory::new(&mut store, MemoryType::new(Pages(8), None, false))
-            .map_err(|e| format!("Failed to create memory: {e}"))?;
-
-        // Write environment data into memory
-        // For example, seed:
-        memory
-            .view(&mut store)
-            .write(10_000, &(32i32.to_le_bytes()))
-            .map_err(|e| format!("mem write failed: {e}"))?;
-        memory
-            .view(&mut store)
-            .write(10_004, &mapenv.seed)
-            .map_err(|e| format!("mem write failed: {e}"))?;
-
-        // ... repeat for other fields from mapenv like signer, prev_hash, etc ...
-
-        // Host environment
-        let host_env = FunctionEnv::new(
-            &mut store,
-            HostEnv {
-                memory: Some(memory.clone()),
-                readonly: true,
-                current_account: mapenv.account_current.clone(),
-                attached_symbol: mapenv.attached_symbol.clone(),
-                attached_amount: mapenv.attached_amount.clone(),
-                ..Default::default()
-            },
-        );
-
-        // Import object
-        let import_object = imports! {
-            "env" => {
-                "memory" => memory,
-                "seed_ptr" => Global::new(&mut store, Value::I32(10_000)),
-                "entry_signer_ptr" => Global::new(&mut store, Value::I32(10_100)),
-                "entry_prev_hash_ptr" => Global::new(&mut store, Value::I32(10_200)),
-                "entry_slot" => Global::new(&mut store, Value::I64(mapenv.entry_slot)),
-                "entry_prev_slot" => Global::new(&mut store, Value::I64(mapenv.entry_prev_slot)),
-                "entry_height" => Global::new(&mut store, Value::I64(mapenv.entry_height)),
-                "entry_epoch" => Global::new(&mut store, Value::I64(mapenv.entry_epoch)),
-                "entry_vr_ptr" => Global::new(&mut store, Value::I32(10_300)),
-                "entry_dr_ptr" => Global::new(&mut store, Value::I32(10_400)),
-                "tx_signer_ptr" => Global::new(&mut store, Value::I32(11_000)),
-                "tx_nonce" => Global::new(&mut store, Value::I64(mapenv.tx_nonce)),
-                "account_current_ptr" => Global::new(&mut store, Value::I32(12_000)),
-                "account_caller_ptr" => Global::new(&mut store, Value::I32(13_000)),
-                "account_origin_ptr" => Global::new(&mut store, Value::I32(14_000)),
-                "attached_symbol_ptr" => Global::new(&mut store, Value::I32(15_000)),
-                "attached_amount_ptr" => Global::new(&mut store, Value::I32(16_000)),
-
-                // Hook up host functions (placeholders here)
-                "import_log" => Function::new_typed_with_env(&mut store, &host_env, |_env: FunctionEnvMut<HostEnv>, ptr: i32, len: i32| {
-                    println!("log from wasm at ptr {ptr}, len {len}");
-                }),
-                "abort" => Function::new_typed_with_env(&mut store, &host_env, |_env: FunctionEnvMut<HostEnv>, msg_ptr: i32, msg_len: i32| {
-                    eprintln!("wasm abort called at {msg_ptr}, len {msg_len}");
-                }),
-            }
-        };
-
-        // Instantiate
-        let _instance = Instance::new(&mut store, &module, &import_object)
-            .map_err(|e| format!("Instance creation failed: {e}"))?;
-
-        Ok(())
+        features
     }
 }
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+
+use sha2::{Digest, Sha256};
+use wasmer::{Engine, Module, sys::EngineBuilder};
+
+use crate::*;
+
+/// Bump when the compiler setup changes in a way that makes old artifacts unusable
+/// (features, middlewares, canonicalization). The wasmer version is added on top.
+pub const ENGINE_REVISION: u32 = 1;
+
+static MODULE_CACHE: LazyLock<Mutex<ModuleLru>> = LazyLock::new(|| {
+    ModuleCache::prune_stale_artifacts();
+    Mutex::new(ModuleLru::default())
+});
+
+#[derive(Default)]
+struct ModuleLru {
+    entries: HashMap<[u8; 32], (Engine, Module, u64)>,
+    tick: u64,
+}
+
+impl ModuleLru {
+    fn get(&mut self, key: &[u8; 32]) -> Option<(Engine, Module)> {
+        self.tick += 1;
+        let (engine, module, used) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        Some((engine.clone(), module.clone()))
+    }
+
+    fn insert(&mut self, key: [u8; 32], engine: Engine, module: Module) {
+        if self.entries.len() >= ModuleCache::CAPACITY {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(key, (engine, module, self.tick));
+    }
+}
+
+/// Compiled contracts, kept in memory (LRU) and as serialized artifacts under
+/// `work_folder/wasm_cache/{engine_version}`. Artifacts are tied to the engine
+/// version and the gas schedule, since metering is compiled into the module.
+pub struct ModuleCache;
+
+impl ModuleCache {
+    pub const CAPACITY: usize = 256;
+
+    pub fn engine_version() -> String {
+        format!("wasmer{}-r{}", wasmer::VERSION, ENGINE_REVISION)
+    }
+
+    /// sha256(bytecode) salted with the engine version and the gas schedule version
+    pub fn key(wasm_bytes: &[u8], gas: &GasSchedule) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(wasm_bytes);
+        hasher.update(Self::engine_version().as_bytes());
+        hasher.update(gas.version.to_le_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Compiled module for `wasm_bytes`, from memory, disk or a fresh compile
+    pub fn get(wasm_bytes: &[u8], gas: &GasSchedule) -> Result<(Engine, Module), String> {
+        let key = Self::key(wasm_bytes, gas);
+        if let Some(hit) = MODULE_CACHE.lock().unwrap().get(&key) {
+            return Ok(hit);
+        }
+
+        let (engine, module) = match Self::load_artifact(&key) {
+            Some(loaded) => loaded,
+            None => {
+                let (engine, module) = WasmerRs::compile(wasm_bytes, gas)?;
+                Self::store_artifact(&key, &module);
+                (engine, module)
+            }
+        };
+        MODULE_CACHE
+            .lock()
+            .unwrap()
+            .insert(key, engine.clone(), module.clone());
+        Ok((engine, module))
+    }
+
+    fn artifact_dir() -> PathBuf {
+        AMACONFIG.work_folder.join("wasm_cache")
+    }
+
+    fn artifact_path(key: &[u8; 32]) -> PathBuf {
+        Self::artifact_dir()
+            .join(Self::engine_version())
+            .join(format!("{}.wasmu", hex::encode(key)))
+    }
+
+    fn load_artifact(key: &[u8; 32]) -> Option<(Engine, Module)> {
+        let bytes = std::fs::read(Self::artifact_path(key)).ok()?;
+        let engine: Engine = EngineBuilder::headless().into();
+        // SAFETY: artifacts are only written by `store_artifact` from modules this
+        // node compiled itself, under a directory named after the exact engine version
+        let module = unsafe { Module::deserialize(&engine, bytes) }.ok()?;
+        Some((engine, module))
+    }
+
+    /// Best effort, a failed write only costs a recompile later
+    fn store_artifact(key: &[u8; 32], module: &Module) {
+        let Ok(bytes) = module.serialize() else {
+            return;
+        };
+        let path = Self::artifact_path(key);
+        let tmp_path = path.with_extension("tmp");
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if std::fs::write(&tmp_path, &bytes).is_ok() {
+            let _ = std::fs::rename(&tmp_path, &path);
+        }
+    }
+
+    /// Drop artifact directories left behind by other engine versions
+    fn prune_stale_artifacts() {
+        let Ok(dirs) = std::fs::read_dir(Self::artifact_dir()) else {
+            return;
+        };
+        let current = Self::engine_version();
+        for dir in dirs.flatten() {
+            if dir.file_name().to_string_lossy() != current {
+                let _ = std::fs::remove_dir_all(dir.path());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_depends_on_bytecode_and_schedule() {
+        let gas = GAS_SCHEDULE_V0;
+        let repriced = GasSchedule {
+            version: gas.version + 1,
+            ..gas
+        };
+        assert_eq!(ModuleCache::key(b"\0asm", &gas), ModuleCache::key(b"\0asm", &gas));
+        assert_ne!(ModuleCache::key(b"\0asm", &gas), ModuleCache::key(b"\0asm\x01", &gas));
+        assert_ne!(ModuleCache::key(b"\0asm", &gas), ModuleCache::key(b"\0asm", &repriced));
+    }
+
+    fn compiled() -> (Engine, Module) {
+        let wat = r#"(module (import "env" "memory" (memory 1)) (func (export "noop")))"#;
+        let wasm_bytes = wasmer::wat2wasm(wat.as_bytes()).unwrap();
+        WasmerRs::compile(&wasm_bytes, &GAS_SCHEDULE_V0).unwrap()
+    }
+
+    fn names(module: &Module) -> (Vec<String>, Vec<String>) {
+        let imports = module.imports().map(|i| i.name().to_string()).collect();
+        let exports = module.exports().map(|e| e.name().to_string()).collect();
+        (imports, exports)
+    }
+
+    #[test]
+    fn test_artifact_round_trip() {
+        let (_, module) = compiled();
+        let key = ModuleCache::key(b"test_artifact_round_trip", &GAS_SCHEDULE_V0);
+        let path = ModuleCache::artifact_path(&key);
+        let _ = std::fs::remove_file(&path);
+        assert!(ModuleCache::load_artifact(&key).is_none());
+
+        ModuleCache::store_artifact(&key, &module);
+        assert!(path.exists());
+        assert!(!path.with_extension("tmp").exists());
+        let (_, loaded) = ModuleCache::load_artifact(&key).unwrap();
+        assert_eq!(names(&loaded), names(&module));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let (engine, module) = compiled();
+        let key = |i: usize| ModuleCache::key(&i.to_le_bytes(), &GAS_SCHEDULE_V0);
+        let mut lru = ModuleLru::default();
+        for i in 0..ModuleCache::CAPACITY {
+            lru.insert(key(i), engine.clone(), module.clone());
+        }
+        assert_eq!(lru.entries.len(), ModuleCache::CAPACITY);
+
+        // a hit on the oldest entry leaves the second one as the least recently used
+        assert!(lru.get(&key(0)).is_some());
+        lru.insert(key(ModuleCache::CAPACITY), engine.clone(), module.clone());
+        assert_eq!(lru.entries.len(), ModuleCache::CAPACITY);
+        assert!(lru.get(&key(1)).is_none());
+        assert!(lru.get(&key(0)).is_some());
+        assert!(lru.get(&key(ModuleCache::CAPACITY)).is_some());
+
+        lru.insert(key(ModuleCache::CAPACITY + 1), engine, module);
+        assert!(lru.get(&key(2)).is_none());
+        assert_eq!(lru.entries.len(), ModuleCache::CAPACITY);
+    }
+}
@@ -0,0 +1,142 @@
+/// Value model of VanillaSer, the term encoding shared with the Elixir node.
+/// Atoms and strings are plain `Bytes`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VanillaValue {
+    Nil,
+    Bool(bool),
+    Int(i128),
+    Bytes(Vec<u8>),
+    List(Vec<VanillaValue>),
+    Map(Vec<(VanillaValue, VanillaValue)>),
+}
+
+impl From<i128> for VanillaValue {
+    fn from(n: i128) -> Self {
+        VanillaValue::Int(n)
+    }
+}
+
+impl From<&[u8]> for VanillaValue {
+    fn from(bytes: &[u8]) -> Self {
+        VanillaValue::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<&str> for VanillaValue {
+    fn from(s: &str) -> Self {
+        VanillaValue::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+/// Encoding, one tag byte per value:
+///
+/// | tag | value  | body                                                     |
+/// |-----|--------|----------------------------------------------------------|
+/// | 0   | nil    | -                                                        |
+/// | 1   | true   | -                                                        |
+/// | 2   | false  | -                                                        |
+/// | 3   | int    | varint                                                   |
+/// | 5   | bytes  | varint(len) ++ bytes                                     |
+/// | 6   | list   | varint(count) ++ items                                   |
+/// | 7   | map    | varint(count) ++ (key ++ value) sorted by encoded key    |
+///
+/// A varint is 0x00 for zero, otherwise one header byte `sign << 7 | len`
+/// followed by the `len` byte big endian magnitude with no leading zeros.
+pub struct VanillaSer;
+
+impl VanillaSer {
+    pub const TAG_NIL: u8 = 0;
+    pub const TAG_TRUE: u8 = 1;
+    pub const TAG_FALSE: u8 = 2;
+    pub const TAG_INT: u8 = 3;
+    pub const TAG_BYTES: u8 = 5;
+    pub const TAG_LIST: u8 = 6;
+    pub const TAG_MAP: u8 = 7;
+
+    pub fn encode(value: &VanillaValue) -> Vec<u8> {
+        let mut out = Vec::new();
+        Self::encode_into(value, &mut out);
+        out
+    }
+
+    fn encode_into(value: &VanillaValue, out: &mut Vec<u8>) {
+        match value {
+            VanillaValue::Nil => out.push(Self::TAG_NIL),
+            VanillaValue::Bool(true) => out.push(Self::TAG_TRUE),
+            VanillaValue::Bool(false) => out.push(Self::TAG_FALSE),
+            VanillaValue::Int(n) => {
+                out.push(Self::TAG_INT);
+                Self::encode_varint(*n, out);
+            }
+            VanillaValue::Bytes(bytes) => {
+                out.push(Self::TAG_BYTES);
+                Self::encode_varint(bytes.len() as i128, out);
+                out.extend_from_slice(bytes);
+            }
+            VanillaValue::List(items) => {
+                out.push(Self::TAG_LIST);
+                Self::encode_varint(items.len() as i128, out);
+                for item in items {
+                    Self::encode_into(item, out);
+                }
+            }
+            VanillaValue::Map(pairs) => {
+                let mut encoded: Vec<(Vec<u8>, Vec<u8>)> = pairs
+                    .iter()
+                    .map(|(k, v)| (Self::encode(k), Self::encode(v)))
+                    .collect();
+                encoded.sort();
+
+                out.push(Self::TAG_MAP);
+                Self::encode_varint(encoded.len() as i128, out);
+                for (k, v) in encoded {
+                    out.extend_from_slice(&k);
+                    out.extend_from_slice(&v);
+                }
+            }
+        }
+    }
+
+    pub fn encode_varint(n: i128, out: &mut Vec<u8>) {
+        if n == 0 {
+            out.push(0);
+            return;
+        }
+        let magnitude = n.unsigned_abs().to_be_bytes();
+        let skip = magnitude.iter().take_while(|b| **b == 0).count();
+        let sign = if n < 0 { 0x80 } else { 0 };
+        out.push(sign | (magnitude.len() - skip) as u8);
+        out.extend_from_slice(&magnitude[skip..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(n: i128) -> Vec<u8> {
+        let mut out = vec![];
+        VanillaSer::encode_varint(n, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_varint() {
+        assert_eq!(varint(0), vec![0x00]);
+        assert_eq!(varint(1), vec![0x01, 0x01]);
+        assert_eq!(varint(-1), vec![0x81, 0x01]);
+        assert_eq!(varint(256), vec![0x02, 0x01, 0x00]);
+        assert_eq!(varint(i128::MIN)[0], 0x80 | 16);
+    }
+
+    #[test]
+    fn test_map_order_is_canonical() {
+        let a = VanillaValue::Map(vec![("b".into(), VanillaValue::Nil), ("a".into(), 1.into())]);
+        let b = VanillaValue::Map(vec![("a".into(), 1.into()), ("b".into(), VanillaValue::Nil)]);
+        assert_eq!(VanillaSer::encode(&a), VanillaSer::encode(&b));
+        assert_eq!(
+            VanillaSer::encode(&a),
+            vec![7, 1, 2, 5, 1, 1, b'a', 3, 1, 1, 5, 1, 1, b'b', 0]
+        );
+    }
+}
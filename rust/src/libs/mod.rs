@@ -1,13 +1,14 @@
 use {
     crate::*,
     std::{
+        net::IpAddr,
         sync::{
             Arc,
             atomic::{AtomicBool, Ordering},
         },
         time::{Duration, Instant},
     },
-    tokio::{task, time::sleep},
+    tokio::{sync::mpsc, task, time::sleep},
 };
 
 pub mod bic;
@@ -55,9 +56,35 @@ impl AmaApp {
             println!("LoggerGen started");
         });
 
-        task::spawn(async {
-            // FabricGen::start_link();
+        // outbound traffic of the consensus actors; the socket gen only receives so far,
+        // so these queues are drained here until it takes sends
+        let (socket_sender, mut socket_receiver) = mpsc::channel::<(Vec<IpAddr>, Vec<u8>)>(1024);
+        let (sync_outbound, mut sync_receiver) = mpsc::unbounded_channel::<(IpAddr, NodeMsg)>();
+        task::spawn(async move { while socket_receiver.recv().await.is_some() {} });
+        task::spawn(async move { while sync_receiver.recv().await.is_some() {} });
+
+        let mut fabric_gen = FabricGen::start_link(socket_sender.clone());
+        task::spawn(async move {
             println!("FabricGen started");
+            fabric_gen.run().await;
+        });
+
+        let mut fabric_sync_gen = FabricSyncGen::start_link(sync_outbound);
+        task::spawn(async move {
+            println!("FabricSyncGen started");
+            fabric_sync_gen.run().await;
+        });
+
+        let mut fabric_coordinator_gen = FabricCoordinatorGen::start_link();
+        task::spawn(async move {
+            println!("FabricCoordinatorGen started");
+            fabric_coordinator_gen.run().await;
+        });
+
+        let mut special_meeting_gen = SpecialMeetingGen::start_link(socket_sender);
+        task::spawn(async move {
+            println!("SpecialMeetingGen started");
+            special_meeting_gen.run().await;
         });
 
         // You can spawn more tasks dynamically
@@ -94,14 +94,14 @@ impl LoggerGen {
         let pk = Config::trainer_pk();
         let coins = Consensus::chain_balance(&pk).await;
 
-        let trainers = Consensus::trainers_for_height(Entry::height(&entry) + 1).await;
+        let trainers = Consensus::trainers_for_height(Entry::height(&entry) + 1);
         let is_trainer = if trainers.contains(&pk) {
             "💰"
         } else {
             "🪙"
         };
 
-        let is_synced = FabricSyncAttestGen::is_quorum_synced_off_by_1().await;
+        let is_synced = FabricSyncAttestGen::is_quorum_synced_off_by_1();
         let highest_height = std::cmp::max(
             FabricSyncAttestGen::highest_temporal_height().unwrap_or(height),
             height,
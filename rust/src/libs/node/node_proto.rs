@@ -13,7 +13,7 @@ pub struct NodeProto {}
 
 impl NodeProto {
     pub fn ping() -> NodeMsg {
-        let tip = Consensus::chain_tip_entry();
+        let tip = Consensus::best_tip();
         let temporal = Tip {
             header_unpacked: tip.header_unpacked,
             mask: tip.mask,
@@ -56,6 +56,61 @@ impl NodeProto {
         }
     }
 
+    pub fn catchup_entry(heights: Vec<u64>) -> NodeMsg {
+        NodeMsg::CatchupEntry {
+            op: "catchup_entry".to_string(),
+            heights,
+        }
+    }
+
+    pub fn catchup_tri(heights: Vec<u64>) -> NodeMsg {
+        NodeMsg::CatchupTri {
+            op: "catchup_tri".to_string(),
+            heights,
+        }
+    }
+
+    pub fn catchup_bi(heights: Vec<u64>) -> NodeMsg {
+        NodeMsg::CatchupBi {
+            op: "catchup_bi".to_string(),
+            heights,
+        }
+    }
+
+    pub fn catchup_attestation(hashes: Vec<Vec<u8>>) -> NodeMsg {
+        NodeMsg::CatchupAttestation {
+            op: "catchup_attestation".to_string(),
+            hashes,
+        }
+    }
+
+    pub fn solicit_entry(hash: Vec<u8>) -> NodeMsg {
+        NodeMsg::SolicitEntry {
+            op: "solicit_entry".to_string(),
+            hash,
+        }
+    }
+
+    pub fn solicit_entry2() -> NodeMsg {
+        NodeMsg::SolicitEntry2 {
+            op: "solicit_entry2".to_string(),
+        }
+    }
+
+    pub fn entry_reply(
+        entry_packed: Vec<u8>,
+        consensus_packed: Option<Vec<u8>>,
+        attestation_packed: Option<Vec<u8>>,
+    ) -> NodeMsg {
+        NodeMsg::Entry {
+            op: "entry".to_string(),
+            entry_packed,
+            consensus_packed,
+            attestation_packed,
+            ts_m: None,
+        }
+    }
+
     pub fn txpool(param: &[u8]) -> Vec<u8> {
         vec![]
     }
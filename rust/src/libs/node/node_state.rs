@@ -4,6 +4,7 @@
 // This is a behavioral port — domain modules are left as stubs to be implemented.
 
 use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
@@ -201,6 +202,13 @@ impl NodeState {
         }
     }
 
+    /// Tips a peer advertises in its ping feed the catch-up and quorum views
+    pub fn observe_ping(peer: &Peer, temporal: &Tip, rooted: &Tip) {
+        if let Ok(ip) = peer.ip.parse::<IpAddr>() {
+            FabricSyncGen::observe_ping(ip, &peer.signer, temporal, rooted);
+        }
+    }
+
     fn now_secs() -> i64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -234,6 +242,7 @@ impl NodeState {
     //             }
     //         }
     //         NodeMsg::Ping { temporal, rooted, ts_m } => {
+    //             Self::observe_ping(&istate.peer, &temporal, &rooted);
     //             self.handle_ping(istate, temporal, rooted, ts_m).await;
     //         }
     //         NodeMsg::PingNs { temporal, rooted, ts_m, has_permission_slip } => {
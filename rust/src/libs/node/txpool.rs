@@ -1,6 +1,7 @@
 use borsh::{BorshDeserialize, from_slice};
 use dashmap::DashMap;
 use once_cell::sync::Lazy;
+use std::collections::HashMap;
 
 use crate::*;
 
@@ -28,76 +29,84 @@ impl TXPool {
         TX_POOL.insert(hash_bs58_string, txu);
     }
 
-    // pub fn delete_packed<T: AsRef<[u8]>>(txs_packed: &[T]) {
-    //     for tx_packed in txs_packed {
-    //         let txu = Tx::unpack(tx_packed.as_ref());
-    //         TX_POOL.remove(&(txu.tx.nonce, txu.hash));
-    //     }
-    // }
+    pub fn delete_packed<T: AsRef<[u8]>>(txs_packed: &[T]) {
+        for tx_packed in txs_packed {
+            if let Ok(txu) = Txu::try_from_slice(tx_packed.as_ref()) {
+                TX_POOL.remove(&bs58::encode(&txu.hash).into_string());
+            }
+        }
+    }
 
     // pub fn purge_stale() {
     //     let cur_epoch = Consensus::chain_epoch();
     //     TX_POOL.retain(|_key, txu| !Self::is_stale(txu, cur_epoch));
     // }
 
-    // pub fn grab_next_valid(amt: usize) -> Vec<Vec<u8>> {
-    //     let mut acc = Vec::new();
-    //     let mut state: HashMap<(&str, Vec<u8>), u64> = HashMap::new();
-    //     let chain_epoch = Consensus::chain_epoch();
-
-    //     for item in TX_POOL.iter() {
-    //         let txu = item.value().clone();
-    //         let signer = txu.tx.signer.clone();
-
-    //         let chain_nonce_key = ("chain_nonce", signer.clone());
-    //         let chain_nonce = state
-    //             .entry(chain_nonce_key.clone())
-    //             .or_insert_with(|| Consensus::chain_nonce(&signer));
-
-    //         if txu.tx.nonce <= *chain_nonce {
-    //             TX_POOL.remove(&(txu.tx.nonce, txu.hash.clone()));
-    //             continue;
-    //         }
-    //         *chain_nonce = txu.tx.nonce;
-
-    //         let balance_key = ("balance", signer.clone());
-    //         let mut balance = *state
-    //             .entry(balance_key.clone())
-    //             .or_insert_with(|| Consensus::chain_balance(&signer));
-    //         balance = balance.saturating_sub(BIC::Base::exec_cost(&txu));
-    //         balance = balance.saturating_sub(BIC::Coin::to_cents(1));
-    //         if balance < 0 {
-    //             TX_POOL.remove(&(txu.tx.nonce, txu.hash.clone()));
-    //             continue;
-    //         }
-    //         state.insert(balance_key, balance);
-
-    //         let has_sol = txu
-    //             .tx
-    //             .actions
-    //             .iter()
-    //             .find(|a| a.function == "submit_sol" && !a.args.is_empty())
-    //             .map(|a| &a.args[0]);
-
-    //         let epoch_sol_valid = if let Some(sol) = has_sol {
-    //             let sol_epoch = u32::from_le_bytes(sol[0..4].try_into().unwrap());
-    //             sol_epoch as u64 == chain_epoch && sol.len() == BIC::Sol::size()
-    //         } else {
-    //             true
-    //         };
-
-    //         if !epoch_sol_valid {
-    //             TX_POOL.remove(&(txu.tx.nonce, txu.hash.clone()));
-    //             continue;
-    //         }
-
-    //         acc.push(Tx::pack(&txu));
-    //         if acc.len() == amt {
-    //             break;
-    //         }
-    //     }
-    //     acc
-    // }
+    /// Picks txs in nonce order per signer, dropping the ones that can no longer
+    /// be included, until `max_bytes` or the per-entry tx limit is reached
+    pub fn grab_next_valid(max_bytes: usize) -> Vec<Vec<u8>> {
+        let chain_epoch = Consensus::chain_epoch();
+
+        let mut candidates: Vec<Txu> = TX_POOL.iter().map(|item| item.value().clone()).collect();
+        candidates.sort_by(|a, b| (&a.tx.signer, a.tx.nonce).cmp(&(&b.tx.signer, b.tx.nonce)));
+
+        let mut acc = Vec::new();
+        let mut size = 0;
+        let mut nonces: HashMap<Vec<u8>, u128> = HashMap::new();
+        let mut balances: HashMap<Vec<u8>, i64> = HashMap::new();
+        for txu in candidates {
+            let chain_nonce = nonces
+                .entry(txu.tx.signer.clone())
+                .or_insert_with(|| Consensus::chain_nonce(&txu.tx.signer).unwrap_or(0));
+
+            if txu.tx.nonce <= *chain_nonce {
+                TX_POOL.remove(&bs58::encode(&txu.hash).into_string());
+                continue;
+            }
+
+            // the signer must cover the exec cost of every tx picked so far plus a reserve
+            let balance = balances
+                .entry(txu.tx.signer.clone())
+                .or_insert_with(|| Coin::balance(&txu.tx.signer, "AMA"));
+            let balance_after = balance
+                .saturating_sub(Base::exec_cost(chain_epoch, &txu))
+                .saturating_sub(Coin::to_cents(1) as i64);
+            if balance_after < 0 {
+                TX_POOL.remove(&bs58::encode(&txu.hash).into_string());
+                continue;
+            }
+
+            let sol_epoch_valid = txu
+                .tx
+                .actions
+                .iter()
+                .find(|a| a.function == "submit_sol" && !a.args.is_empty())
+                .map(|a| {
+                    let sol = &a.args[0];
+                    sol.len() == Sol::size()
+                        && u32::from_le_bytes(sol[0..4].try_into().unwrap()) as u64 == chain_epoch
+                })
+                .unwrap_or(true);
+            if !sol_epoch_valid {
+                TX_POOL.remove(&bs58::encode(&txu.hash).into_string());
+                continue;
+            }
+
+            let tx_packed = TX::pack(&txu);
+            if size + tx_packed.len() > max_bytes {
+                break;
+            }
+            size += tx_packed.len();
+            *chain_nonce = txu.tx.nonce;
+            *balance = balance_after;
+            acc.push(tx_packed);
+
+            if acc.len() == Entry::MAX_TXS {
+                break;
+            }
+        }
+        acc
+    }
 
     // pub fn is_stale(txu: &TXU, cur_epoch: u64) -> bool {
     //     let chain_nonce = Consensus::chain_nonce(&txu.tx.signer);
@@ -160,3 +169,50 @@ impl TXPool {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer_tx(sk: &[u8], nonce: u128) -> Vec<u8> {
+        let receiver = BlsRs::get_public_key(&[33u8; 64]).unwrap();
+        let args = vec![receiver, b"1".to_vec()];
+        TX::build(sk, "Coin", "transfer", args, Some(nonce), None, None)
+    }
+
+    fn nonces(txs: &[Vec<u8>]) -> Vec<u128> {
+        txs.iter().map(|tx| TX::unpack(tx).unwrap().tx.nonce).collect()
+    }
+
+    #[test]
+    fn test_grab_next_valid() {
+        let _guard = Fabric::init_for_test();
+        TX_POOL.clear();
+        let funded_sk = [31u8; 64];
+        let broke_sk = [32u8; 64];
+        let balance_key = Coin::balance_key(&BlsRs::get_public_key(&funded_sk).unwrap(), "AMA");
+        let cf = Fabric::cf_handle("contractstate");
+        Fabric::db().put_cf(&cf, &balance_key, Coin::to_flat(10).to_string()).unwrap();
+
+        let txs: Vec<Vec<u8>> = [3, 1, 2].iter().map(|n| transfer_tx(&funded_sk, *n)).collect();
+        for tx in &txs {
+            TXPool::insert(tx.clone());
+        }
+        let broke_tx = transfer_tx(&broke_sk, 1);
+        TXPool::insert(broke_tx.clone());
+
+        // picked in nonce order, the signer that cannot pay is dropped from the pool
+        assert_eq!(nonces(&TXPool::grab_next_valid(usize::MAX)), vec![1, 2, 3]);
+        let broke_hash = TX::unpack(&broke_tx).unwrap().hash;
+        assert!(!TX_POOL.contains_key(&bs58::encode(&broke_hash).into_string()));
+
+        // the size budget cuts the batch without dropping the rest
+        let max_bytes = txs[1].len() + txs[2].len();
+        assert_eq!(nonces(&TXPool::grab_next_valid(max_bytes)), vec![1, 2]);
+        assert_eq!(nonces(&TXPool::grab_next_valid(max_bytes - 1)), vec![1]);
+        assert_eq!(TX_POOL.len(), 3);
+
+        Fabric::db().delete_cf(&cf, &balance_key).unwrap();
+        TX_POOL.clear();
+    }
+}
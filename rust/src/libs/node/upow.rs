@@ -78,7 +78,7 @@ impl UPOW0 {
         (hash, sol_seed)
     }
 
-    fn calculate(sol_seed: &[u8]) -> Vec<u8> {
+    pub fn calculate(sol_seed: &[u8]) -> Vec<u8> {
         // create Blake3 hasher and seed
         let mut hasher = Hasher::new();
         hasher.update(sol_seed);
@@ -169,7 +169,7 @@ impl UPOW1 {
         (hash, sol_seed)
     }
 
-    fn calculate(sol_seed: &[u8]) -> Vec<u8> {
+    pub fn calculate(sol_seed: &[u8]) -> Vec<u8> {
         let mut hasher = Hasher::new();
         hasher.update(sol_seed);
 
@@ -252,7 +252,7 @@ impl UPOW2 {
         (hash.as_bytes().to_vec(), sol)
     }
 
-    fn calculate_matmul(sol_seed: &[u8]) -> Vec<u8> {
+    pub fn calculate_matmul(sol_seed: &[u8]) -> Vec<u8> {
         // require sol_seed length == 240 (as in Elixir guard)
         if sol_seed.len() != 240 {
             panic!("sol_seed must be exactly 240 bytes for UPOW2.calculate_matmul");